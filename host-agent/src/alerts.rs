@@ -0,0 +1,322 @@
+use crate::config::{AlertRule, AlertsConfig};
+use crate::error::{AgentError, Result};
+use crate::persist;
+use crate::protocol::{Alert, AlertState, Comparison, Metric};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// How many alert changes a slow subscriber may fall behind on.
+const EVENT_BUFFER: usize = 64;
+
+/// Evaluates the configured alert rules against metrics samples, keeps
+/// the resulting alerts and broadcasts every change to subscribers.
+#[derive(Clone)]
+pub struct AlertManager {
+    state: Arc<Mutex<State>>,
+    events: broadcast::Sender<Alert>,
+    history_path: Option<PathBuf>,
+    /// Sequence number of the last snapshot written to `history_path`
+    saved: Arc<Mutex<u64>>,
+}
+
+struct State {
+    rules: Vec<RuleState>,
+    alerts: VecDeque<Alert>,
+    max_history: usize,
+    next_seq: u64,
+    /// Bumped for every snapshot taken for saving
+    snapshots: u64,
+}
+
+struct RuleState {
+    rule: AlertRule,
+    /// When the current run of samples beyond the threshold began
+    breached_since: Option<i64>,
+    /// Id of the alert this rule currently has firing
+    firing: Option<String>,
+}
+
+impl AlertManager {
+    pub fn new(config: &AlertsConfig) -> Self {
+        let history_path = config.history_path.as_ref().map(PathBuf::from);
+        let mut state = State {
+            rules: config
+                .rules
+                .iter()
+                .map(|rule| RuleState {
+                    rule: rule.clone(),
+                    breached_since: None,
+                    firing: None,
+                })
+                .collect(),
+            alerts: VecDeque::new(),
+            max_history: config.max_history.max(1),
+            next_seq: 1,
+            snapshots: 0,
+        };
+
+        if let Some(path) = &history_path {
+            match load(path) {
+                Ok(alerts) => state.restore(alerts),
+                Err(e) => warn!("Failed to load alert history: {}", e),
+            }
+        }
+
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            events,
+            history_path,
+            saved: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Alert> {
+        self.events.subscribe()
+    }
+
+    /// Checks every rule against a sample taken at `timestamp`. Rules
+    /// whose metric has no value in this sample keep their state.
+    pub fn evaluate(&self, timestamp: i64, value: impl Fn(Metric) -> Option<f64>) {
+        let mut changed = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            for i in 0..state.rules.len() {
+                let Some(value) = value(state.rules[i].rule.metric) else {
+                    continue;
+                };
+                if let Some(alert) = state.check(i, timestamp, value) {
+                    changed.push(alert);
+                }
+            }
+        }
+        if changed.is_empty() {
+            return;
+        }
+
+        for alert in changed {
+            match alert.state {
+                AlertState::Firing => warn!(
+                    "Alert {} fired: {:?} is {:.1} ({:?} {})",
+                    alert.rule, alert.metric, alert.value, alert.comparison, alert.threshold
+                ),
+                AlertState::Resolved => info!("Alert {} resolved", alert.rule),
+            }
+            // Nobody listening is not an error
+            let _ = self.events.send(alert);
+        }
+        self.save();
+    }
+
+    pub fn list(&self) -> Vec<Alert> {
+        self.state.lock().unwrap().alerts.iter().cloned().collect()
+    }
+
+    pub fn acknowledge(&self, id: &str) -> Result<Alert> {
+        let alert = {
+            let mut state = self.state.lock().unwrap();
+            let alert = state
+                .alerts
+                .iter_mut()
+                .find(|alert| alert.id == id)
+                .ok_or_else(|| AgentError::NotFound(format!("Unknown alert: {}", id)))?;
+            alert.acknowledged = true;
+            alert.clone()
+        };
+        self.save();
+        Ok(alert)
+    }
+
+    /// Writes the current alerts to the history file. On the runtime the
+    /// write happens on the blocking pool; snapshots are numbered so one
+    /// that finishes late never replaces a newer one.
+    fn save(&self) {
+        let Some(path) = self.history_path.clone() else {
+            return;
+        };
+        let (alerts, snapshot) = {
+            let mut state = self.state.lock().unwrap();
+            state.snapshots += 1;
+            (Vec::from(state.alerts.clone()), state.snapshots)
+        };
+
+        let saved = self.saved.clone();
+        let write = move || {
+            let mut saved = saved.lock().unwrap();
+            if *saved > snapshot {
+                return;
+            }
+            if let Err(e) = save(&path, &alerts) {
+                warn!("Failed to save alert history: {}", e);
+            }
+            *saved = snapshot;
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+impl State {
+    /// Advances rule `i` by one sample, returning the alert if it fired
+    /// or resolved.
+    fn check(&mut self, i: usize, timestamp: i64, value: f64) -> Option<Alert> {
+        let rule = &mut self.rules[i];
+
+        if let Some(id) = rule.firing.clone() {
+            if !rule.rule.cleared(value) {
+                return None;
+            }
+            rule.firing = None;
+            rule.breached_since = None;
+            // The alert may already have dropped out of the history
+            let alert = self.alerts.iter_mut().find(|alert| alert.id == id)?;
+            alert.state = AlertState::Resolved;
+            alert.resolved_at = Some(timestamp);
+            return Some(alert.clone());
+        }
+
+        if !rule.rule.breached(value) {
+            rule.breached_since = None;
+            return None;
+        }
+        let since = *rule.breached_since.get_or_insert(timestamp);
+        if timestamp - since < rule.rule.duration_secs as i64 {
+            return None;
+        }
+
+        let alert = Alert {
+            id: format!("alert-{}", self.next_seq),
+            rule: rule.rule.name.clone(),
+            metric: rule.rule.metric,
+            comparison: rule.rule.comparison,
+            threshold: rule.rule.threshold,
+            value,
+            state: AlertState::Firing,
+            triggered_at: timestamp,
+            resolved_at: None,
+            acknowledged: false,
+        };
+        self.next_seq += 1;
+        rule.firing = Some(alert.id.clone());
+
+        self.alerts.push_back(alert.clone());
+        while self.alerts.len() > self.max_history {
+            self.alerts.pop_front();
+        }
+        Some(alert)
+    }
+
+    /// Takes over a saved history. Alerts still firing stay attached to
+    /// their rule so they resolve normally; those whose rule is gone are
+    /// resolved right away.
+    fn restore(&mut self, alerts: Vec<Alert>) {
+        let now = chrono::Utc::now().timestamp();
+        for mut alert in alerts {
+            let seq = alert
+                .id
+                .strip_prefix("alert-")
+                .and_then(|seq| seq.parse::<u64>().ok())
+                .unwrap_or(0);
+            self.next_seq = self.next_seq.max(seq + 1);
+
+            if alert.state == AlertState::Firing {
+                match self.rules.iter_mut().find(|r| r.rule.name == alert.rule) {
+                    Some(rule) => rule.firing = Some(alert.id.clone()),
+                    None => {
+                        alert.state = AlertState::Resolved;
+                        alert.resolved_at = Some(now);
+                    }
+                }
+            }
+            self.alerts.push_back(alert);
+        }
+        while self.alerts.len() > self.max_history {
+            self.alerts.pop_front();
+        }
+    }
+}
+
+impl AlertRule {
+    fn breached(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+
+    fn cleared(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value < self.threshold - self.hysteresis,
+            Comparison::Below => value > self.threshold + self.hysteresis,
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Alert>> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| AgentError::Internal(format!("Corrupt alert history: {}", e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(path: &Path, alerts: &[Alert]) -> Result<()> {
+    let json = serde_json::to_vec(alerts).map_err(|e| AgentError::Internal(e.to_string()))?;
+    persist::write_atomic(path, &json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_and_hysteresis() {
+        let alerts = AlertManager::new(&AlertsConfig {
+            rules: vec![AlertRule {
+                name: "hot".to_string(),
+                metric: Metric::Temperature,
+                comparison: Comparison::Above,
+                threshold: 80.0,
+                duration_secs: 10,
+                hysteresis: 5.0,
+            }],
+            max_history: 10,
+            history_path: None,
+        });
+        let mut events = alerts.subscribe();
+        let sample = |t: i64, celsius: f64| alerts.evaluate(t, |_| Some(celsius));
+
+        // A short spike does not fire
+        sample(0, 85.0);
+        sample(5, 70.0);
+        sample(6, 85.0);
+        sample(15, 85.0);
+        assert!(alerts.list().is_empty());
+
+        sample(16, 85.0);
+        let fired = events.try_recv().unwrap();
+        assert_eq!(fired.state, AlertState::Firing);
+        assert_eq!(fired.triggered_at, 16);
+
+        // Within the hysteresis band the alert keeps firing
+        sample(20, 77.0);
+        sample(25, 90.0);
+        assert!(events.try_recv().is_err());
+        assert_eq!(alerts.list().len(), 1);
+
+        sample(30, 74.0);
+        let resolved = events.try_recv().unwrap();
+        assert_eq!(resolved.state, AlertState::Resolved);
+        assert_eq!(resolved.resolved_at, Some(30));
+
+        assert!(alerts.acknowledge(&fired.id).unwrap().acknowledged);
+        assert_eq!(alerts.acknowledge("alert-99").unwrap_err().code(), 404);
+    }
+}
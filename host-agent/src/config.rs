@@ -1,3 +1,4 @@
+use crate::protocol::{Comparison, Metric};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use anyhow::Result;
@@ -8,6 +9,16 @@ pub struct Config {
     pub security: SecurityConfig,
     pub logging: LoggingConfig,
     pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub thumbnails: ThumbnailConfig,
+    #[serde(default)]
+    pub versioning: VersioningConfig,
+    #[serde(default)]
+    pub hardware: HardwareConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -24,6 +35,47 @@ pub struct SecurityConfig {
     pub max_file_size: u64,
     pub max_path_depth: usize,
     pub audit_enabled: bool,
+    #[serde(default)]
+    pub allow_metadata_changes: bool,
+    #[serde(default = "default_max_extract_size")]
+    pub max_extract_size: u64,
+    #[serde(default = "default_max_archive_entries")]
+    pub max_archive_entries: u64,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+}
+
+/// Byte limits on disk usage. Going over a soft limit is only logged and
+/// reported by `QuotaStatus`; writes that would exceed a hard limit fail.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub paths: Vec<PathQuota>,
+    #[serde(default)]
+    pub users: Vec<UserQuota>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PathQuota {
+    pub path: PathBuf,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+}
+
+/// Limits for what clients connecting as the UID write through the agent.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UserQuota {
+    pub uid: u32,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+}
+
+fn default_max_extract_size() -> u64 {
+    1024 * 1024 * 1024 // 1GB
+}
+
+fn default_max_archive_entries() -> u64 {
+    10_000
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -38,6 +90,139 @@ pub struct PerformanceConfig {
     pub operation_timeout_secs: u64,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ThumbnailConfig {
+    pub cache_dir: String,
+    pub max_decode_dimension: u32,
+    pub max_decode_memory: u64,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        Self {
+            cache_dir: "/var/cache/webdesk/thumbnails".to_string(),
+            max_decode_dimension: 8192,
+            max_decode_memory: 128 * 1024 * 1024, // 128MB
+        }
+    }
+}
+
+/// Copies of files kept when `WriteFile` overwrites them. Without a
+/// `store_dir`, each allowed root keeps its versions in a hidden
+/// `.webdesk-versions` directory. A limit of 0 disables that limit.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VersioningConfig {
+    pub enabled: bool,
+    pub store_dir: Option<String>,
+    pub max_versions: usize,
+    pub max_age_days: u64,
+    pub max_file_size: u64,
+}
+
+impl Default for VersioningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            store_dir: None,
+            max_versions: 20,
+            max_age_days: 30,
+            max_file_size: 10 * 1024 * 1024, // 10MB
+        }
+    }
+}
+
+/// Where hardware telemetry is read from. Pointing these at a fake tree
+/// allows testing on machines that are not a Pi.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HardwareConfig {
+    pub sysfs_root: PathBuf,
+    pub procfs_root: PathBuf,
+}
+
+impl Default for HardwareConfig {
+    fn default() -> Self {
+        Self {
+            sysfs_root: PathBuf::from("/sys"),
+            procfs_root: PathBuf::from("/proc"),
+        }
+    }
+}
+
+/// Background sampling for `MetricsHistory`. Each tier keeps
+/// `retention_secs` of history averaged to `resolution_secs`; with a
+/// `persist_path` the history survives restarts.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub sample_interval_secs: u64,
+    pub tiers: Vec<MetricsTier>,
+    pub persist_path: Option<String>,
+    pub persist_interval_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MetricsTier {
+    pub resolution_secs: u64,
+    pub retention_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sample_interval_secs: 1,
+            tiers: vec![
+                // 1s for 10 minutes, 1 minute for 24 hours
+                MetricsTier {
+                    resolution_secs: 1,
+                    retention_secs: 600,
+                },
+                MetricsTier {
+                    resolution_secs: 60,
+                    retention_secs: 86400,
+                },
+            ],
+            persist_path: None,
+            persist_interval_secs: 300,
+        }
+    }
+}
+
+/// Rules checked against every metrics sample. Alerts beyond
+/// `max_history` are forgotten, oldest first; with a `history_path` they
+/// survive restarts.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AlertsConfig {
+    pub rules: Vec<AlertRule>,
+    pub max_history: usize,
+    pub history_path: Option<String>,
+}
+
+/// Fires once `metric` has been beyond `threshold` for `duration_secs`,
+/// and resolves when it is back by more than `hysteresis`, so a value
+/// hovering around the threshold does not flap.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    #[serde(default)]
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub hysteresis: f64,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_history: 100,
+            history_path: None,
+        }
+    }
+}
+
 impl Config {
     pub fn load(path: &str) -> Result<Self> {
         let content = std::fs::read_to_string(path)?;
@@ -62,10 +247,15 @@ impl Config {
                     ".ssh".to_string(),
                     ".gnupg".to_string(),
                     "*.key".to_string(),
+                    ".webdesk-versions".to_string(),
                 ],
                 max_file_size: 100 * 1024 * 1024, //100MB
                 max_path_depth: 10,
                 audit_enabled: true,
+                allow_metadata_changes: false,
+                max_extract_size: default_max_extract_size(),
+                max_archive_entries: default_max_archive_entries(),
+                quotas: QuotaConfig::default(),
             },
             logging: LoggingConfig {
                 level: "info".to_string(),
@@ -75,6 +265,11 @@ impl Config {
                 max_concurrent_operations: 10,
                 operation_timeout_secs: 30,
             },
+            thumbnails: ThumbnailConfig::default(),
+            versioning: VersioningConfig::default(),
+            hardware: HardwareConfig::default(),
+            metrics: MetricsConfig::default(),
+            alerts: AlertsConfig::default(),
         }
     }
 }
\ No newline at end of file
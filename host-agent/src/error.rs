@@ -14,17 +14,77 @@ pub enum AgentError {
     #[error("File not found: {0}")]
     FileNotFound(String),
 
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Archive error: {0}")]
+    Archive(#[from] zip::result::ZipError),
+
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
 
+    #[allow(dead_code)]
     #[error("Operation timeout")]
     Timeout,
 
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("File is locked: {0}")]
+    Locked(String),
+
     #[error("Internal error: {0}")]
     Internal(String),
 }
 
-pub type Result<T> = std::result::Result<T, AgentError>;
\ No newline at end of file
+impl AgentError {
+    /// Response code sent to the client for this error.
+    pub fn code(&self) -> u32 {
+        match self {
+            AgentError::InvalidRequest(_) => 400,
+            AgentError::PermissionDenied(_)
+            | AgentError::PathNotAllowed(_)
+            | AgentError::PathTraversal(_) => 403,
+            AgentError::FileNotFound(_) | AgentError::NotFound(_) => 404,
+            AgentError::Locked(_) => 423,
+            AgentError::QuotaExceeded(_) => 507,
+            _ => 500,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, AgentError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        let text = || "x".to_string();
+        let cases = [
+            (AgentError::PermissionDenied(text()), 403),
+            (AgentError::PathNotAllowed(text()), 403),
+            (AgentError::PathTraversal(text()), 403),
+            (AgentError::FileNotFound(text()), 404),
+            (AgentError::NotFound(text()), 404),
+            (std::io::Error::other("x").into(), 500),
+            (zip::result::ZipError::FileNotFound.into(), 500),
+            (AgentError::InvalidRequest(text()), 400),
+            (AgentError::Timeout, 500),
+            (AgentError::Cancelled, 500),
+            (AgentError::QuotaExceeded(text()), 507),
+            (AgentError::Locked(text()), 423),
+            (AgentError::Internal(text()), 500),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.code(), code, "{:?}", error);
+        }
+    }
+}
@@ -0,0 +1,933 @@
+use crate::error::{AgentError, Result};
+use crate::handlers::listing;
+use crate::jobs::Job;
+use crate::protocol::{ArchiveFormat, FileInfo, JobOutput, SortKey, SortOrder};
+use crate::security::Validator;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Symlink targets stored in zip archives are tiny; anything larger is bogus.
+const MAX_LINK_TARGET_LEN: u64 = 4096;
+
+pub struct ArchiveHandler {
+    validator: Validator,
+}
+
+struct SourceEntry {
+    path: PathBuf,
+    name: String,
+    metadata: Metadata,
+}
+
+/// An archive member as seen by listing and single-entry reads.
+struct Member {
+    name: String,
+    is_dir: bool,
+    is_symlink: bool,
+    size: u64,
+    modified: i64,
+    mode: u32,
+}
+
+#[derive(Default)]
+struct ExtractStats {
+    entries: u64,
+    bytes: u64,
+}
+
+impl ArchiveHandler {
+    pub fn new(validator: Validator) -> Self {
+        Self { validator }
+    }
+
+    pub fn create_archive(
+        &self,
+        paths: &[String],
+        dest: &str,
+        format: ArchiveFormat,
+        job: &Job,
+    ) -> Result<JobOutput> {
+        info!("Creating {:?} archive {}", format, dest);
+
+        if paths.is_empty() {
+            return Err(AgentError::InvalidRequest("No paths to archive".to_string()));
+        }
+
+        let dest_path = self.validator.validate_path(dest)?;
+        self.validator.check_lock(&dest_path)?;
+        if fs::symlink_metadata(&dest_path).is_ok() {
+            return Err(AgentError::InvalidRequest(
+                "Destination already exists".to_string(),
+            ));
+        }
+
+        let mut entries = Vec::new();
+        for path in paths {
+            // A symlink given as a source is archived as a link
+            let source = self.validator.validate_link_path(path)?;
+            let base = source.parent().unwrap_or(Path::new("/")).to_path_buf();
+            self.collect_sources(&source, &base, &dest_path, &mut entries)?;
+        }
+
+        // The same member may be reached through overlapping sources
+        let mut seen = HashSet::new();
+        entries.retain(|e| seen.insert(e.name.clone()));
+
+        let total_bytes: u64 = entries
+            .iter()
+            .filter(|e| e.metadata.is_file())
+            .map(|e| e.metadata.len())
+            .sum();
+        job.set_total(Some(entries.len() as u64), Some(total_bytes));
+
+        // The output is estimated at the size of its contents and settled
+        // once written
+        let reservation = self.validator.check_quota(&dest_path, total_bytes)?;
+        let file = File::create(&dest_path)?;
+        let result = match format {
+            ArchiveFormat::Zip => self.write_zip(file, &entries, job),
+            ArchiveFormat::Tar => self.write_tar(file, &entries, job).map(|_| ()),
+            ArchiveFormat::TarGz => self
+                .write_tar(GzEncoder::new(file, Compression::default()), &entries, job)
+                .and_then(|encoder| Ok(encoder.finish().map(|_| ())?)),
+            ArchiveFormat::TarXz => self
+                .write_tar(XzEncoder::new(file, 6), &entries, job)
+                .and_then(|encoder| Ok(encoder.finish().map(|_| ())?)),
+        };
+
+        let result = result.and_then(|_| {
+            let written = fs::metadata(&dest_path)?.len();
+            reservation.commit_written(written)
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&dest_path);
+            // A cancelled read surfaces as an IO error; report the cancellation instead
+            job.check_cancelled()?;
+            return Err(e);
+        }
+
+        self.validator.audit_log("ARCHIVE", &dest_path, true);
+        Ok(JobOutput::Archive {
+            path: dest_path.to_string_lossy().to_string(),
+            entries: entries.len() as u64,
+            bytes: total_bytes,
+        })
+    }
+
+    pub fn extract_archive(&self, archive: &str, dest: &str, job: &Job) -> Result<JobOutput> {
+        info!("Extracting {} to {}", archive, dest);
+
+        let archive_path = self.validator.validate_path(archive)?;
+        let dest_path = self.validator.validate_path(dest)?;
+        // Extracting overwrites whatever is in the way below the destination
+        self.validator.check_lock(&dest_path)?;
+
+        let format = detect_format(&archive_path)?;
+        fs::create_dir_all(&dest_path)?;
+
+        let file = File::open(&archive_path)?;
+        let mut stats = ExtractStats::default();
+
+        let result = match format {
+            ArchiveFormat::Zip => self.extract_zip(file, &dest_path, &mut stats, job),
+            ArchiveFormat::Tar | ArchiveFormat::TarGz | ArchiveFormat::TarXz => {
+                job.set_total(None, Some(file.metadata()?.len()));
+
+                // Progress is measured on the compressed input
+                let input = ProgressReader { inner: file, job };
+                match format {
+                    ArchiveFormat::TarGz => {
+                        self.extract_tar(GzDecoder::new(input), &dest_path, &mut stats, job)
+                    }
+                    ArchiveFormat::TarXz => {
+                        self.extract_tar(XzDecoder::new(input), &dest_path, &mut stats, job)
+                    }
+                    _ => self.extract_tar(input, &dest_path, &mut stats, job),
+                }
+            }
+        };
+
+        if let Err(e) = result {
+            job.check_cancelled()?;
+            return Err(e);
+        }
+
+        self.validator.audit_log("EXTRACT", &archive_path, true);
+        Ok(JobOutput::Extract {
+            dest: dest_path.to_string_lossy().to_string(),
+            entries: stats.entries,
+            bytes: stats.bytes,
+        })
+    }
+
+    pub fn list_archive(&self, path: &str, inner_path: Option<&str>) -> Result<Vec<FileInfo>> {
+        info!("Listing archive {} at {:?}", path, inner_path);
+
+        let archive_path = self.validator.validate_path(path)?;
+        self.validator.validate_file_size(&archive_path)?;
+
+        let prefix = normalize_member_name(inner_path.unwrap_or(""));
+        let mut children: BTreeMap<String, FileInfo> = BTreeMap::new();
+        let mut found = prefix.is_empty();
+
+        self.for_each_member(&archive_path, &mut |member, _| {
+            let rest = if prefix.is_empty() {
+                member.name.as_str()
+            } else if member.name == prefix {
+                found = true;
+                return Ok(false);
+            } else if let Some(rest) = member.name.strip_prefix(&format!("{}/", prefix)) {
+                rest
+            } else {
+                return Ok(false);
+            };
+            found = true;
+
+            let (child, nested) = match rest.split_once('/') {
+                Some((child, _)) => (child, true),
+                None => (rest, false),
+            };
+            if child.is_empty() {
+                return Ok(false);
+            }
+
+            let child_path = if prefix.is_empty() {
+                child.to_string()
+            } else {
+                format!("{}/{}", prefix, child)
+            };
+
+            if nested {
+                // Directories are often implied by their members only
+                children.entry(child.to_string()).or_insert_with(|| FileInfo {
+                    name: child.to_string(),
+                    path: child_path,
+                    is_dir: true,
+                    permissions: "755".to_string(),
+                    ..Default::default()
+                });
+            } else {
+                children.insert(
+                    child.to_string(),
+                    FileInfo {
+                        name: child.to_string(),
+                        path: child_path,
+                        is_dir: member.is_dir,
+                        is_symlink: member.is_symlink,
+                        size: member.size,
+                        modified: member.modified,
+                        permissions: format!("{:o}", member.mode & 0o777),
+                        extended: None,
+                    },
+                );
+            }
+            Ok(false)
+        })?;
+
+        if !found {
+            return Err(AgentError::FileNotFound(format!(
+                "{}:{}",
+                archive_path.display(),
+                prefix
+            )));
+        }
+
+        let mut files: Vec<FileInfo> = children.into_values().collect();
+        listing::sort_files(&mut files, SortKey::Name, SortOrder::Asc);
+
+        self.validator.audit_log("LIST_ARCHIVE", &archive_path, true);
+        Ok(files)
+    }
+
+    pub fn read_archive_entry(&self, path: &str, entry: &str) -> Result<(String, u64)> {
+        info!("Reading {} from archive {}", entry, path);
+
+        let archive_path = self.validator.validate_path(path)?;
+        self.validator.validate_file_size(&archive_path)?;
+
+        let wanted = normalize_member_name(entry);
+        let limit = self.validator.config().max_file_size;
+        let mut content = None;
+
+        self.for_each_member(&archive_path, &mut |member, reader| {
+            if member.name != wanted {
+                return Ok(false);
+            }
+            if member.is_dir || member.is_symlink {
+                return Err(AgentError::InvalidRequest(
+                    "Archive entry is not a regular file".to_string(),
+                ));
+            }
+
+            // Declared sizes can lie, so cap the actual read as well
+            let mut data = Vec::new();
+            reader.take(limit + 1).read_to_end(&mut data)?;
+            if data.len() as u64 > limit {
+                return Err(AgentError::PermissionDenied(format!(
+                    "File size exceeds limit {}",
+                    limit
+                )));
+            }
+            content = Some(data);
+            Ok(true)
+        })?;
+
+        let data = content.ok_or_else(|| AgentError::FileNotFound(entry.to_string()))?;
+        let size = data.len() as u64;
+        let text = String::from_utf8(data).map_err(|_| {
+            AgentError::InvalidRequest("File is not valid UTF-8 text".to_string())
+        })?;
+
+        self.validator.audit_log("READ_ARCHIVE", &archive_path, true);
+        Ok((text, size))
+    }
+
+    /// Calls `f` with every member of the archive and a reader for its
+    /// data, until `f` returns `true`.
+    fn for_each_member(
+        &self,
+        archive_path: &Path,
+        f: &mut dyn FnMut(&Member, &mut dyn Read) -> Result<bool>,
+    ) -> Result<()> {
+        let format = detect_format(archive_path)?;
+        let file = File::open(archive_path)?;
+
+        if format == ArchiveFormat::Zip {
+            let mut archive = ZipArchive::new(file)?;
+            for i in 0..archive.len() {
+                let mut zip_file = archive.by_index(i)?;
+                let member = Member {
+                    name: normalize_member_name(&zip_file.name()?),
+                    is_dir: zip_file.is_dir(),
+                    is_symlink: zip_file.is_symlink(),
+                    size: zip_file.size(),
+                    modified: zip_file.last_modified().map(zip_timestamp).unwrap_or(0),
+                    mode: zip_file.unix_mode().unwrap_or(0o644),
+                };
+                if f(&member, &mut zip_file)? {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+
+        let reader: Box<dyn Read> = match format {
+            ArchiveFormat::TarGz => Box::new(GzDecoder::new(file)),
+            ArchiveFormat::TarXz => Box::new(XzDecoder::new(file)),
+            _ => Box::new(file),
+        };
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            let member = Member {
+                name: normalize_member_name(&String::from_utf8_lossy(&entry.path_bytes())),
+                is_dir: entry_type.is_dir(),
+                is_symlink: entry_type.is_symlink(),
+                size: header.size().unwrap_or(0),
+                modified: header.mtime().unwrap_or(0) as i64,
+                mode: header.mode().unwrap_or(0o644),
+            };
+            if f(&member, &mut entry)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn collect_sources(
+        &self,
+        path: &Path,
+        base: &Path,
+        dest: &Path,
+        entries: &mut Vec<SourceEntry>,
+    ) -> Result<()> {
+        // Never archive the archive being written
+        if path == dest {
+            return Ok(());
+        }
+
+        let metadata = fs::symlink_metadata(path)?;
+        let name = path
+            .strip_prefix(base)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string();
+
+        let is_dir = metadata.is_dir();
+        let file_type = metadata.file_type();
+        if !is_dir && !file_type.is_file() && !file_type.is_symlink() {
+            debug!("Skipping special file {:?}", path);
+            return Ok(());
+        }
+
+        entries.push(SourceEntry {
+            path: path.to_path_buf(),
+            name,
+            metadata,
+        });
+
+        if is_dir {
+            for entry in fs::read_dir(path)? {
+                let entry_path = entry?.path();
+                if let Err(e) = self.validator.validate_child(&entry_path) {
+                    debug!("Skipping {:?}: {}", entry_path, e);
+                    continue;
+                }
+                self.collect_sources(&entry_path, base, dest, entries)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_tar<W: Write>(&self, writer: W, entries: &[SourceEntry], job: &Job) -> Result<W> {
+        let mut builder = tar::Builder::new(writer);
+
+        for entry in entries {
+            job.check_cancelled()?;
+            job.set_current(&entry.name);
+
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&entry.metadata);
+
+            if entry.metadata.is_dir() {
+                header.set_size(0);
+                builder.append_data(&mut header, &entry.name, io::empty())?;
+            } else if entry.metadata.file_type().is_symlink() {
+                header.set_size(0);
+                let target = fs::read_link(&entry.path)?;
+                builder.append_link(&mut header, &entry.name, target)?;
+            } else {
+                let file = File::open(&entry.path)?;
+                let reader = ProgressReader { inner: file, job };
+                builder.append_data(&mut header, &entry.name, reader)?;
+            }
+
+            job.advance(1, 0);
+        }
+
+        Ok(builder.into_inner()?)
+    }
+
+    fn write_zip(&self, file: File, entries: &[SourceEntry], job: &Job) -> Result<()> {
+        let mut zip = ZipWriter::new(file);
+
+        for entry in entries {
+            job.check_cancelled()?;
+            job.set_current(&entry.name);
+
+            let options = SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .unix_permissions(entry.metadata.permissions().mode() & 0o7777)
+                .large_file(entry.metadata.len() >= u32::MAX as u64);
+
+            if entry.metadata.is_dir() {
+                zip.add_directory(entry.name.as_str(), options)?;
+            } else if entry.metadata.file_type().is_symlink() {
+                let target = fs::read_link(&entry.path)?;
+                zip.add_symlink(entry.name.as_str(), target.to_string_lossy(), options)?;
+            } else {
+                zip.start_file(entry.name.as_str(), options)?;
+                let mut reader = ProgressReader {
+                    inner: File::open(&entry.path)?,
+                    job,
+                };
+                io::copy(&mut reader, &mut zip)?;
+            }
+
+            job.advance(1, 0);
+        }
+
+        zip.finish()?;
+        Ok(())
+    }
+
+    fn extract_tar<R: Read>(
+        &self,
+        reader: R,
+        dest: &Path,
+        stats: &mut ExtractStats,
+        job: &Job,
+    ) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries()? {
+            job.check_cancelled()?;
+            let mut entry = entry?;
+
+            let name = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+            let path = self.validator.validate_archive_entry(dest, &name)?;
+            self.count_entry(stats)?;
+            job.set_current(&name);
+
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => {
+                    Self::create_dirs(dest, &path)?;
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let mode = entry.header().mode().unwrap_or(0o644);
+                    let size = entry.size();
+                    self.write_file(&mut entry, dest, &path, size, mode, stats)?;
+                }
+                tar::EntryType::Symlink => {
+                    let target = entry.link_name()?.ok_or_else(|| {
+                        AgentError::InvalidRequest(format!("Symlink without target: {}", name))
+                    })?;
+                    Self::prepare_target(dest, &path)?;
+                    self.validator.validate_archive_link(dest, &path, &target)?;
+                    std::os::unix::fs::symlink(&target, &path)?;
+                }
+                tar::EntryType::Link => {
+                    let target = entry.link_name()?.ok_or_else(|| {
+                        AgentError::InvalidRequest(format!("Hard link without target: {}", name))
+                    })?;
+                    let source = self
+                        .validator
+                        .validate_archive_entry(dest, &target.to_string_lossy())?;
+                    // link() follows symlinks in the source's directories too
+                    Self::check_dirs(dest, source.parent().unwrap_or(dest))?;
+                    Self::prepare_target(dest, &path)?;
+                    fs::hard_link(&source, &path)?;
+                }
+                other => {
+                    debug!("Skipping unsupported tar entry {} ({:?})", name, other);
+                }
+            }
+
+            job.advance(1, 0);
+        }
+
+        Ok(())
+    }
+
+    fn extract_zip(
+        &self,
+        file: File,
+        dest: &Path,
+        stats: &mut ExtractStats,
+        job: &Job,
+    ) -> Result<()> {
+        let mut archive = ZipArchive::new(file)?;
+
+        if archive.len() as u64 > self.validator.config().max_archive_entries {
+            return Err(AgentError::PermissionDenied(format!(
+                "Archive has more than {} entries",
+                self.validator.config().max_archive_entries
+            )));
+        }
+
+        let mut total_bytes = 0;
+        for i in 0..archive.len() {
+            total_bytes += archive.by_index_raw(i)?.size();
+        }
+        job.set_total(Some(archive.len() as u64), Some(total_bytes));
+
+        for i in 0..archive.len() {
+            job.check_cancelled()?;
+            let file = archive.by_index(i)?;
+
+            let name = file.name()?.to_string();
+            let path = self.validator.validate_archive_entry(dest, &name)?;
+            self.count_entry(stats)?;
+            job.set_current(&name);
+
+            if file.is_dir() {
+                Self::create_dirs(dest, &path)?;
+            } else if file.is_symlink() {
+                let mut target = String::new();
+                file.take(MAX_LINK_TARGET_LEN).read_to_string(&mut target)?;
+                Self::prepare_target(dest, &path)?;
+                self.validator
+                    .validate_archive_link(dest, &path, Path::new(&target))?;
+                std::os::unix::fs::symlink(&target, &path)?;
+            } else {
+                let mode = file.unix_mode().unwrap_or(0o644);
+                let size = file.size();
+                let mut reader = ProgressReader { inner: file, job };
+                self.write_file(&mut reader, dest, &path, size, mode, stats)?;
+            }
+
+            job.advance(1, 0);
+        }
+
+        Ok(())
+    }
+
+    fn count_entry(&self, stats: &mut ExtractStats) -> Result<()> {
+        stats.entries += 1;
+        let limit = self.validator.config().max_archive_entries;
+        if stats.entries > limit {
+            return Err(AgentError::PermissionDenied(format!(
+                "Archive has more than {} entries",
+                limit
+            )));
+        }
+        Ok(())
+    }
+
+    /// Writes one extracted file, enforcing `max_extract_size` on the
+    /// bytes actually produced rather than on sizes the archive claims.
+    /// Quota is reserved for the claimed `size` up front and settled on
+    /// what was written.
+    fn write_file(
+        &self,
+        reader: &mut dyn Read,
+        dest: &Path,
+        path: &Path,
+        size: u64,
+        mode: u32,
+        stats: &mut ExtractStats,
+    ) -> Result<()> {
+        let limit = self.validator.config().max_extract_size;
+        let remaining = limit.saturating_sub(stats.bytes);
+
+        Self::prepare_target(dest, path)?;
+        let replaced = fs::symlink_metadata(path)
+            .ok()
+            .filter(|m| m.is_file())
+            .map_or(0, |m| m.len());
+        let reservation = self.validator.check_quota(path, size.min(remaining))?;
+        let mut file = File::create(path)?;
+        self.validator.release_quota(path, replaced);
+
+        let written = io::copy(&mut reader.take(remaining + 1), &mut file);
+        drop(file);
+        let written = match written {
+            Ok(written) if written > remaining => {
+                let _ = fs::remove_file(path);
+                return Err(AgentError::PermissionDenied(format!(
+                    "Archive exceeds extraction size limit of {} bytes",
+                    limit
+                )));
+            }
+            Ok(written) => written,
+            Err(e) => {
+                let _ = fs::remove_file(path);
+                return Err(e.into());
+            }
+        };
+        stats.bytes += written;
+
+        if let Err(e) = reservation.commit_written(written) {
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+
+        // Never restore setuid/setgid bits from an archive
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+        Ok(())
+    }
+
+    /// Creates the parent directories and removes a stale symlink so that
+    /// writing `path` cannot be redirected elsewhere.
+    fn prepare_target(dest: &Path, path: &Path) -> Result<()> {
+        Self::create_dirs(dest, path.parent().unwrap_or(dest))?;
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_symlink() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the directories from `dest` down to `dir` one at a time.
+    /// Earlier entries may have planted symlinks on the way, which the
+    /// kernel would follow, so any symlink found there is refused.
+    fn create_dirs(dest: &Path, dir: &Path) -> Result<()> {
+        Self::walk_dirs(dest, dir, true)
+    }
+
+    /// Checks that the directories from `dest` down to `dir` are real
+    /// directories, without creating anything.
+    fn check_dirs(dest: &Path, dir: &Path) -> Result<()> {
+        Self::walk_dirs(dest, dir, false)
+    }
+
+    fn walk_dirs(dest: &Path, dir: &Path, create: bool) -> Result<()> {
+        let relative = dir
+            .strip_prefix(dest)
+            .map_err(|_| AgentError::PathTraversal(dir.display().to_string()))?;
+
+        let mut current = dest.to_path_buf();
+        for component in relative.components() {
+            current.push(component);
+            match fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    warn!("Archive entry leads through symlink {:?}", current);
+                    return Err(AgentError::PathTraversal(current.display().to_string()));
+                }
+                Ok(_) => {
+                    return Err(AgentError::InvalidRequest(format!(
+                        "Not a directory: {}",
+                        current.display()
+                    )));
+                }
+                Err(e) if create && e.kind() == io::ErrorKind::NotFound => {
+                    fs::create_dir(&current)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Detects the archive format from the file name, falling back to the
+/// magic bytes at the start of the file.
+pub fn detect_format(path: &Path) -> Result<ArchiveFormat> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    if name.ends_with(".zip") {
+        return Ok(ArchiveFormat::Zip);
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        return Ok(ArchiveFormat::TarGz);
+    } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+        return Ok(ArchiveFormat::TarXz);
+    } else if name.ends_with(".tar") {
+        return Ok(ArchiveFormat::Tar);
+    }
+
+    let mut header = Vec::with_capacity(512);
+    File::open(path)?.take(512).read_to_end(&mut header)?;
+
+    if header.starts_with(b"PK\x03\x04") {
+        Ok(ArchiveFormat::Zip)
+    } else if header.starts_with(&[0x1f, 0x8b]) {
+        Ok(ArchiveFormat::TarGz)
+    } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Ok(ArchiveFormat::TarXz)
+    } else if header.len() >= 262 && &header[257..262] == b"ustar" {
+        Ok(ArchiveFormat::Tar)
+    } else {
+        Err(AgentError::InvalidRequest(
+            "Unsupported archive format".to_string(),
+        ))
+    }
+}
+
+/// Strips `./`, leading and trailing slashes so member names can be
+/// compared regardless of how the archiver wrote them.
+fn normalize_member_name(name: &str) -> String {
+    let mut name = name.trim_matches('/');
+    while let Some(rest) = name.strip_prefix("./") {
+        name = rest.trim_start_matches('/');
+    }
+    if name == "." {
+        return String::new();
+    }
+    name.to_string()
+}
+
+fn zip_timestamp(dt: zip::DateTime) -> i64 {
+    chrono::NaiveDate::from_ymd_opt(dt.year() as i32, dt.month() as u32, dt.day() as u32)
+        .and_then(|d| d.and_hms_opt(dt.hour() as u32, dt.minute() as u32, dt.second() as u32))
+        .map(|t| t.and_utc().timestamp())
+        .unwrap_or(0)
+}
+
+/// Reports bytes read to the job and aborts the read once it is cancelled.
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    job: &'a Job,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(inner: R, job: &'a Job) -> Self {
+        Self { inner, job }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.job.check_cancelled().is_err() {
+            return Err(io::Error::other("job cancelled"));
+        }
+        let n = self.inner.read(buf)?;
+        self.job.advance(0, n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    fn test_root(name: &str) -> (TestDir, ArchiveHandler) {
+        let root = TestDir::new(&format!("archive-{}", name));
+        let handler = ArchiveHandler::new(root.validator());
+        (root, handler)
+    }
+
+    /// Appends a tar entry with its name and link written as-is, since
+    /// the tar crate refuses to build the malicious names being tested.
+    fn tar_entry(
+        builder: &mut tar::Builder<Vec<u8>>,
+        kind: tar::EntryType,
+        name: &str,
+        link: &str,
+        data: &[u8],
+    ) {
+        let mut header = tar::Header::new_gnu();
+        let old = header.as_old_mut();
+        old.name[..name.len()].copy_from_slice(name.as_bytes());
+        old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    fn extract_tar(
+        handler: &ArchiveHandler,
+        root: &Path,
+        name: &str,
+        entries: &[(tar::EntryType, &str, &str)],
+    ) -> Result<JobOutput> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (kind, name, link) in entries {
+            tar_entry(&mut builder, *kind, name, link, b"pwned");
+        }
+        let archive = root.join(format!("{}.tar", name));
+        fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        handler.extract_archive(
+            &archive.to_string_lossy(),
+            &root.join(name).to_string_lossy(),
+            &Job::for_tests(),
+        )
+    }
+
+    #[test]
+    fn test_create_and_extract_roundtrip() {
+        let (root, handler) = test_root("roundtrip");
+        let source = root.join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/readme.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink("docs/readme.txt", source.join("link")).unwrap();
+        let sources = vec![source.to_string_lossy().to_string()];
+
+        for (format, name) in [
+            (ArchiveFormat::Zip, "out.zip"),
+            (ArchiveFormat::TarGz, "out.tar.gz"),
+            (ArchiveFormat::TarXz, "out.tar.xz"),
+        ] {
+            let archive = root.join(name).to_string_lossy().to_string();
+            let dest = root.join(format!("{}.d", name));
+            let job = Job::for_tests();
+
+            handler.create_archive(&sources, &archive, format, &job).unwrap();
+            handler
+                .extract_archive(&archive, &dest.to_string_lossy(), &job)
+                .unwrap();
+
+            let extracted = dest.join("source");
+            assert_eq!(
+                fs::read_to_string(extracted.join("docs/readme.txt")).unwrap(),
+                "hello"
+            );
+            assert_eq!(
+                fs::read_link(extracted.join("link")).unwrap(),
+                Path::new("docs/readme.txt")
+            );
+        }
+    }
+
+    #[test]
+    fn test_malicious_tar() {
+        use tar::EntryType::{Link, Regular, Symlink};
+        let (root, handler) = test_root("evil-tar");
+        fs::write(root.join("secret"), "secret").unwrap();
+
+        assert!(extract_tar(&handler, &root, "dotdot", &[(Regular, "../evil", "")]).is_err());
+        assert!(extract_tar(&handler, &root, "absolute", &[(Regular, "/tmp/evil", "")]).is_err());
+        assert!(!root.join("evil").exists());
+
+        // a/b/c leads back to the destination; writing through it again
+        // would land outside
+        let chained = [
+            (Symlink, "a/b/c", "../.."),
+            (Symlink, "a/b/c/x", "../../esc"),
+            (Regular, "a/b/c/x/pwn", ""),
+        ];
+        assert!(matches!(
+            extract_tar(&handler, &root, "chained", &chained),
+            Err(AgentError::PathTraversal(_))
+        ));
+        assert!(!root.join("esc").exists());
+
+        // On paper a/d is chained/a/b; through the link it is root
+        let hard_link = [
+            (Symlink, "a/b/c", "../.."),
+            (Symlink, "a/d", "b/c/.."),
+            (Link, "h", "a/d/secret"),
+        ];
+        assert!(extract_tar(&handler, &root, "hardlink", &hard_link).is_err());
+        assert!(!root.join("hardlink/h").exists());
+
+        // Hard links to members outside the destination
+        assert!(extract_tar(&handler, &root, "hard", &[(Link, "h", "../secret")]).is_err());
+    }
+
+    #[test]
+    fn test_malicious_zip() {
+        let (root, handler) = test_root("evil-zip");
+        let zip_with = |name: &str, entries: &[(&str, Option<&str>)]| {
+            let path = root.join(name);
+            let mut zip = ZipWriter::new(File::create(&path).unwrap());
+            for (entry, link) in entries {
+                match link {
+                    Some(target) => zip
+                        .add_symlink(*entry, *target, SimpleFileOptions::default())
+                        .unwrap(),
+                    None => {
+                        zip.start_file(*entry, SimpleFileOptions::default()).unwrap();
+                        zip.write_all(b"pwned").unwrap();
+                    }
+                }
+            }
+            zip.finish().unwrap();
+            handler.extract_archive(
+                &path.to_string_lossy(),
+                &root.join(format!("{}.d", name)).to_string_lossy(),
+                &Job::for_tests(),
+            )
+        };
+
+        assert!(zip_with("dotdot.zip", &[("../evil", None)]).is_err());
+        assert!(zip_with("absolute.zip", &[("/tmp/evil", None)]).is_err());
+        assert!(!root.join("evil").exists());
+
+        let chained = zip_with(
+            "chained.zip",
+            &[
+                ("a/b/c", Some("../..")),
+                ("a/b/c/x", Some("../../esc")),
+                ("a/b/c/x/pwn", None),
+            ],
+        );
+        assert!(matches!(chained, Err(AgentError::PathTraversal(_))));
+        assert!(!root.join("esc").exists());
+    }
+}
+
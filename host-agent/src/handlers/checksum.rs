@@ -0,0 +1,379 @@
+use crate::error::{AgentError, Result};
+use crate::jobs::Job;
+use crate::protocol::{ChecksumResult, HashAlgorithm, JobOutput, VerifyStatus};
+use crate::security::Validator;
+use log::info;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+pub struct ChecksumHandler {
+    validator: Validator,
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Crc32(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(h) => to_hex(&h.finalize()),
+            Hasher::Sha1(h) => to_hex(&h.finalize()),
+            Hasher::Md5(h) => to_hex(&h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            Hasher::Crc32(h) => format!("{:08x}", h.finalize()),
+        }
+    }
+}
+
+impl ChecksumHandler {
+    pub fn new(validator: Validator) -> Self {
+        Self { validator }
+    }
+
+    /// Hashes a file with every requested algorithm in a single pass.
+    /// Files are streamed, so `max_file_size` does not apply.
+    pub fn checksum(
+        &self,
+        path: &str,
+        algorithms: &[HashAlgorithm],
+        job: &Job,
+    ) -> Result<JobOutput> {
+        info!("Computing checksums of {}", path);
+
+        let validated_path = self.validator.validate_path(path)?;
+        let metadata = fs::metadata(&validated_path)?;
+        if !metadata.is_file() {
+            return Err(AgentError::InvalidRequest("Path is not a file".to_string()));
+        }
+
+        let algorithms = if algorithms.is_empty() {
+            vec![HashAlgorithm::Sha256]
+        } else {
+            algorithms.to_vec()
+        };
+
+        job.set_total(Some(1), Some(metadata.len()));
+        job.set_current(&validated_path.to_string_lossy());
+        let checksums = hash_file(&validated_path, &algorithms, Some(job))?;
+        job.advance(1, 0);
+
+        self.validator.audit_log("CHECKSUM", &validated_path, true);
+        Ok(JobOutput::Checksums {
+            path: validated_path.to_string_lossy().to_string(),
+            size: metadata.len(),
+            checksums,
+        })
+    }
+
+    /// Checks every file listed in a `SHA256SUMS`-style file. Listed names
+    /// are resolved against the directory containing the sums file.
+    pub fn verify_checksums(&self, path: &str, job: &Job) -> Result<JobOutput> {
+        info!("Verifying checksums from {}", path);
+
+        let sums_path = self.validator.validate_path(path)?;
+        self.validator.validate_file_size(&sums_path)?;
+        let content = fs::read_to_string(&sums_path)?;
+        let dir = sums_path.parent().unwrap_or(Path::new("/"));
+        let default_algorithm = algorithm_for_sums_file(&sums_path);
+
+        let lines: Vec<SumsLine> = content
+            .lines()
+            .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                parse_sums_line(l)
+                    .ok_or_else(|| AgentError::InvalidRequest(format!("Malformed line: {}", l)))
+            })
+            .collect::<Result<_>>()?;
+
+        // Names are validated before anything looks at them, so sizes of
+        // files outside the allowed paths do not show up in the progress
+        let lines: Vec<(SumsLine, Result<PathBuf>)> = lines
+            .into_iter()
+            .map(|l| {
+                let file_path = dir.join(&l.name);
+                let file_path = self.validator.validate_path(&file_path.to_string_lossy());
+                (l, file_path)
+            })
+            .collect();
+
+        let total_bytes = lines
+            .iter()
+            .filter_map(|(_, p)| fs::metadata(p.as_ref().ok()?).ok())
+            .map(|m| m.len())
+            .sum();
+        job.set_total(Some(lines.len() as u64), Some(total_bytes));
+
+        let mut results = Vec::with_capacity(lines.len());
+        for (line, file_path) in lines {
+            job.check_cancelled()?;
+            job.set_current(&line.name);
+
+            let algorithm = line
+                .algorithm
+                .or(default_algorithm)
+                .or_else(|| algorithm_for_length(line.hash.len()))
+                .unwrap_or(HashAlgorithm::Sha256);
+            let expected = line.hash.to_lowercase();
+
+            let mut result = ChecksumResult {
+                path: line.name.clone(),
+                status: VerifyStatus::Ok,
+                expected: expected.clone(),
+                actual: None,
+                error: None,
+            };
+
+            match file_path {
+                Ok(p) if !p.is_file() => result.status = VerifyStatus::Missing,
+                Ok(p) => match hash_file(&p, &[algorithm], Some(job)) {
+                    Ok(mut sums) => {
+                        let actual = sums.remove(&algorithm).unwrap_or_default();
+                        if actual != expected {
+                            result.status = VerifyStatus::Mismatch;
+                        }
+                        result.actual = Some(actual);
+                    }
+                    Err(AgentError::Cancelled) => return Err(AgentError::Cancelled),
+                    Err(e) => {
+                        result.status = VerifyStatus::Error;
+                        result.error = Some(e.to_string());
+                    }
+                },
+                Err(e) => {
+                    result.status = VerifyStatus::Error;
+                    result.error = Some(e.to_string());
+                }
+            }
+
+            results.push(result);
+            job.advance(1, 0);
+        }
+
+        let count = |status| results.iter().filter(|r| r.status == status).count() as u64;
+        let output = JobOutput::Verification {
+            ok: count(VerifyStatus::Ok),
+            failed: count(VerifyStatus::Mismatch) + count(VerifyStatus::Error),
+            missing: count(VerifyStatus::Missing),
+            results,
+        };
+
+        self.validator.audit_log("VERIFY", &sums_path, true);
+        Ok(output)
+    }
+}
+
+/// Streams `path` through the requested hashers. When a job is given,
+/// progress is reported and cancellation honoured between reads.
+pub fn hash_file(
+    path: &Path,
+    algorithms: &[HashAlgorithm],
+    job: Option<&Job>,
+) -> Result<BTreeMap<HashAlgorithm, String>> {
+    let mut file = File::open(path)?;
+    let mut hashers: Vec<(HashAlgorithm, Hasher)> =
+        algorithms.iter().map(|a| (*a, Hasher::new(*a))).collect();
+
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        if let Some(job) = job {
+            job.check_cancelled()?;
+        }
+
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for (_, hasher) in hashers.iter_mut() {
+            hasher.update(&buf[..n]);
+        }
+
+        if let Some(job) = job {
+            job.advance(0, n as u64);
+        }
+    }
+
+    Ok(hashers
+        .into_iter()
+        .map(|(algorithm, hasher)| (algorithm, hasher.finalize()))
+        .collect())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[derive(Debug, PartialEq)]
+struct SumsLine {
+    algorithm: Option<HashAlgorithm>,
+    hash: String,
+    name: String,
+}
+
+/// Parses GNU (`<hash>  <name>`, `<hash> *<name>`) and BSD
+/// (`SHA256 (<name>) = <hash>`) checksum lines.
+fn parse_sums_line(line: &str) -> Option<SumsLine> {
+    let line = line.trim_end();
+
+    if let Some((head, hash)) = line.rsplit_once(") = ") {
+        let (tag, name) = head.split_once(" (")?;
+        return Some(SumsLine {
+            algorithm: Some(algorithm_for_tag(tag)?),
+            hash: hash.trim().to_string(),
+            name: name.to_string(),
+        });
+    }
+
+    let (hash, name) = line.split_once(' ')?;
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let name = name.strip_prefix([' ', '*']).unwrap_or(name);
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(SumsLine {
+        algorithm: None,
+        hash: hash.to_string(),
+        name: name.to_string(),
+    })
+}
+
+fn algorithm_for_tag(tag: &str) -> Option<HashAlgorithm> {
+    match tag.to_uppercase().as_str() {
+        "SHA256" => Some(HashAlgorithm::Sha256),
+        "SHA1" => Some(HashAlgorithm::Sha1),
+        "MD5" => Some(HashAlgorithm::Md5),
+        "BLAKE3" => Some(HashAlgorithm::Blake3),
+        "CRC32" => Some(HashAlgorithm::Crc32),
+        _ => None,
+    }
+}
+
+fn algorithm_for_sums_file(path: &Path) -> Option<HashAlgorithm> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    if name.contains("sha256") {
+        Some(HashAlgorithm::Sha256)
+    } else if name.contains("sha1") {
+        Some(HashAlgorithm::Sha1)
+    } else if name.contains("md5") {
+        Some(HashAlgorithm::Md5)
+    } else if name.contains("b3") || name.contains("blake3") {
+        Some(HashAlgorithm::Blake3)
+    } else if name.contains("crc32") {
+        Some(HashAlgorithm::Crc32)
+    } else {
+        None
+    }
+}
+
+fn algorithm_for_length(len: usize) -> Option<HashAlgorithm> {
+    match len {
+        64 => Some(HashAlgorithm::Sha256),
+        40 => Some(HashAlgorithm::Sha1),
+        32 => Some(HashAlgorithm::Md5),
+        8 => Some(HashAlgorithm::Crc32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn test_parse_gnu_sums_line() {
+        let line = parse_sums_line("d41d8cd98f00b204e9800998ecf8427e  empty file.txt").unwrap();
+        assert_eq!(line.algorithm, None);
+        assert_eq!(line.hash, "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(line.name, "empty file.txt");
+
+        let binary = parse_sums_line("d41d8cd98f00b204e9800998ecf8427e *image.img").unwrap();
+        assert_eq!(binary.name, "image.img");
+
+        assert!(parse_sums_line("not-a-hash  file").is_none());
+    }
+
+    #[test]
+    fn test_parse_bsd_sums_line() {
+        let line = parse_sums_line("SHA1 (boot/kernel.img) = da39a3ee5e6b4b0d3255bfef95601890afd80709")
+            .unwrap();
+        assert_eq!(line.algorithm, Some(HashAlgorithm::Sha1));
+        assert_eq!(line.name, "boot/kernel.img");
+        assert_eq!(line.hash, "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    }
+
+    #[test]
+    fn test_empty_input_digests() {
+        let hex = |a| {
+            let mut h = Hasher::new(a);
+            h.update(b"");
+            h.finalize()
+        };
+        assert_eq!(
+            hex(HashAlgorithm::Sha256),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(hex(HashAlgorithm::Md5), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(HashAlgorithm::Crc32), "00000000");
+    }
+
+    #[test]
+    fn test_verify_outside_names() {
+        let root = TestDir::new("checksum");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/empty"), "").unwrap();
+        fs::write(root.join("secret"), "").unwrap();
+        let sums = "d41d8cd98f00b204e9800998ecf8427e  empty\n\
+                    d41d8cd98f00b204e9800998ecf8427e  ../secret\n\
+                    d41d8cd98f00b204e9800998ecf8427e  /etc/hostname\n";
+        fs::write(root.join("dir/MD5SUMS"), sums).unwrap();
+
+        let handler = ChecksumHandler::new(Validator::for_tests(&root.join("dir")));
+        let output = handler
+            .verify_checksums(&root.join("dir/MD5SUMS").to_string_lossy(), &Job::for_tests())
+            .unwrap();
+
+        let JobOutput::Verification { ok, failed, results, .. } = output else {
+            panic!("unexpected output");
+        };
+        assert_eq!((ok, failed), (1, 2));
+        assert_eq!(results[1].status, VerifyStatus::Error);
+        assert_eq!(results[2].status, VerifyStatus::Error);
+    }
+}
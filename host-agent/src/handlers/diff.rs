@@ -0,0 +1,322 @@
+use crate::error::{AgentError, Result};
+use crate::handlers::checksum::hash_file;
+use crate::handlers::encoding;
+use crate::jobs::Job;
+use crate::protocol::{
+    CompareMode, DiffEntry, DiffHunk, DiffLine, DiffStatus, FileDiff, HashAlgorithm, JobOutput,
+    LineChange,
+};
+use crate::security::Validator;
+use log::{debug, info};
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeSet;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+
+/// Lines of unchanged context around each hunk.
+const CONTEXT_LINES: usize = 3;
+
+/// Upper bound for the diff algorithm; past it a less minimal diff is
+/// returned rather than blocking on pathological inputs.
+const DIFF_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct DiffHandler {
+    validator: Validator,
+}
+
+impl DiffHandler {
+    pub fn new(validator: Validator) -> Self {
+        Self { validator }
+    }
+
+    /// Compares two text files line by line. Both files are subject to
+    /// `max_file_size` and may use any encoding `ReadFile` understands.
+    pub fn diff_files(&self, a: &str, b: &str) -> Result<FileDiff> {
+        info!("Diffing {} and {}", a, b);
+
+        let path_a = self.validator.validate_path(a)?;
+        let path_b = self.validator.validate_path(b)?;
+        for path in [&path_a, &path_b] {
+            if !fs::metadata(path)?.is_file() {
+                return Err(AgentError::InvalidRequest(format!(
+                    "Not a file: {}",
+                    path.display()
+                )));
+            }
+            self.validator.validate_file_size(path)?;
+        }
+
+        let bytes_a = fs::read(&path_a)?;
+        let bytes_b = fs::read(&path_b)?;
+
+        let label_a = path_a.to_string_lossy();
+        let label_b = path_b.to_string_lossy();
+        let diff = if bytes_a == bytes_b {
+            FileDiff {
+                a: label_a.to_string(),
+                b: label_b.to_string(),
+                identical: true,
+                unified: String::new(),
+                hunks: Vec::new(),
+            }
+        } else {
+            let (text_a, _) = encoding::decode(&bytes_a)?;
+            let (text_b, _) = encoding::decode(&bytes_b)?;
+            diff_text(&label_a, &label_b, &text_a, &text_b)
+        };
+
+        self.validator.audit_log("DIFF", &path_a, true);
+        Ok(diff)
+    }
+
+    /// Walks two directory trees side by side. Entries that exist on one
+    /// side only are reported once, without listing their contents.
+    pub fn diff_directories(
+        &self,
+        a: &str,
+        b: &str,
+        mode: CompareMode,
+        job: &Job,
+    ) -> Result<JobOutput> {
+        info!("Diffing directories {} and {} by {:?}", a, b, mode);
+
+        let root_a = self.validator.validate_path(a)?;
+        let root_b = self.validator.validate_path(b)?;
+        for root in [&root_a, &root_b] {
+            if !fs::metadata(root)?.is_dir() {
+                return Err(AgentError::InvalidRequest(format!(
+                    "Not a directory: {}",
+                    root.display()
+                )));
+            }
+        }
+
+        let mut entries = Vec::new();
+        self.compare_dirs(&root_a, &root_b, Path::new(""), mode, job, &mut entries)?;
+
+        let count = |status| {
+            entries
+                .iter()
+                .filter(|e: &&DiffEntry| e.status == status)
+                .count() as u64
+        };
+        let output = JobOutput::DirectoryDiff {
+            added: count(DiffStatus::Added),
+            removed: count(DiffStatus::Removed),
+            modified: count(DiffStatus::Modified),
+            entries,
+        };
+
+        self.validator.audit_log("DIFF_DIRS", &root_a, true);
+        Ok(output)
+    }
+
+    fn compare_dirs(
+        &self,
+        root_a: &Path,
+        root_b: &Path,
+        rel: &Path,
+        mode: CompareMode,
+        job: &Job,
+        entries: &mut Vec<DiffEntry>,
+    ) -> Result<()> {
+        let dir_a = root_a.join(rel);
+        let dir_b = root_b.join(rel);
+
+        let mut names = BTreeSet::new();
+        for dir in [&dir_a, &dir_b] {
+            for entry in fs::read_dir(dir)? {
+                names.insert(entry?.file_name());
+            }
+        }
+
+        for name in names {
+            job.check_cancelled()?;
+
+            let rel_path = rel.join(&name);
+            let path_a = dir_a.join(&name);
+            let path_b = dir_b.join(&name);
+            if let Err(e) = self
+                .validator
+                .validate_child(&path_a)
+                .and_then(|_| self.validator.validate_child(&path_b))
+            {
+                debug!("Skipping {:?}: {}", rel_path, e);
+                continue;
+            }
+            job.set_current(&rel_path.to_string_lossy());
+
+            let meta_a = fs::symlink_metadata(&path_a).ok();
+            let meta_b = fs::symlink_metadata(&path_b).ok();
+            let status = match (&meta_a, &meta_b) {
+                (Some(a), Some(b)) if a.is_dir() && b.is_dir() => {
+                    self.compare_dirs(root_a, root_b, &rel_path, mode, job, entries)?;
+                    None
+                }
+                (Some(a), Some(b)) => {
+                    if entries_differ(&path_a, a, &path_b, b, mode, job)? {
+                        Some(DiffStatus::Modified)
+                    } else {
+                        None
+                    }
+                }
+                (Some(_), None) => Some(DiffStatus::Removed),
+                (None, Some(_)) => Some(DiffStatus::Added),
+                (None, None) => None,
+            };
+            job.advance(1, 0);
+
+            if let Some(status) = status {
+                entries.push(DiffEntry {
+                    path: rel_path.to_string_lossy().to_string(),
+                    status,
+                    is_dir: meta_a.or(meta_b).is_some_and(|m| m.is_dir()),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decides whether two non-directory entries differ under `mode`.
+/// Symlinks are compared by target and never followed.
+pub fn entries_differ(
+    path_a: &Path,
+    a: &Metadata,
+    path_b: &Path,
+    b: &Metadata,
+    mode: CompareMode,
+    job: &Job,
+) -> Result<bool> {
+    if a.file_type() != b.file_type() {
+        return Ok(true);
+    }
+    if a.file_type().is_symlink() {
+        return Ok(fs::read_link(path_a)? != fs::read_link(path_b)?);
+    }
+    if a.len() != b.len() {
+        return Ok(true);
+    }
+
+    match mode {
+        CompareMode::Size => Ok(false),
+        CompareMode::Mtime => Ok(a.mtime() != b.mtime()),
+        CompareMode::Hash => {
+            // Hashed bytes are not reported as progress, callers size
+            // their jobs by entries or by bytes to copy
+            job.check_cancelled()?;
+            let algorithms = [HashAlgorithm::Blake3];
+            Ok(hash_file(path_a, &algorithms, None)? != hash_file(path_b, &algorithms, None)?)
+        }
+    }
+}
+
+/// Builds a unified diff and the matching structured hunks.
+pub fn diff_text(label_a: &str, label_b: &str, old: &str, new: &str) -> FileDiff {
+    let diff = TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .diff_lines(old, new);
+
+    let unified = diff
+        .unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(label_a, label_b)
+        .to_string();
+
+    let hunks = diff
+        .grouped_ops(CONTEXT_LINES)
+        .iter()
+        .filter_map(|group| {
+            let first = group.first()?;
+            let last = group.last()?;
+            let old_range = first.old_range().start..last.old_range().end;
+            let new_range = first.new_range().start..last.new_range().end;
+
+            let lines = group
+                .iter()
+                .flat_map(|op| diff.iter_changes(op))
+                .map(|change| DiffLine {
+                    kind: match change.tag() {
+                        ChangeTag::Equal => LineChange::Context,
+                        ChangeTag::Insert => LineChange::Insert,
+                        ChangeTag::Delete => LineChange::Delete,
+                    },
+                    content: change.value().trim_end_matches(['\n', '\r']).to_string(),
+                })
+                .collect();
+
+            Some(DiffHunk {
+                old_start: hunk_start(&old_range),
+                old_lines: old_range.len(),
+                new_start: hunk_start(&new_range),
+                new_lines: new_range.len(),
+                lines,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    FileDiff {
+        a: label_a.to_string(),
+        b: label_b.to_string(),
+        identical: hunks.is_empty(),
+        unified,
+        hunks,
+    }
+}
+
+/// Unified diff line numbers are 1-based, except that an empty range
+/// names the line before it.
+fn hunk_start(range: &std::ops::Range<usize>) -> usize {
+    if range.is_empty() {
+        range.start
+    } else {
+        range.start + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_text_hunks() {
+        let old = "one\ntwo\nthree\nfour\n";
+        let new = "one\n2\nthree\nfour\nfive\n";
+        let diff = diff_text("a.txt", "b.txt", old, new);
+
+        assert!(!diff.identical);
+        assert!(diff
+            .unified
+            .starts_with("--- a.txt\n+++ b.txt\n@@ -1,4 +1,5 @@\n"));
+        assert_eq!(diff.hunks.len(), 1);
+
+        let hunk = &diff.hunks[0];
+        assert_eq!((hunk.old_start, hunk.old_lines), (1, 4));
+        assert_eq!((hunk.new_start, hunk.new_lines), (1, 5));
+        let changed: Vec<_> = hunk
+            .lines
+            .iter()
+            .filter(|l| l.kind != LineChange::Context)
+            .map(|l| (l.kind, l.content.as_str()))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                (LineChange::Delete, "two"),
+                (LineChange::Insert, "2"),
+                (LineChange::Insert, "five"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_text_identical() {
+        let diff = diff_text("a", "b", "same\n", "same\n");
+        assert!(diff.identical);
+        assert!(diff.hunks.is_empty());
+        assert!(diff.unified.is_empty());
+    }
+}
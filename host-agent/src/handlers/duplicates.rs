@@ -0,0 +1,254 @@
+use crate::error::{AgentError, Result};
+use crate::handlers::checksum::hash_file;
+use crate::jobs::Job;
+use crate::protocol::{DuplicateGroup, HashAlgorithm, JobOutput};
+use crate::security::Validator;
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes hashed from the start and the end of a file before committing
+/// to a full hash.
+const PARTIAL_LEN: u64 = 16 * 1024;
+
+pub struct DuplicateHandler {
+    validator: Validator,
+}
+
+struct Candidate {
+    path: PathBuf,
+    size: u64,
+}
+
+impl DuplicateHandler {
+    pub fn new(validator: Validator) -> Self {
+        Self { validator }
+    }
+
+    /// Finds files with identical content below `roots`. Candidates are
+    /// narrowed by size, then by a hash of their head and tail, and only
+    /// the remaining ones are hashed in full. Hard links to the same inode
+    /// are counted once, as they do not take up extra space.
+    pub fn find_duplicates(&self, roots: &[String], min_size: u64, job: &Job) -> Result<JobOutput> {
+        info!("Finding duplicates in {:?} (min size {})", roots, min_size);
+
+        if roots.is_empty() {
+            return Err(AgentError::InvalidRequest("No roots to scan".to_string()));
+        }
+
+        let mut files = Vec::new();
+        let mut inodes = HashSet::new();
+        let root_paths = roots
+            .iter()
+            .map(|root| self.validator.validate_path(root))
+            .collect::<Result<Vec<_>>>()?;
+        for root_path in &root_paths {
+            self.collect(root_path, min_size, &mut inodes, &mut files, job)?;
+        }
+        let scanned = files.len() as u64;
+
+        let mut groups = group_by(files, |c| Ok(c.size))?;
+        groups = regroup(groups, |c| {
+            job.check_cancelled()?;
+            partial_hash(&c.path, c.size)
+        })?;
+
+        let total_bytes = groups.iter().flatten().map(|c| c.size).sum();
+        let total_files = groups.iter().map(|g| g.len() as u64).sum();
+        job.set_total(Some(total_files), Some(total_bytes));
+
+        let groups = regroup(groups, |c| {
+            job.set_current(&c.path.to_string_lossy());
+            let sums = hash_file(&c.path, &[HashAlgorithm::Blake3], Some(job));
+            // A file that cannot be read is skipped but still counts as done
+            job.advance(1, 0);
+            Ok(sums?.remove(&HashAlgorithm::Blake3).unwrap_or_default())
+        })?;
+
+        let mut result: Vec<DuplicateGroup> = groups
+            .into_iter()
+            .map(|group| {
+                let size = group[0].size;
+                let mut paths: Vec<String> = group
+                    .into_iter()
+                    .map(|c| c.path.to_string_lossy().to_string())
+                    .collect();
+                paths.sort();
+                DuplicateGroup {
+                    size,
+                    wasted: size * (paths.len() as u64 - 1),
+                    paths,
+                }
+            })
+            .collect();
+        result.sort_by(|a, b| b.wasted.cmp(&a.wasted).then_with(|| a.paths.cmp(&b.paths)));
+
+        let wasted = result.iter().map(|g| g.wasted).sum();
+        for root_path in &root_paths {
+            self.validator.audit_log("FIND_DUPLICATES", root_path, true);
+        }
+        Ok(JobOutput::Duplicates {
+            scanned,
+            wasted,
+            groups: result,
+        })
+    }
+
+    fn collect(
+        &self,
+        path: &Path,
+        min_size: u64,
+        inodes: &mut HashSet<(u64, u64)>,
+        files: &mut Vec<Candidate>,
+        job: &Job,
+    ) -> Result<()> {
+        job.check_cancelled()?;
+
+        // Symlinks are never followed, so every file is reached by its real path
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("{:?} vanished during the scan", path);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_file() {
+            if metadata.len() >= min_size && inodes.insert((metadata.dev(), metadata.ino())) {
+                files.push(Candidate {
+                    path: path.to_path_buf(),
+                    size: metadata.len(),
+                });
+            }
+            return Ok(());
+        }
+        if !metadata.is_dir() {
+            return Ok(());
+        }
+
+        job.set_current(&path.to_string_lossy());
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("Cannot read {:?}: {}", path, e);
+                return Ok(());
+            }
+        };
+        for entry in entries {
+            let entry_path = entry?.path();
+            if let Err(e) = self.validator.validate_child(&entry_path) {
+                debug!("Skipping {:?}: {}", entry_path, e);
+                continue;
+            }
+            self.collect(&entry_path, min_size, inodes, files, job)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits candidates by `key`, dropping groups with a single member.
+fn group_by<K, F>(candidates: Vec<Candidate>, mut key: F) -> Result<Vec<Vec<Candidate>>>
+where
+    K: Eq + std::hash::Hash,
+    F: FnMut(&Candidate) -> Result<K>,
+{
+    let mut groups: HashMap<K, Vec<Candidate>> = HashMap::new();
+    for candidate in candidates {
+        // Files that vanish or become unreadable mid-scan are left out
+        match key(&candidate) {
+            Ok(k) => groups.entry(k).or_default().push(candidate),
+            Err(AgentError::Cancelled) => return Err(AgentError::Cancelled),
+            Err(e) => debug!("Skipping {:?}: {}", candidate.path, e),
+        }
+    }
+    Ok(groups.into_values().filter(|g| g.len() > 1).collect())
+}
+
+fn regroup<K, F>(groups: Vec<Vec<Candidate>>, mut key: F) -> Result<Vec<Vec<Candidate>>>
+where
+    K: Eq + std::hash::Hash,
+    F: FnMut(&Candidate) -> Result<K>,
+{
+    let mut result = Vec::new();
+    for group in groups {
+        result.extend(group_by(group, &mut key)?);
+    }
+    Ok(result)
+}
+
+/// Hashes the first and last `PARTIAL_LEN` bytes. Files of equal size
+/// that differ usually do so near the start (headers) or the end.
+fn partial_hash(path: &Path, size: u64) -> Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = Vec::with_capacity(PARTIAL_LEN as usize);
+
+    (&mut file).take(PARTIAL_LEN).read_to_end(&mut buf)?;
+    hasher.update(&buf);
+
+    if size > PARTIAL_LEN * 2 {
+        buf.clear();
+        file.seek(SeekFrom::End(-(PARTIAL_LEN as i64)))?;
+        file.take(PARTIAL_LEN).read_to_end(&mut buf)?;
+        hasher.update(&buf);
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn test_groups_and_pruning() {
+        let root = TestDir::new("duplicates");
+        fs::create_dir_all(root.join("sub")).unwrap();
+
+        fs::write(root.join("a"), "same content").unwrap();
+        fs::write(root.join("sub/b"), "same content").unwrap();
+        fs::hard_link(root.join("a"), root.join("a-link")).unwrap();
+        // Same size, different head
+        fs::write(root.join("c"), "other conten").unwrap();
+        // Same head and tail, so only the full hash tells them apart
+        let big = |middle: u8| {
+            let mut data = vec![0u8; PARTIAL_LEN as usize * 3];
+            data[PARTIAL_LEN as usize + 1] = middle;
+            data
+        };
+        fs::write(root.join("big1"), big(1)).unwrap();
+        fs::write(root.join("big2"), big(2)).unwrap();
+        assert_eq!(
+            partial_hash(&root.join("big1"), PARTIAL_LEN * 3).unwrap(),
+            partial_hash(&root.join("big2"), PARTIAL_LEN * 3).unwrap()
+        );
+
+        let handler = DuplicateHandler::new(root.validator());
+        let job = Job::for_tests();
+        let output = handler
+            .find_duplicates(&[root.to_string_lossy().to_string()], 1, &job)
+            .unwrap();
+        let JobOutput::Duplicates { scanned, wasted, groups } = output else {
+            panic!("unexpected output");
+        };
+
+        // The hard link shares an inode with a and is counted once
+        assert_eq!(scanned, 5);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].paths.len(), 2);
+        assert!(groups[0].paths[1].ends_with("sub/b"));
+        assert_eq!(wasted, 12);
+
+        // Files removed between listing and scanning are skipped
+        let mut files = Vec::new();
+        handler
+            .collect(&root.join("gone"), 1, &mut HashSet::new(), &mut files, &job)
+            .unwrap();
+        assert!(files.is_empty());
+    }
+}
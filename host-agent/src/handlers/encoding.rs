@@ -0,0 +1,260 @@
+use crate::error::{AgentError, Result};
+use crate::protocol::{LineEnding, TextFormat};
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use std::cmp::Ordering;
+
+/// Decodes file content, detecting the encoding from a BOM, by trying
+/// BOM-less UTF-16 in both byte orders, or by statistical guessing for
+/// legacy single-byte encodings such as Windows-1250 or ISO-8859-2.
+pub fn decode(bytes: &[u8]) -> Result<(String, TextFormat)> {
+    let (encoding, bom_len) = match Encoding::for_bom(bytes) {
+        Some((encoding, len)) => (encoding, len),
+        None => (detect(bytes)?, 0),
+    };
+
+    let (content, had_errors) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+    if had_errors {
+        return Err(AgentError::InvalidRequest(format!(
+            "File is not valid {} text",
+            encoding.name()
+        )));
+    }
+
+    let format = TextFormat {
+        encoding: encoding.name().to_string(),
+        line_ending: line_ending(&content),
+        bom: bom_len > 0,
+    };
+    Ok((content.into_owned(), format))
+}
+
+fn detect(bytes: &[u8]) -> Result<&'static Encoding> {
+    // UTF-16 of most scripts read as UTF-8 is full of NULs and control
+    // characters, so it is only tried when UTF-8 does not fit cleanly
+    let utf8 = std::str::from_utf8(bytes).ok();
+    if !utf8.is_some_and(is_plain_text) {
+        if let Some(encoding) = detect_utf16(bytes) {
+            return Ok(encoding);
+        }
+    }
+    if utf8.is_some() {
+        return Ok(UTF_8);
+    }
+    if bytes.contains(&0) {
+        return Err(AgentError::InvalidRequest(
+            "File appears to be binary".to_string(),
+        ));
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    Ok(detector.guess(None, false))
+}
+
+/// Picks the byte order BOM-less UTF-16 decodes to plain text in. When
+/// both do, the high bytes are the ones that are zero more often or, for
+/// text without Latin characters, that take fewer distinct values.
+fn detect_utf16(bytes: &[u8]) -> Option<&'static Encoding> {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let decodes = |encoding: &'static Encoding| {
+        let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+        !had_errors && is_plain_text(&text)
+    };
+    match (decodes(UTF_16LE), decodes(UTF_16BE)) {
+        (true, false) => Some(UTF_16LE),
+        (false, true) => Some(UTF_16BE),
+        (false, false) => None,
+        (true, true) => {
+            let at = |offset: usize| bytes.iter().skip(offset).step_by(2);
+            let zeros = |offset| at(offset).filter(|&&b| b == 0).count();
+            let distinct = |offset| {
+                let mut seen = [false; 256];
+                at(offset).for_each(|&b| seen[b as usize] = true);
+                seen.iter().filter(|&&s| s).count()
+            };
+            // Little endian has its high bytes at odd offsets
+            match zeros(1).cmp(&zeros(0)).then(distinct(0).cmp(&distinct(1))) {
+                Ordering::Greater => Some(UTF_16LE),
+                Ordering::Less => Some(UTF_16BE),
+                Ordering::Equal => None,
+            }
+        }
+    }
+}
+
+/// Whether decoded text looks like something a person wrote: no NULs,
+/// no control characters beyond whitespace and escape sequences, and none
+/// of the private use characters that legacy 8-bit text turns into when
+/// misread as UTF-16.
+fn is_plain_text(text: &str) -> bool {
+    !text.chars().any(|c| {
+        (c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+            || ('\u{E000}'..='\u{F8FF}').contains(&c)
+            || matches!(c, '\u{FFFE}' | '\u{FFFF}')
+    })
+}
+
+/// Reports the line ending style, or `None` if the text has no line breaks.
+pub fn line_ending(text: &str) -> Option<LineEnding> {
+    let bytes = text.as_bytes();
+    let (mut lf, mut crlf, mut cr) = (0, 0, 0);
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\r' if bytes.get(i + 1) == Some(&b'\n') => {
+                crlf += 1;
+                i += 1;
+            }
+            b'\r' => cr += 1,
+            b'\n' => lf += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+
+    match (lf, crlf, cr) {
+        (0, 0, 0) => None,
+        (_, 0, 0) => Some(LineEnding::Lf),
+        (0, _, 0) => Some(LineEnding::Crlf),
+        (0, 0, _) => Some(LineEnding::Cr),
+        _ => Some(LineEnding::Mixed),
+    }
+}
+
+/// Rewrites every line break to the requested style. `Mixed` leaves the
+/// text untouched.
+pub fn convert_line_endings(text: &str, ending: LineEnding) -> String {
+    let target = match ending {
+        LineEnding::Lf => "\n",
+        LineEnding::Crlf => "\r\n",
+        LineEnding::Cr => "\r",
+        LineEnding::Mixed => return text.to_string(),
+    };
+
+    text.replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', target)
+}
+
+/// Encodes text for writing. Characters the target encoding cannot
+/// represent are rejected instead of being replaced, so a round trip
+/// never silently loses data.
+pub fn encode(text: &str, encoding: &str, bom: bool) -> Result<Vec<u8>> {
+    let encoding = Encoding::for_label(encoding.as_bytes())
+        .ok_or_else(|| AgentError::InvalidRequest(format!("Unknown encoding: {}", encoding)))?;
+
+    let mut out = Vec::with_capacity(text.len() + 3);
+
+    // encoding_rs only decodes UTF-16, so it is encoded by hand
+    if encoding == UTF_16LE || encoding == UTF_16BE {
+        let little_endian = encoding == UTF_16LE;
+        let units = bom.then_some(0xFEFF).into_iter().chain(text.encode_utf16());
+        for unit in units {
+            let bytes = if little_endian {
+                unit.to_le_bytes()
+            } else {
+                unit.to_be_bytes()
+            };
+            out.extend_from_slice(&bytes);
+        }
+        return Ok(out);
+    }
+
+    if bom && encoding == UTF_8 {
+        out.extend_from_slice(b"\xEF\xBB\xBF");
+    }
+
+    let (bytes, _, had_errors) = encoding.encode(text);
+    if had_errors {
+        return Err(AgentError::InvalidRequest(format!(
+            "Content cannot be represented in {}",
+            encoding.name()
+        )));
+    }
+    out.extend_from_slice(&bytes);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_legacy_and_utf16() {
+        let (text, format) = decode(b"\xEF\xBB\xBFahoj\r\n").unwrap();
+        assert_eq!(text, "ahoj\r\n");
+        assert_eq!(format.encoding, "UTF-8");
+        assert!(format.bom);
+        assert_eq!(format.line_ending, Some(LineEnding::Crlf));
+
+        let utf16: Vec<u8> = "key=value\n"
+            .encode_utf16()
+            .flat_map(|u| u.to_le_bytes())
+            .collect();
+        let (text, format) = decode(&utf16).unwrap();
+        assert_eq!(text, "key=value\n");
+        assert_eq!(format.encoding, "UTF-16LE");
+        assert!(!format.bom);
+
+        // Without a BOM and with few or no zero high bytes
+        for (text, encoding) in [
+            ("Příliš žluťoučký kůň úpěl ďábelské ódy", "UTF-16LE"),
+            ("Příliš žluťoučký kůň úpěl ďábelské ódy", "UTF-16BE"),
+            ("Ďábelské ódy\nĀĀ", "UTF-16BE"),
+            ("Привет, мир!", "UTF-16LE"),
+            ("ПриветМир", "UTF-16LE"),
+            ("日本語のテキストです。\n", "UTF-16BE"),
+        ] {
+            let bytes = encode(text, encoding, false).unwrap();
+            let (decoded, format) = decode(&bytes).unwrap();
+            assert_eq!((decoded.as_str(), format.encoding.as_str()), (text, encoding));
+        }
+        assert!(decode(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").is_err());
+
+        let cp1250 = encode(
+            "Příliš žluťoučký kůň úpěl ďábelské ódy",
+            "windows-1250",
+            false,
+        )
+        .unwrap();
+        let (text, format) = decode(&cp1250).unwrap();
+        assert_eq!(text, "Příliš žluťoučký kůň úpěl ďábelské ódy");
+        assert_eq!(format.encoding, "windows-1250");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let text = "čeština\nřádek";
+        for encoding in [
+            "UTF-8",
+            "UTF-16LE",
+            "UTF-16BE",
+            "windows-1250",
+            "ISO-8859-2",
+        ] {
+            let bytes = encode(text, encoding, true).unwrap();
+            let (decoded, _) = decode(&bytes).unwrap();
+            assert_eq!(decoded, text, "{}", encoding);
+        }
+
+        assert!(encode("日本語", "windows-1250", false).is_err());
+        assert!(encode("text", "no-such-encoding", false).is_err());
+    }
+
+    #[test]
+    fn test_line_endings() {
+        assert_eq!(line_ending("a\nb\n"), Some(LineEnding::Lf));
+        assert_eq!(line_ending("a\rb"), Some(LineEnding::Cr));
+        assert_eq!(line_ending("a\r\nb\n"), Some(LineEnding::Mixed));
+        assert_eq!(line_ending("single line"), None);
+        assert_eq!(
+            convert_line_endings("a\r\nb\rc\n", LineEnding::Crlf),
+            "a\r\nb\r\nc\r\n"
+        );
+    }
+}
@@ -1,24 +1,69 @@
+use crate::config::VersioningConfig;
 use crate::error::{AgentError, Result};
-use crate::protocol::FileInfo;
+use crate::handlers::archive::ProgressReader;
+use crate::handlers::diff;
+use crate::handlers::encoding;
+use crate::handlers::listing;
+use crate::handlers::metadata::{self, OwnerCache};
+use crate::handlers::permissions;
+use crate::handlers::versions::VersionStore;
+use crate::handlers::xattrs;
+use crate::jobs::Job;
+use crate::protocol::{
+    FileInfo, HexDump, HexRow, JobOutput, LockInfo, LineEnding, LinkInfo, ListOptions, SortKey, SortOrder, SyncAction,
+    SyncOp, SyncOptions, TextFormat, TreeNode,
+};
 use crate::security::Validator;
-use log::{debug, info};
-use serde::{Deserialize, Serialize};
-use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use base64::Engine;
+use log::{debug, info, warn};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{Group, User};
+use std::fs::{self, File, FileTimes, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 
+/// Entry limits for `ListTree` when the client does not ask for less.
+const DEFAULT_TREE_ENTRIES: usize = 1000;
+const MAX_TREE_ENTRIES: usize = 10_000;
+
+/// How many planned sync actions are listed in the job output.
+const MAX_SYNC_REPORT: usize = 1000;
+
+/// Largest range returned by a single `HexDump`.
+const MAX_HEX_LENGTH: usize = 64 * 1024;
+const HEX_ROW_LEN: usize = 16;
+
+/// One page of a directory listing.
+pub struct Listing {
+    pub files: Vec<FileInfo>,
+    pub total: usize,
+    pub next_cursor: Option<String>,
+}
+
 pub struct FileHandler {
     validator: Validator,
+    versions: VersionStore,
 }
 
 impl FileHandler {
-    pub fn new(validator: Validator) -> Self {
-        Self { validator }
+    pub fn new(validator: Validator, versioning: VersioningConfig) -> Self {
+        let versions = VersionStore::new(versioning, &validator.config().allowed_paths);
+        Self {
+            validator,
+            versions,
+        }
     }
 
-    pub fn list_files(&self, path: &str) -> Result<Vec<FileInfo>> {
+    pub fn list_files(
+        &self,
+        path: &str,
+        extended: bool,
+        options: &ListOptions,
+    ) -> Result<Listing> {
         info!("Listing files: {}", path);
 
         let validated_path = self.validator.validate_path(path)?;
@@ -30,15 +75,32 @@ impl FileHandler {
             ));
         }
 
+        let options = match &options.cursor {
+            Some(cursor) => listing::decode_cursor(cursor, path)?,
+            None => options.clone(),
+        };
+        let offset = options.offset;
+
         let entries = fs::read_dir(&validated_path)?;
         let mut files = Vec::new();
 
         for entry in entries {
             match entry {
                 Ok(entry) => {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if !options.show_hidden && name.starts_with('.') {
+                        continue;
+                    }
+                    if let Some(filter) = &options.filter {
+                        if !listing::matches_filter(&name, filter) {
+                            continue;
+                        }
+                    }
+
                     let entry_path = entry.path();
 
-                    let metadata = match entry.metadata() {
+                    // Symlinks are reported as links, never followed
+                    let metadata = match fs::symlink_metadata(&entry_path) {
                         Ok(m) => m,
                         Err(e) => {
                             debug!("Failed to read metadata for {:?}: {}", entry_path, e);
@@ -46,23 +108,7 @@ impl FileHandler {
                         }
                     };
 
-                    let modified = metadata
-                        .modified()
-                        .unwrap_or(SystemTime::UNIX_EPOCH)
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs() as i64;
-
-                    let permissions = format!("{:o}", metadata.permissions().mode() & 0o777);
-
-                    files.push(FileInfo {
-                        name: entry.file_name().to_string_lossy().to_string(),
-                        path: entry_path.to_string_lossy().to_string(),
-                        is_dir: metadata.is_dir(),
-                        size: if metadata.is_dir() { 0 } else { metadata.len() },
-                        modified,
-                        permissions,
-                    });
+                    files.push(Self::file_info(&entry_path, &metadata));
                 }
                 Err(e) => {
                     debug!("Error reading directory entry: {}", e);
@@ -71,51 +117,276 @@ impl FileHandler {
             }
         }
 
-        files.sort_by(|a, b| {
-            if a.is_dir == b.is_dir {
-                a.name.to_lowercase().cmp(&b.name.to_lowercase())
-            } else if a.is_dir {
-                std::cmp::Ordering::Less
-            } else {
-                std::cmp::Ordering::Greater
+        listing::sort_files(&mut files, options.sort_by, options.sort_order);
+
+        let total = files.len();
+        let end = match options.limit {
+            Some(limit) => offset.saturating_add(limit).min(total),
+            None => total,
+        };
+        let mut files: Vec<FileInfo> = files.drain(offset.min(total)..end).collect();
+
+        // Extended metadata is only gathered for the page being returned
+        if extended {
+            let mut owners = OwnerCache::new();
+            for info in files.iter_mut() {
+                let entry_path = Path::new(&info.path);
+                if let Ok(metadata) = fs::symlink_metadata(entry_path) {
+                    info.extended = Some(Box::new(metadata::extended_info(
+                        entry_path,
+                        &metadata,
+                        &mut owners,
+                        false,
+                    )));
+                }
             }
-        });
+        }
+
+        let next_cursor = (end < total).then(|| listing::encode_cursor(path, &options, end));
 
         self.validator.audit_log("LIST", &validated_path, true);
-        Ok(files)
+        Ok(Listing {
+            files,
+            total,
+            next_cursor,
+        })
     }
 
-    pub fn read_file(&self, path: &str) -> Result<(String, u64)> {
-        info!("Reading file: {}", path);
+    /// Lists a directory tree breadth-first, so that when `max_entries` is
+    /// reached the upper levels are complete and only deeper ones are cut.
+    pub fn list_tree(
+        &self,
+        path: &str,
+        depth: usize,
+        dirs_only: bool,
+        max_entries: Option<usize>,
+    ) -> Result<TreeNode> {
+        info!("Listing tree: {} (depth {})", path, depth);
 
         let validated_path = self.validator.validate_path(path)?;
+        let metadata = fs::metadata(&validated_path)?;
+        if !metadata.is_dir() {
+            return Err(AgentError::InvalidRequest(
+                "Path is not a directory".to_string(),
+            ));
+        }
 
-        let size = self.validator.validate_file_size(&validated_path)?;
+        let max_entries = max_entries
+            .unwrap_or(DEFAULT_TREE_ENTRIES)
+            .min(MAX_TREE_ENTRIES);
+
+        // Nodes live in a flat arena while the tree is built level by level
+        let mut nodes = vec![TreeNode {
+            info: Self::file_info(&validated_path, &metadata),
+            ..Default::default()
+        }];
+        let mut children: Vec<Option<Vec<usize>>> = vec![None];
+        let mut level = vec![0];
+        let mut count = 0;
+
+        for _ in 0..depth {
+            if level.is_empty() {
+                break;
+            }
+            let mut next_level = Vec::new();
+
+            for parent in level {
+                if count >= max_entries {
+                    nodes[parent].truncated = true;
+                    continue;
+                }
+
+                let dir = PathBuf::from(&nodes[parent].info.path);
+                // One unreadable directory should not fail the whole tree
+                let read_dir = match fs::read_dir(&dir) {
+                    Ok(read_dir) => read_dir,
+                    Err(e) => {
+                        debug!("Cannot read {:?}: {}", dir, e);
+                        nodes[parent].truncated = true;
+                        nodes[parent].error = Some(e.to_string());
+                        continue;
+                    }
+                };
+                let mut entries = Vec::new();
+                for entry in read_dir.flatten() {
+                    let entry_path = entry.path();
+                    if self.validator.validate_child(&entry_path).is_err() {
+                        continue;
+                    }
+                    let Ok(metadata) = fs::symlink_metadata(&entry_path) else {
+                        continue;
+                    };
+                    if dirs_only && !metadata.is_dir() {
+                        continue;
+                    }
+                    entries.push(Self::file_info(&entry_path, &metadata));
+                }
+                listing::sort_files(&mut entries, SortKey::Name, SortOrder::Asc);
+
+                let remaining = max_entries - count;
+                if entries.len() > remaining {
+                    entries.truncate(remaining);
+                    nodes[parent].truncated = true;
+                }
+                count += entries.len();
+
+                let mut ids = Vec::with_capacity(entries.len());
+                for info in entries {
+                    if info.is_dir {
+                        next_level.push(nodes.len());
+                    }
+                    ids.push(nodes.len());
+                    nodes.push(TreeNode {
+                        info,
+                        ..Default::default()
+                    });
+                    children.push(None);
+                }
+                children[parent] = Some(ids);
+            }
+
+            level = next_level;
+        }
+
+        // Directories on the last level were not expanded
+        for id in level {
+            nodes[id].truncated = true;
+        }
 
-        let content = fs::read_to_string(&validated_path).map_err(|e| {
-            if e.kind() == std::io::ErrorKind::InvalidData {
-                AgentError::InvalidRequest("File is not valid UTF-8 text".to_string())
+        let mut nodes: Vec<Option<TreeNode>> = nodes.into_iter().map(Some).collect();
+        let tree = Self::build_tree(0, &mut nodes, &children);
+
+        self.validator.audit_log("LIST_TREE", &validated_path, true);
+        Ok(tree)
+    }
+
+    fn build_tree(
+        id: usize,
+        nodes: &mut [Option<TreeNode>],
+        children: &[Option<Vec<usize>>],
+    ) -> TreeNode {
+        let mut node = nodes[id].take().unwrap_or_default();
+        node.children = children[id].as_ref().map(|ids| {
+            ids.iter()
+                .map(|&child| Self::build_tree(child, nodes, children))
+                .collect()
+        });
+        node
+    }
+
+    pub fn stat(&self, path: &str) -> Result<FileInfo> {
+        info!("Stat: {}", path);
+
+        let validated_path = self.validator.validate_link_path(path)?;
+
+        let link_meta = fs::symlink_metadata(&validated_path).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                AgentError::FileNotFound(path.to_string())
             } else {
                 AgentError::Io(e)
             }
         })?;
 
+        let mut info = Self::file_info(&validated_path, &link_meta);
+        info.extended = Some(Box::new(metadata::extended_info(
+            &validated_path,
+            &link_meta,
+            &mut OwnerCache::new(),
+            true,
+        )));
+
+        self.validator.audit_log("STAT", &validated_path, true);
+        Ok(info)
+    }
+
+    /// Reads a text file, detecting its encoding and line ending style.
+    /// The content is returned as-is apart from the charset conversion.
+    pub fn read_file(&self, path: &str) -> Result<(String, u64, TextFormat)> {
+        info!("Reading file: {}", path);
+
+        let validated_path = self.validator.validate_path(path)?;
+
+        let size = self.validator.validate_file_size(&validated_path)?;
+
+        let bytes = fs::read(&validated_path)?;
+        let (content, format) = encoding::decode(&bytes)?;
+
         self.validator.audit_log("READ", &validated_path, true);
-        Ok((content, size))
+        Ok((content, size, format))
+    }
+
+    /// Returns a byte range of any file as hex rows plus the raw bytes.
+    /// Only the requested range is read, so `max_file_size` does not apply.
+    pub fn hex_dump(&self, path: &str, offset: u64, length: usize) -> Result<HexDump> {
+        info!("Hex dump of {} at {} ({} bytes)", path, offset, length);
+
+        let validated_path = self.validator.validate_path(path)?;
+
+        // Checked before opening, as opening a FIFO blocks until a writer shows up
+        let metadata = fs::metadata(&validated_path)?;
+        if !metadata.is_file() {
+            return Err(AgentError::InvalidRequest("Path is not a file".to_string()));
+        }
+        let mut file = File::open(&validated_path)?;
+
+        let mut data = Vec::with_capacity(length.min(MAX_HEX_LENGTH));
+        file.seek(SeekFrom::Start(offset))?;
+        file.take(length.min(MAX_HEX_LENGTH) as u64)
+            .read_to_end(&mut data)?;
+
+        self.validator.audit_log("HEXDUMP", &validated_path, true);
+        Ok(HexDump {
+            offset,
+            size: metadata.len(),
+            rows: hex_rows(offset, &data),
+            data: base64::engine::general_purpose::STANDARD.encode(&data),
+        })
     }
 
-    pub fn write_file(&self, path: &str, content: &str) -> Result<()> {
+    /// Writes a text file, optionally converting line endings and
+    /// encoding it in something other than UTF-8.
+    pub fn write_file(
+        &self,
+        path: &str,
+        content: &str,
+        charset: Option<&str>,
+        line_ending: Option<LineEnding>,
+        bom: bool,
+    ) -> Result<()> {
         info!("Writing file: {}", path);
 
         let validated_path = self.validator.validate_path(path)?;
 
-        if content.len() as u64 > self.validator.config.max_file_size {
+        let content = match line_ending {
+            Some(ending) => encoding::convert_line_endings(content, ending),
+            None => content.to_string(),
+        };
+        let bytes = encoding::encode(&content, charset.unwrap_or("UTF-8"), bom)?;
+
+        if bytes.len() as u64 > self.validator.config().max_file_size {
             return Err(AgentError::PermissionDenied(
                 "Content size exceeds limit".to_string(),
             ));
         }
 
-        fs::write(&validated_path, content)?;
+        self.validator.check_lock(&validated_path)?;
+
+        // Overwriting only counts the growth against quotas
+        let existing = fs::metadata(&validated_path).map(|m| m.len()).unwrap_or(0);
+        let new_len = bytes.len() as u64;
+        let reservation = self
+            .validator
+            .check_quota(&validated_path, new_len.saturating_sub(existing))?;
+
+        // Losing history is preferable to failing the save
+        if let Err(e) = self.versions.snapshot(&self.validator, &validated_path, &bytes) {
+            warn!("Failed to keep version of {:?}: {}", validated_path, e);
+        }
+
+        fs::write(&validated_path, bytes)?;
+        reservation.commit();
+        self.validator
+            .release_quota(&validated_path, existing.saturating_sub(new_len));
 
         self.validator.audit_log("WRITE", &validated_path, true);
         Ok(())
@@ -135,23 +406,27 @@ impl FileHandler {
     pub fn delete(&self, path: &str) -> Result<()> {
         info!("Deleting: {}", path);
 
-        let validated_path = self.validator.validate_path(path)?;
+        // Deleting a symlink removes the link, not its target
+        let validated_path = self.validator.validate_link_path(path)?;
 
-        if !validated_path.exists() {
-            return Err(AgentError::FileNotFound(path.to_string()));
-        }
+        let metadata = fs::symlink_metadata(&validated_path)
+            .map_err(|_| AgentError::FileNotFound(path.to_string()))?;
+        self.validator.check_lock(&validated_path)?;
 
-        if validated_path.is_dir() {
+        let freed = self.quota_bytes(&validated_path)?;
+
+        if metadata.is_dir() {
             fs::remove_dir_all(&validated_path)?;
         } else {
             fs::remove_file(&validated_path)?;
         }
+        self.validator.release_quota(&validated_path, freed);
 
         self.validator.audit_log("DELETE", &validated_path, true);
         Ok(())
     }
 
-    pub fn copy(&self, from: &str, to: &str) -> Result<()> {
+    pub fn copy(&self, from: &str, to: &str, preserve_xattrs: bool) -> Result<()> {
         info!("Copying from {} to {}", from, to);
 
         let from_path = self.validator.validate_path(from)?;
@@ -160,49 +435,1240 @@ impl FileHandler {
         if !from_path.exists() {
             return Err(AgentError::FileNotFound(from.to_string()));
         }
+        self.validator.check_lock(&to_path)?;
 
         if from_path.is_dir() {
-            self.copy_dir_recursive(&from_path, &to_path)?;
+            self.copy_dir_recursive(&from_path, &to_path, preserve_xattrs, true)?;
         } else {
+            let bytes = fs::metadata(&from_path)?.len();
+            let reservation = self.validator.check_quota(&to_path, bytes)?;
+            // Overwriting a file only counts the growth
+            let replaced = replaced_bytes(&to_path);
             fs::copy(&from_path, &to_path)?;
+            reservation.commit();
+            self.validator.release_quota(&to_path, replaced);
+            if preserve_xattrs {
+                xattrs::copy_xattrs(&from_path, &to_path)?;
+            }
         }
 
         self.validator.audit_log("COPY", &from_path, true);
         Ok(())
     }
 
-    pub fn move_item(&self, from: &str, to: &str) -> Result<()> {
+    /// Renames in place where possible. Across filesystems the item is
+    /// copied and the source removed; a rename keeps attributes anyway, the
+    /// copy only when `preserve_xattrs` is set.
+    pub fn move_item(&self, from: &str, to: &str, preserve_xattrs: bool) -> Result<()> {
         info!("Moving from {} to {}", from, to);
 
-        let from_path = self.validator.validate_path(from)?;
+        let from_path = self.validator.validate_link_path(from)?;
         let to_path = self.validator.validate_path(to)?;
 
-        if !from_path.exists() {
+        if fs::symlink_metadata(&from_path).is_err() {
             return Err(AgentError::FileNotFound(from.to_string()));
         }
+        self.validator.check_lock(&from_path)?;
+        self.validator.check_lock(&to_path)?;
 
-        fs::rename(&from_path, &to_path)?;
+        // Even a rename can move data into a path quota; without any
+        // there is no need to walk the tree
+        let bytes = if self.validator.config().quotas.paths.is_empty() {
+            0
+        } else {
+            self.quota_bytes(&from_path)?
+        };
+        let reservation = self.validator.check_move_quota(&from_path, &to_path, bytes)?;
+
+        match fs::rename(&from_path, &to_path) {
+            Err(e) if e.raw_os_error() == Some(nix::libc::EXDEV) => {
+                debug!("{:?} is on another filesystem, copying", to_path);
+                self.move_across_devices(&from_path, &to_path, preserve_xattrs)?;
+            }
+            result => result?,
+        }
+        reservation.commit();
 
         self.validator.audit_log("MOVE", &from_path, true);
         Ok(())
     }
 
-    fn copy_dir_recursive(&self, from: &Path, to: &Path) -> Result<()> {
+    /// Takes or renews a lease on a file for the calling connection.
+    pub fn lock_file(&self, path: &str, owner: &str, duration_secs: u64) -> Result<LockInfo> {
+        info!("Locking {} for {}", path, owner);
+
+        let validated_path = self.validator.validate_path(path)?;
+        let session = self.validator.session().ok_or_else(|| {
+            AgentError::Internal("Locks require a client connection".to_string())
+        })?;
+
+        let lock = self
+            .validator
+            .locks()
+            .acquire(&validated_path, owner, session, duration_secs)?;
+
+        self.validator.audit_log("LOCK", &validated_path, true);
+        Ok(lock)
+    }
+
+    pub fn unlock_file(&self, path: &str) -> Result<()> {
+        info!("Unlocking {}", path);
+
+        let validated_path = self.validator.validate_path(path)?;
+        let session = self.validator.session().ok_or_else(|| {
+            AgentError::Internal("Locks require a client connection".to_string())
+        })?;
+
+        self.validator.locks().release(&validated_path, session)?;
+
+        self.validator.audit_log("UNLOCK", &validated_path, true);
+        Ok(())
+    }
+
+    pub fn list_locks(&self) -> Vec<LockInfo> {
+        self.validator.locks().list(self.validator.session())
+    }
+
+    pub fn create_symlink(&self, target: &str, link: &str) -> Result<()> {
+        info!("Creating symlink {} -> {}", link, target);
+
+        let link_path = self.validator.validate_link_path(link)?;
+        self.validator.validate_link_target(&link_path, target)?;
+
+        if fs::symlink_metadata(&link_path).is_ok() {
+            return Err(AgentError::InvalidRequest(
+                "Link path already exists".to_string(),
+            ));
+        }
+
+        // Store the target as given so relative links stay relative
+        std::os::unix::fs::symlink(target, &link_path)?;
+
+        self.validator.audit_log("SYMLINK", &link_path, true);
+        Ok(())
+    }
+
+    pub fn create_hardlink(&self, target: &str, link: &str) -> Result<()> {
+        info!("Creating hard link {} -> {}", link, target);
+
+        let target_path = self.validator.validate_path(target)?;
+        let link_path = self.validator.validate_link_path(link)?;
+
+        let metadata = fs::metadata(&target_path)
+            .map_err(|_| AgentError::FileNotFound(target.to_string()))?;
+        if metadata.is_dir() {
+            return Err(AgentError::InvalidRequest(
+                "Cannot hard link a directory".to_string(),
+            ));
+        }
+
+        fs::hard_link(&target_path, &link_path)?;
+
+        self.validator.audit_log("HARDLINK", &link_path, true);
+        Ok(())
+    }
+
+    pub fn read_link(&self, path: &str) -> Result<LinkInfo> {
+        info!("Reading link: {}", path);
+
+        let link_path = self.validator.validate_link_path(path)?;
+
+        let metadata = fs::symlink_metadata(&link_path)
+            .map_err(|_| AgentError::FileNotFound(path.to_string()))?;
+        if !metadata.file_type().is_symlink() {
+            return Err(AgentError::InvalidRequest("Path is not a symlink".to_string()));
+        }
+
+        let target = fs::read_link(&link_path)?.to_string_lossy().to_string();
+        let broken = fs::metadata(&link_path).is_err();
+
+        // Only reveal where the link points to if that is an allowed location
+        let resolved = if broken {
+            None
+        } else {
+            self.validator
+                .validate_link_target(&link_path, &target)
+                .ok()
+                .map(|p| p.to_string_lossy().to_string())
+        };
+
+        self.validator.audit_log("READLINK", &link_path, true);
+        Ok(LinkInfo {
+            path: link_path.to_string_lossy().to_string(),
+            target,
+            resolved,
+            broken,
+        })
+    }
+
+    pub fn set_permissions(&self, path: &str, mode: &str, recursive: bool) -> Result<()> {
+        info!("Setting permissions of {} to {}", path, mode);
+
+        self.validator.ensure_metadata_changes_allowed()?;
+        let validated_path = self.validator.validate_path(path)?;
+        self.validator.check_lock(&validated_path)?;
+
+        // Validate the mode up front so a bad spec fails before anything changes
+        let metadata = fs::metadata(&validated_path)?;
+        permissions::apply_mode(mode, metadata.mode(), metadata.is_dir())?;
+
+        self.walk(&validated_path, recursive, &mut |entry, metadata| {
+            // chmod on a symlink would change its target, which may lie outside the tree
+            if metadata.file_type().is_symlink() {
+                return Ok(());
+            }
+            let new_mode = permissions::apply_mode(mode, metadata.mode(), metadata.is_dir())?;
+            fs::set_permissions(entry, fs::Permissions::from_mode(new_mode))?;
+            Ok(())
+        })?;
+
+        self.validator.audit_log("CHMOD", &validated_path, true);
+        Ok(())
+    }
+
+    pub fn set_owner(
+        &self,
+        path: &str,
+        user: Option<&str>,
+        group: Option<&str>,
+        recursive: bool,
+    ) -> Result<()> {
+        info!("Setting owner of {} to {:?}:{:?}", path, user, group);
+
+        self.validator.ensure_metadata_changes_allowed()?;
+        let validated_path = self.validator.validate_path(path)?;
+        self.validator.check_lock(&validated_path)?;
+
+        if user.is_none() && group.is_none() {
+            return Err(AgentError::InvalidRequest(
+                "Either user or group must be given".to_string(),
+            ));
+        }
+        let uid = user.map(Self::resolve_uid).transpose()?;
+        let gid = group.map(Self::resolve_gid).transpose()?;
+
+        self.walk(&validated_path, recursive, &mut |entry, _metadata| {
+            std::os::unix::fs::lchown(entry, uid, gid)?;
+            Ok(())
+        })?;
+
+        self.validator.audit_log("CHOWN", &validated_path, true);
+        Ok(())
+    }
+
+    pub fn set_times(
+        &self,
+        path: &str,
+        accessed: Option<i64>,
+        modified: Option<i64>,
+    ) -> Result<()> {
+        info!("Setting times of {}", path);
+
+        self.validator.ensure_metadata_changes_allowed()?;
+        let validated_path = self.validator.validate_path(path)?;
+        self.validator.check_lock(&validated_path)?;
+
+        // Like touch(1), create the file if it does not exist yet
+        if !validated_path.exists() {
+            File::create(&validated_path)?;
+        }
+
+        // Without any times both become now; otherwise a time that is not
+        // given keeps its current value. Setting them by path does not open
+        // the file, so FIFOs and unreadable files work as with touch(1)
+        let time = |secs: Option<i64>| match secs {
+            Some(secs) => TimeSpec::new(secs as nix::libc::time_t, 0),
+            None if accessed.is_none() && modified.is_none() => {
+                TimeSpec::new(0, nix::libc::UTIME_NOW)
+            }
+            None => TimeSpec::new(0, nix::libc::UTIME_OMIT),
+        };
+        utimensat(
+            None,
+            &validated_path,
+            &time(accessed),
+            &time(modified),
+            UtimensatFlags::FollowSymlink,
+        )
+        .map_err(std::io::Error::from)?;
+
+        self.validator.audit_log("TOUCH", &validated_path, true);
+        Ok(())
+    }
+
+    /// Calls `f` for `root` and, when `recursive` is set, for every entry
+    /// below it that passes the validator. Symlinked directories are not
+    /// descended into.
+    fn walk(
+        &self,
+        root: &Path,
+        recursive: bool,
+        f: &mut dyn FnMut(&Path, &Metadata) -> Result<()>,
+    ) -> Result<()> {
+        let metadata = fs::symlink_metadata(root)?;
+        f(root, &metadata)?;
+
+        if !recursive || !metadata.is_dir() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(root)? {
+            let entry_path = entry?.path();
+            if let Err(e) = self.validator.validate_child(&entry_path) {
+                debug!("Skipping {:?}: {}", entry_path, e);
+                continue;
+            }
+            self.walk(&entry_path, true, f)?;
+        }
+
+        Ok(())
+    }
+
+    /// One-way sync of `source` into `dest`: copies new and changed
+    /// entries and, with `delete`, removes entries that are gone from the
+    /// source. Excluded entries are neither copied nor deleted. The plan
+    /// is built first, so a dry run reports exactly what a real run does.
+    pub fn sync_directories(
+        &self,
+        source: &str,
+        dest: &str,
+        options: &SyncOptions,
+        job: &Job,
+    ) -> Result<JobOutput> {
+        info!("Syncing {} to {} (dry run: {})", source, dest, options.dry_run);
+
+        let source_path = self.validator.validate_path(source)?;
+        let dest_path = self.validator.validate_path(dest)?;
+        self.validator.check_lock(&dest_path)?;
+
+        if !fs::metadata(&source_path)?.is_dir() {
+            return Err(AgentError::InvalidRequest(
+                "Source is not a directory".to_string(),
+            ));
+        }
+        if dest_path.starts_with(&source_path) || source_path.starts_with(&dest_path) {
+            return Err(AgentError::InvalidRequest(
+                "Source and destination overlap".to_string(),
+            ));
+        }
+        match fs::symlink_metadata(&dest_path) {
+            Ok(metadata) if !metadata.is_dir() => {
+                return Err(AgentError::InvalidRequest(
+                    "Destination is not a directory".to_string(),
+                ))
+            }
+            Ok(_) => {}
+            Err(_) if options.dry_run => {}
+            Err(_) => fs::create_dir_all(&dest_path)?,
+        }
+
+        let dest_exists = fs::metadata(&dest_path).is_ok();
+        let mut plan = Vec::new();
+        self.plan_sync(
+            &source_path,
+            &dest_path,
+            Path::new(""),
+            dest_exists,
+            options,
+            job,
+            &mut plan,
+        )?;
+
+        let count = |op| plan.iter().filter(|a: &&SyncAction| a.op == op).count() as u64;
+        let (copied, created_dirs, deleted) = (
+            count(SyncOp::Copy),
+            count(SyncOp::CreateDir),
+            count(SyncOp::Delete),
+        );
+        let bytes = plan.iter().map(|a| a.size).sum();
+        job.set_total(Some(plan.len() as u64), Some(bytes));
+
+        if !options.dry_run {
+            for action in &plan {
+                job.check_cancelled()?;
+                job.set_current(&action.path);
+
+                let from = source_path.join(&action.path);
+                let to = dest_path.join(&action.path);
+                if let Err(e) = self.apply_sync_action(action, &from, &to, job) {
+                    job.check_cancelled()?;
+                    return Err(e);
+                }
+                job.advance(1, 0);
+            }
+        }
+
+        let truncated = plan.len() > MAX_SYNC_REPORT;
+        plan.truncate(MAX_SYNC_REPORT);
+
+        self.validator.audit_log("SYNC", &dest_path, true);
+        Ok(JobOutput::Sync {
+            dry_run: options.dry_run,
+            copied,
+            created_dirs,
+            deleted,
+            bytes,
+            actions: plan,
+            truncated,
+        })
+    }
+
+    /// `dest_exists` tells whether `rel` is a real directory in the
+    /// destination. When it is missing or a link that CreateDir will
+    /// replace, nothing below it is compared or deleted.
+    #[allow(clippy::too_many_arguments)]
+    fn plan_sync(
+        &self,
+        source_root: &Path,
+        dest_root: &Path,
+        rel: &Path,
+        dest_exists: bool,
+        options: &SyncOptions,
+        job: &Job,
+        plan: &mut Vec<SyncAction>,
+    ) -> Result<()> {
+        let source_dir = source_root.join(rel);
+        let dest_dir = dest_root.join(rel);
+
+        let mut names: Vec<_> = fs::read_dir(&source_dir)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<std::io::Result<_>>()?;
+        names.sort();
+
+        for name in &names {
+            job.check_cancelled()?;
+
+            let rel_path = rel.join(name);
+            let from = source_dir.join(name);
+            let to = dest_dir.join(name);
+            if Self::is_excluded(&rel_path, &options.exclude) {
+                continue;
+            }
+            if let Err(e) = self
+                .validator
+                .validate_child(&from)
+                .and_then(|_| self.validator.validate_child(&to))
+            {
+                debug!("Skipping {:?}: {}", rel_path, e);
+                continue;
+            }
+
+            let source_meta = fs::symlink_metadata(&from)?;
+            let dest_meta = if dest_exists {
+                fs::symlink_metadata(&to).ok()
+            } else {
+                None
+            };
+            let path = rel_path.to_string_lossy().to_string();
+
+            if source_meta.is_dir() {
+                // A link in place of the directory is replaced like a file
+                let dest_is_dir = dest_meta.as_ref().is_some_and(|m| m.is_dir());
+                if !dest_is_dir {
+                    plan.push(SyncAction {
+                        path,
+                        op: SyncOp::CreateDir,
+                        size: 0,
+                    });
+                }
+                self.plan_sync(
+                    source_root,
+                    dest_root,
+                    &rel_path,
+                    dest_is_dir,
+                    options,
+                    job,
+                    plan,
+                )?;
+                continue;
+            }
+
+            let file_type = source_meta.file_type();
+            if !file_type.is_file() && !file_type.is_symlink() {
+                debug!("Skipping special file {:?}", rel_path);
+                continue;
+            }
+            if file_type.is_symlink() {
+                let target = fs::read_link(&from)?;
+                if let Err(e) = self
+                    .validator
+                    .validate_link_target(&to, &target.to_string_lossy())
+                {
+                    debug!("Skipping link {:?}: {}", rel_path, e);
+                    continue;
+                }
+            }
+
+            let changed = match &dest_meta {
+                Some(dest_meta) => {
+                    diff::entries_differ(&from, &source_meta, &to, dest_meta, options.compare, job)?
+                }
+                None => true,
+            };
+            if changed {
+                plan.push(SyncAction {
+                    path,
+                    op: SyncOp::Copy,
+                    size: if file_type.is_file() { source_meta.len() } else { 0 },
+                });
+            }
+        }
+
+        if !options.delete || !dest_exists {
+            return Ok(());
+        }
+
+        let mut extraneous = Vec::new();
+        for entry in fs::read_dir(&dest_dir)? {
+            let name = entry?.file_name();
+            let rel_path = rel.join(&name);
+            if names.contains(&name)
+                || Self::is_excluded(&rel_path, &options.exclude)
+                || self.validator.validate_child(&dest_dir.join(&name)).is_err()
+            {
+                continue;
+            }
+            extraneous.push(rel_path.to_string_lossy().to_string());
+        }
+        extraneous.sort();
+        plan.extend(extraneous.into_iter().map(|path| SyncAction {
+            path,
+            op: SyncOp::Delete,
+            size: 0,
+        }));
+
+        Ok(())
+    }
+
+    /// Exclude patterns containing a `/` match the path relative to the
+    /// sync root, all others match the entry name alone.
+    fn is_excluded(rel_path: &Path, patterns: &[String]) -> bool {
+        let rel = rel_path.to_string_lossy();
+        let name = rel_path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+
+        patterns.iter().any(|pattern| {
+            if pattern.contains('/') {
+                let pattern = pattern.trim_start_matches('/');
+                listing::glob_match(pattern.as_bytes(), rel.as_bytes())
+            } else {
+                listing::glob_match(pattern.as_bytes(), name.as_bytes())
+            }
+        })
+    }
+
+    /// Carries out one planned action. Each settles its own quota, so a
+    /// run that fails partway leaves the tracker matching the disk: copies
+    /// are charged as they are written, and whatever an action replaces
+    /// or deletes is given back.
+    fn apply_sync_action(
+        &self,
+        action: &SyncAction,
+        from: &Path,
+        to: &Path,
+        job: &Job,
+    ) -> Result<()> {
+        let freed = match fs::symlink_metadata(to) {
+            Ok(_) => self.quota_bytes(to)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        match action.op {
+            SyncOp::CreateDir => Self::sync_dir(from, to)?,
+            SyncOp::Copy => {
+                let reservation = self.validator.check_quota(to, action.size)?;
+                Self::sync_file(from, to, job)?;
+                reservation.commit();
+            }
+            SyncOp::Delete => Self::remove_entry(to)?,
+        }
+        self.validator.release_quota(to, freed);
+        Ok(())
+    }
+
+    fn sync_dir(from: &Path, to: &Path) -> Result<()> {
+        if fs::symlink_metadata(to).is_ok() {
+            Self::remove_entry(to)?;
+        }
+        fs::create_dir(to)?;
+        fs::set_permissions(to, fs::symlink_metadata(from)?.permissions())?;
+        Ok(())
+    }
+
+    /// Copies a file through a temporary name next to the target, so an
+    /// interrupted sync never leaves a truncated file under the real name.
+    /// Permissions and mtime are carried over for the next comparison.
+    fn sync_file(from: &Path, to: &Path, job: &Job) -> Result<()> {
+        let metadata = fs::symlink_metadata(from)?;
+        if fs::symlink_metadata(to).is_ok_and(|m| m.is_dir()) {
+            Self::remove_entry(to)?;
+        }
+
+        let name = to.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = to.with_file_name(format!(".{}.sync-{}", name, std::process::id()));
+
+        let result = (|| -> Result<()> {
+            if metadata.file_type().is_symlink() {
+                std::os::unix::fs::symlink(fs::read_link(from)?, &tmp_path)?;
+            } else {
+                let mut reader = ProgressReader::new(File::open(from)?, job);
+                let mut writer = File::create(&tmp_path)?;
+                std::io::copy(&mut reader, &mut writer)?;
+                writer.set_permissions(metadata.permissions())?;
+                writer.set_times(FileTimes::new().set_modified(metadata.modified()?))?;
+            }
+            fs::rename(&tmp_path, to)?;
+            Ok(())
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    /// Makes way for copying an entry of type `kind` to `to`. A directory
+    /// over a directory is merged and a file over a file overwritten;
+    /// anything else already there, links in particular, is removed
+    /// rather than written through.
+    fn clear_destination(&self, to: &Path, kind: fs::FileType) -> Result<()> {
+        let existing = match fs::symlink_metadata(to) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if (existing.is_dir() && kind.is_dir()) || (existing.is_file() && kind.is_file()) {
+            return Ok(());
+        }
+        let freed = self.quota_bytes(to)?;
+        Self::remove_entry(to)?;
+        self.validator.release_quota(to, freed);
+        Ok(())
+    }
+
+    fn remove_entry(path: &Path) -> Result<()> {
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_uid(name: &str) -> Result<u32> {
+        if let Ok(uid) = name.parse() {
+            return Ok(uid);
+        }
+        match User::from_name(name) {
+            Ok(Some(user)) => Ok(user.uid.as_raw()),
+            Ok(None) => Err(AgentError::InvalidRequest(format!("Unknown user: {}", name))),
+            Err(e) => Err(AgentError::Internal(format!("User lookup failed: {}", e))),
+        }
+    }
+
+    fn resolve_gid(name: &str) -> Result<u32> {
+        if let Ok(gid) = name.parse() {
+            return Ok(gid);
+        }
+        match Group::from_name(name) {
+            Ok(Some(group)) => Ok(group.gid.as_raw()),
+            Ok(None) => Err(AgentError::InvalidRequest(format!("Unknown group: {}", name))),
+            Err(e) => Err(AgentError::Internal(format!("Group lookup failed: {}", e))),
+        }
+    }
+
+    fn file_info(path: &Path, metadata: &Metadata) -> FileInfo {
+        let modified = metadata
+            .modified()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        let permissions = format!("{:o}", metadata.permissions().mode() & 0o777);
+
+        FileInfo {
+            name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.to_string_lossy().to_string()),
+            path: path.to_string_lossy().to_string(),
+            is_dir: metadata.is_dir(),
+            is_symlink: metadata.file_type().is_symlink(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified,
+            permissions,
+            extended: None,
+        }
+    }
+
+    /// Copies `from` to `to` and removes the source. A destination this
+    /// created is removed again if the copy fails or something in the
+    /// source tree was locked in the meantime.
+    fn move_across_devices(&self, from: &Path, to: &Path, preserve_xattrs: bool) -> Result<()> {
+        let metadata = fs::symlink_metadata(from)?;
+        let existed = fs::symlink_metadata(to).is_ok();
+
+        let copied = self.copy_item(from, to, &metadata, preserve_xattrs).and_then(|_| {
+            // The copy can take a while; a lease taken on anything in the
+            // tree since the first check must still stop the move
+            self.validator.check_lock(from)
+        });
+        if let Err(e) = copied {
+            if !existed {
+                let cleanup = if metadata.is_dir() {
+                    fs::remove_dir_all(to)
+                } else {
+                    fs::remove_file(to)
+                };
+                if let Err(err) = cleanup {
+                    if err.kind() != std::io::ErrorKind::NotFound {
+                        warn!("Failed to remove partial copy {:?}: {}", to, err);
+                    }
+                }
+            }
+            return Err(e);
+        }
+
+        if metadata.is_dir() {
+            fs::remove_dir_all(from)?;
+        } else {
+            fs::remove_file(from)?;
+        }
+        Ok(())
+    }
+
+    fn copy_item(
+        &self,
+        from: &Path,
+        to: &Path,
+        metadata: &Metadata,
+        preserve_xattrs: bool,
+    ) -> Result<()> {
+        if metadata.is_dir() {
+            self.copy_dir_recursive(from, to, preserve_xattrs, false)?;
+        } else if metadata.file_type().is_symlink() {
+            let target = fs::read_link(from)?;
+            self.validator
+                .validate_link_target(to, &target.to_string_lossy())?;
+            std::os::unix::fs::symlink(target, to)?;
+        } else {
+            fs::copy(from, to)?;
+            if preserve_xattrs {
+                xattrs::copy_xattrs(from, to)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes of regular files at or below `path`, as quotas count them.
+    fn quota_bytes(&self, path: &Path) -> Result<u64> {
+        let mut bytes = 0;
+        self.walk(path, true, &mut |_, metadata| {
+            if metadata.is_file() {
+                bytes += metadata.len();
+            }
+            Ok(())
+        })?;
+        Ok(bytes)
+    }
+
+    /// Copies a tree, giving back the quota of whatever it replaces. With
+    /// `charge` every file reserves its own size as it is written; a move
+    /// has reserved the whole tree up front instead.
+    fn copy_dir_recursive(
+        &self,
+        from: &Path,
+        to: &Path,
+        preserve_xattrs: bool,
+        charge: bool,
+    ) -> Result<()> {
         fs::create_dir_all(to)?;
+        if preserve_xattrs {
+            xattrs::copy_xattrs(from, to)?;
+        }
 
         for entry in fs::read_dir(from)? {
             let entry = entry?;
             let file_type = entry.file_type()?;
             let from_path = entry.path();
             let to_path = to.join(entry.file_name());
+            self.clear_destination(&to_path, file_type)?;
 
             if file_type.is_dir() {
-                self.copy_dir_recursive(&from_path, &to_path)?;
+                self.copy_dir_recursive(&from_path, &to_path, preserve_xattrs, charge)?;
+            } else if file_type.is_symlink() {
+                // Recreate links instead of copying whatever they point to,
+                // as long as they point somewhere allowed from the new place
+                let target = fs::read_link(&from_path)?;
+                self.validator
+                    .validate_link_target(&to_path, &target.to_string_lossy())?;
+                std::os::unix::fs::symlink(target, &to_path)?;
             } else {
+                let reservation = match charge {
+                    true => Some(self.validator.check_quota(&to_path, entry.metadata()?.len())?),
+                    false => None,
+                };
+                let replaced = replaced_bytes(&to_path);
                 fs::copy(&from_path, &to_path)?;
+                if let Some(reservation) = reservation {
+                    reservation.commit();
+                }
+                self.validator.release_quota(&to_path, replaced);
+                if preserve_xattrs {
+                    xattrs::copy_xattrs(&from_path, &to_path)?;
+                }
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Size of the regular file at `path` that a copy is about to overwrite.
+fn replaced_bytes(path: &Path) -> u64 {
+    fs::symlink_metadata(path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map_or(0, |m| m.len())
+}
+
+/// Formats bytes like `hexdump -C`: sixteen per row, split into two
+/// groups of eight, with non-printable bytes shown as `.`.
+fn hex_rows(offset: u64, data: &[u8]) -> Vec<HexRow> {
+    data.chunks(HEX_ROW_LEN)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex = chunk
+                .iter()
+                .enumerate()
+                .map(|(j, b)| {
+                    let sep = if j == 0 { "" } else if j == 8 { "  " } else { " " };
+                    format!("{}{:02x}", sep, b)
+                })
+                .collect();
+            let ascii = chunk
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+
+            HexRow {
+                offset: offset + (i * HEX_ROW_LEN) as u64,
+                hex,
+                ascii,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use crate::handlers::xattrs::XattrHandler;
+    use crate::protocol::FileType;
+
+    #[test]
+    fn test_hex_rows() {
+        let data = b"Hello, world!\n\x00\xffXYZ";
+        let rows = hex_rows(0x100, data);
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].offset, 0x100);
+        assert_eq!(
+            rows[0].hex,
+            "48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff"
+        );
+        assert_eq!(rows[0].ascii, "Hello, world!...");
+        assert_eq!(rows[1].offset, 0x110);
+        assert_eq!(rows[1].hex, "58 59 5a");
+        assert_eq!(rows[1].ascii, "XYZ");
+    }
+
+    fn test_root(name: &str) -> (TestDir, FileHandler) {
+        let root = TestDir::new(&format!("files-{}", name));
+        let handler = FileHandler::new(root.validator(), VersioningConfig::default());
+        (root, handler)
+    }
+
+    #[test]
+    fn test_stat_and_extended_listing() {
+        let (root, handler) = test_root("stat");
+        fs::write(root.join("notes.txt"), "hello").unwrap();
+        fs::write(root.join(".hidden"), "").unwrap();
+        std::os::unix::fs::symlink("notes.txt", root.join("link")).unwrap();
+        std::os::unix::fs::symlink("missing", root.join("broken")).unwrap();
+
+        let file = handler.stat(&root.join("notes.txt").to_string_lossy()).unwrap();
+        let extended = file.extended.unwrap();
+        assert_eq!(file.size, 5);
+        assert_eq!(extended.file_type, FileType::File);
+        assert_eq!(extended.mime_type.as_deref(), Some("text/plain"));
+        assert_eq!(extended.nlink, 1);
+        assert!(!extended.hidden);
+
+        // Links are reported as links, not as what they point to
+        let link = handler.stat(&root.join("link").to_string_lossy()).unwrap();
+        assert!(link.is_symlink);
+        let extended = link.extended.unwrap();
+        assert_eq!(extended.file_type, FileType::Symlink);
+        assert_eq!(extended.symlink_target.as_deref(), Some("notes.txt"));
+        assert!(!extended.broken_link);
+
+        let listing = handler
+            .list_files(&root.to_string_lossy(), true, &ListOptions::default())
+            .unwrap();
+        let find = |name: &str| {
+            listing
+                .files
+                .iter()
+                .find(|f| f.name == name)
+                .and_then(|f| f.extended.as_ref())
+                .unwrap()
+        };
+        assert_eq!(listing.total, 4);
+        assert!(find("broken").broken_link);
+        assert!(find(".hidden").hidden);
+        assert_eq!(find("notes.txt").uid, fs::metadata(&root).unwrap().uid());
+
+        assert!(matches!(
+            handler.stat(&root.join("nope").to_string_lossy()),
+            Err(AgentError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_set_times_keeps_unset_time() {
+        let (root, mut handler) = test_root("times");
+        handler.validator.config.allow_metadata_changes = true;
+        let path = root.join("file");
+        let path_str = path.to_string_lossy();
+
+        handler.set_times(&path_str, Some(1_000_000), Some(2_000_000)).unwrap();
+        handler.set_times(&path_str, None, Some(3_000_000)).unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.atime(), 1_000_000);
+        assert_eq!(metadata.mtime(), 3_000_000);
+
+        // A FIFO is touched without opening it, which would block
+        let fifo = root.join("fifo");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU).unwrap();
+        handler.set_times(&fifo.to_string_lossy(), None, Some(4_000_000)).unwrap();
+        assert_eq!(fs::metadata(&fifo).unwrap().mtime(), 4_000_000);
+    }
+
+    #[test]
+    fn test_copy_rejects_link_escaping_from_new_place() {
+        let (root, handler) = test_root("copylink");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("target"), "x").unwrap();
+        // Fine in a/b, but copied one level up it points above root
+        std::os::unix::fs::symlink("../../target", root.join("a/b/link")).unwrap();
+
+        let from = root.join("a/b").to_string_lossy().to_string();
+        assert!(handler
+            .copy(&from, &root.join("a/c").to_string_lossy(), false)
+            .is_ok());
+        assert!(matches!(
+            handler.copy(&from, &root.join("c").to_string_lossy(), false),
+            Err(AgentError::PathNotAllowed(_))
+        ));
+    }
+
+    #[test]
+    fn test_copy_replaces_links_in_destination() {
+        let (root, _) = test_root("copyover");
+        let inside = root.join("inside");
+        fs::create_dir_all(inside.join("src/dir")).unwrap();
+        fs::create_dir_all(root.join("outside")).unwrap();
+        fs::write(inside.join("src/dir/file"), "new").unwrap();
+        fs::write(root.join("outside/file"), "secret").unwrap();
+        std::os::unix::fs::symlink("dir/file", inside.join("src/link")).unwrap();
+        let handler = FileHandler::new(Validator::for_tests(&inside), VersioningConfig::default());
+
+        let from = inside.join("src").to_string_lossy().to_string();
+        let to = inside.join("dest");
+        handler.copy(&from, &to.to_string_lossy(), false).unwrap();
+        // Copying again over the links made the first time
+        handler.copy(&from, &to.to_string_lossy(), false).unwrap();
+        assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("dir/file"));
+
+        // A link planted in the destination is replaced, not followed
+        fs::remove_dir_all(to.join("dir")).unwrap();
+        std::os::unix::fs::symlink(root.join("outside"), to.join("dir")).unwrap();
+        handler.copy(&from, &to.to_string_lossy(), false).unwrap();
+        assert!(fs::symlink_metadata(to.join("dir")).unwrap().is_dir());
+        assert_eq!(fs::read_to_string(to.join("dir/file")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(root.join("outside/file")).unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_list_tree_limits() {
+        let (root, handler) = test_root("tree");
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        fs::write(root.join("a/b/c/deep"), "").unwrap();
+        for name in ["f1", "f2", "f3"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+        let root_str = root.to_string_lossy();
+        let names = |node: &TreeNode| -> Vec<String> {
+            let children = node.children.as_deref().unwrap_or_default();
+            children.iter().map(|c| c.info.name.clone()).collect()
+        };
+
+        let tree = handler.list_tree(&root_str, 1, false, None).unwrap();
+        assert_eq!(names(&tree), ["a", "f1", "f2", "f3"]);
+        assert!(!tree.truncated);
+        let a = &tree.children.as_ref().unwrap()[0];
+        assert!(a.children.is_none() && a.truncated);
+
+        let tree = handler.list_tree(&root_str, 3, true, None).unwrap();
+        let b = &tree.children.as_ref().unwrap()[0].children.as_ref().unwrap()[0];
+        assert_eq!(names(b), ["c"]);
+        assert!(b.children.as_ref().unwrap()[0].truncated);
+
+        let tree = handler.list_tree(&root_str, 3, false, Some(2)).unwrap();
+        assert_eq!(names(&tree), ["a", "f1"]);
+        assert!(tree.truncated);
+    }
+
+    #[test]
+    fn test_list_tree_skips_unreadable_dir() {
+        let (root, handler) = test_root("tree-unreadable");
+        fs::create_dir_all(root.join("locked/inner")).unwrap();
+        fs::create_dir_all(root.join("open/inner")).unwrap();
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        let tree = handler.list_tree(&root.to_string_lossy(), 2, false, None);
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let tree = tree.unwrap();
+        let children = tree.children.unwrap();
+        assert_eq!(children[1].children.as_ref().unwrap().len(), 1);
+        // Permissions do not stop root, which still sees everything
+        if !nix::unistd::geteuid().is_root() {
+            assert!(children[0].truncated && children[0].error.is_some());
+        }
+    }
+
+    #[test]
+    fn test_sync_exclude_and_delete() {
+        let (root, handler) = test_root("sync");
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        fs::create_dir_all(source.join("docs/cache")).unwrap();
+        fs::write(source.join("docs/readme.txt"), "new").unwrap();
+        fs::write(source.join("docs/cache/blob"), "x").unwrap();
+        fs::write(source.join("notes.tmp"), "x").unwrap();
+        fs::create_dir_all(dest.join("old")).unwrap();
+        fs::write(dest.join("stale.txt"), "x").unwrap();
+        fs::write(dest.join("keep.tmp"), "x").unwrap();
+
+        let options = SyncOptions {
+            delete: true,
+            exclude: vec!["*.tmp".to_string(), "docs/cache".to_string()],
+            ..Default::default()
+        };
+        let sync = |dry_run| {
+            let options = SyncOptions { dry_run, ..options.clone() };
+            let output = handler
+                .sync_directories(
+                    &source.to_string_lossy(),
+                    &dest.to_string_lossy(),
+                    &options,
+                    &Job::for_tests(),
+                )
+                .unwrap();
+            let JobOutput::Sync { actions, .. } = output else {
+                panic!("unexpected output");
+            };
+            let actions: Vec<(String, SyncOp)> =
+                actions.into_iter().map(|a| (a.path, a.op)).collect();
+            actions
+        };
+
+        let expected = [
+            ("docs".to_string(), SyncOp::CreateDir),
+            ("docs/readme.txt".to_string(), SyncOp::Copy),
+            ("old".to_string(), SyncOp::Delete),
+            ("stale.txt".to_string(), SyncOp::Delete),
+        ];
+        assert_eq!(sync(true), expected);
+        assert!(dest.join("stale.txt").exists());
+
+        assert_eq!(sync(false), expected);
+        assert_eq!(fs::read_to_string(dest.join("docs/readme.txt")).unwrap(), "new");
+        assert!(!dest.join("docs/cache").exists());
+        assert!(!dest.join("old").exists() && !dest.join("stale.txt").exists());
+        // Excluded names are neither copied nor deleted
+        assert!(!dest.join("notes.tmp").exists() && dest.join("keep.tmp").exists());
+
+        assert!(sync(false).is_empty());
+    }
+
+    #[test]
+    fn test_sync_replaces_linked_dir() {
+        let (root, handler) = test_root("sync-link");
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/readme.txt"), "x").unwrap();
+        fs::create_dir_all(root.join("elsewhere")).unwrap();
+        fs::write(root.join("elsewhere/readme.txt"), "x").unwrap();
+        fs::write(root.join("elsewhere/other.txt"), "x").unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        std::os::unix::fs::symlink("../elsewhere", dest.join("docs")).unwrap();
+
+        let options = SyncOptions {
+            delete: true,
+            ..Default::default()
+        };
+        handler
+            .sync_directories(
+                &source.to_string_lossy(),
+                &dest.to_string_lossy(),
+                &options,
+                &Job::for_tests(),
+            )
+            .unwrap();
+
+        let docs = dest.join("docs");
+        assert!(fs::symlink_metadata(&docs).unwrap().is_dir());
+        assert!(docs.join("readme.txt").exists());
+        assert!(!docs.join("other.txt").exists());
+        // What the link pointed at is left alone
+        assert!(root.join("elsewhere/other.txt").exists());
+    }
+
+    #[test]
+    fn test_copy_and_sync_track_quota() {
+        let root = TestDir::new("files-quota");
+        let mut config = root.validator().config().clone();
+        config.quotas.paths = vec![crate::config::PathQuota {
+            path: root.to_path_buf(),
+            soft_limit: None,
+            hard_limit: Some(1 << 20),
+        }];
+        let handler = FileHandler::new(Validator::new(config), VersioningConfig::default());
+        let used = || handler.validator.quota_status()[0].used;
+
+        fs::create_dir_all(root.join("source/tree/sub")).unwrap();
+        fs::write(root.join("source/tree/sub/file"), [0u8; 50]).unwrap();
+        fs::write(root.join("source/tree/kept"), [0u8; 30]).unwrap();
+        fs::create_dir_all(root.join("dest/tree")).unwrap();
+        // A file where the source has a directory, and one that is overwritten
+        fs::write(root.join("dest/tree/sub"), [0u8; 200]).unwrap();
+        fs::write(root.join("dest/tree/kept"), [0u8; 100]).unwrap();
+        fs::create_dir_all(root.join("dest/synced")).unwrap();
+        fs::write(root.join("dest/synced/kept"), [0u8; 100]).unwrap();
+        fs::write(root.join("dest/synced/extra"), [0u8; 70]).unwrap();
+        assert_eq!(used(), 550);
+
+        let path = |p: &str| root.join(p).to_string_lossy().to_string();
+        handler.copy(&path("source/tree"), &path("dest/tree"), false).unwrap();
+        assert_eq!(used(), 330);
+
+        let options = SyncOptions {
+            delete: true,
+            ..Default::default()
+        };
+        let job = Job::for_tests();
+        handler
+            .sync_directories(&path("source/tree"), &path("dest/synced"), &options, &job)
+            .unwrap();
+        assert!(!root.join("dest/synced/extra").exists());
+        assert_eq!(used(), 3 * 80);
+        assert_eq!(used(), handler.quota_bytes(&root).unwrap());
+    }
+
+    #[test]
+    fn test_writes_respect_locks() {
+        let (root, handler) = test_root("locks");
+        fs::create_dir_all(root.join("dest")).unwrap();
+        fs::create_dir_all(root.join("source")).unwrap();
+        fs::write(root.join("source/file"), "new").unwrap();
+        let locked = root.join("dest/file");
+        fs::write(&locked, "old").unwrap();
+
+        let session = |id| {
+            let mut validator = handler.validator.for_session(id, None);
+            validator.config.allow_metadata_changes = true;
+            FileHandler::new(validator, VersioningConfig::default())
+        };
+        let (owner, other) = (session(1), session(2));
+        owner.lock_file(&locked.to_string_lossy(), "editor", 60).unwrap();
+
+        let source = root.join("source/file").to_string_lossy().to_string();
+        let locked_str = locked.to_string_lossy().to_string();
+        let sync = |handler: &FileHandler| {
+            handler.sync_directories(
+                &root.join("source").to_string_lossy(),
+                &root.join("dest").to_string_lossy(),
+                &SyncOptions::default(),
+                &Job::for_tests(),
+            )
+        };
+        assert!(matches!(
+            other.copy(&source, &locked_str, false),
+            Err(AgentError::Locked(_))
+        ));
+        assert!(matches!(sync(&other), Err(AgentError::Locked(_))));
+        assert!(matches!(
+            other.set_permissions(&locked_str, "600", false),
+            Err(AgentError::Locked(_))
+        ));
+        let xattrs = XattrHandler::new(other.validator.clone());
+        assert!(matches!(
+            xattrs.set_xattr(&locked_str, "user.note", "x", false),
+            Err(AgentError::Locked(_))
+        ));
+        assert!(matches!(
+            xattrs.set_acl(&locked_str, Some(Vec::new()), None),
+            Err(AgentError::Locked(_))
+        ));
+        assert_eq!(fs::read_to_string(&locked).unwrap(), "old");
+
+        // The lock holder is not blocked by its own lock
+        owner.copy(&source, &locked_str, false).unwrap();
+        assert!(sync(&owner).is_ok());
+    }
+
+    #[test]
+    fn test_move_across_devices_cleans_up() {
+        let (root, handler) = test_root("move_devices");
+        let source = root.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file"), "data").unwrap();
+
+        // A lease taken on something inside the tree stops the move and
+        // the copy is removed again
+        let session = |id| {
+            FileHandler::new(handler.validator.for_session(id, None), VersioningConfig::default())
+        };
+        let (owner, other) = (session(1), session(2));
+        let locked = source.join("sub/file").to_string_lossy().to_string();
+        owner.lock_file(&locked, "editor", 60).unwrap();
+
+        let dest = root.join("dest");
+        assert!(matches!(
+            other.move_across_devices(&source, &dest, false),
+            Err(AgentError::Locked(_))
+        ));
+        assert!(!dest.exists());
+        assert_eq!(fs::read_to_string(source.join("sub/file")).unwrap(), "data");
+        owner.unlock_file(&locked).unwrap();
+
+        // So is a copy that fails halfway
+        std::os::unix::fs::symlink("/etc/passwd", source.join("sub/link")).unwrap();
+        assert!(other.move_across_devices(&source, &dest, false).is_err());
+        assert!(!dest.exists());
+        assert!(source.join("sub/file").exists());
+
+        fs::remove_file(source.join("sub/link")).unwrap();
+        other.move_across_devices(&source, &dest, false).unwrap();
+        assert!(!source.exists());
+        assert_eq!(fs::read_to_string(dest.join("sub/file")).unwrap(), "data");
+    }
+}
@@ -0,0 +1,244 @@
+use crate::config::HardwareConfig;
+use crate::error::Result;
+use crate::handlers::mounts::read_attr;
+use crate::protocol::{CpuFrequency, HardwareInfo, Temperature, Throttling};
+use log::{debug, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How deep below devices/platform to look for the firmware's
+/// `get_throttled` attribute, whose parent differs between Pi models.
+const THROTTLED_SEARCH_DEPTH: usize = 3;
+
+// Bits of the firmware's throttled state, as printed by `vcgencmd get_throttled`
+const UNDER_VOLTAGE: u32 = 1 << 0;
+const FREQUENCY_CAPPED: u32 = 1 << 1;
+const THROTTLED: u32 = 1 << 2;
+const SOFT_TEMP_LIMIT: u32 = 1 << 3;
+const UNDER_VOLTAGE_OCCURRED: u32 = 1 << 16;
+const FREQUENCY_CAPPED_OCCURRED: u32 = 1 << 17;
+const THROTTLED_OCCURRED: u32 = 1 << 18;
+const SOFT_TEMP_LIMIT_OCCURRED: u32 = 1 << 19;
+
+pub struct HardwareHandler {
+    config: HardwareConfig,
+}
+
+impl HardwareHandler {
+    pub fn new(config: HardwareConfig) -> Self {
+        Self { config }
+    }
+
+    /// Reads board telemetry. Everything is optional: values the kernel
+    /// does not expose on this machine are left out rather than failing.
+    pub fn hardware_info(&self) -> Result<HardwareInfo> {
+        info!("Collecting hardware info");
+
+        let sys = &self.config.sysfs_root;
+        let proc = &self.config.procfs_root;
+
+        let serial = read_attr(&proc.join("device-tree/serial-number")).or_else(|| {
+            // Older firmware only reports the serial in cpuinfo
+            fs::read_to_string(proc.join("cpuinfo"))
+                .ok()
+                .and_then(|cpuinfo| {
+                    cpuinfo
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Serial")?.split(':').nth(1))
+                        .map(|serial| serial.trim().to_string())
+                })
+        });
+
+        let throttling = find_file(
+            &sys.join("devices/platform"),
+            "get_throttled",
+            THROTTLED_SEARCH_DEPTH,
+        )
+        .and_then(|path| read_attr(&path))
+        .and_then(|value| u32::from_str_radix(value.trim_start_matches("0x"), 16).ok())
+        .map(throttling);
+
+        Ok(HardwareInfo {
+            model: read_attr(&proc.join("device-tree/model")),
+            serial,
+            temperatures: temperatures(sys)?,
+            cpus: cpu_frequencies(sys)?,
+            under_voltage: under_voltage(sys)?,
+            throttling,
+        })
+    }
+}
+
+pub fn temperatures(sys: &Path) -> Result<Vec<Temperature>> {
+    let mut temperatures = Vec::new();
+    for (zone, dir) in numbered_entries(&sys.join("class/thermal"), "thermal_zone")? {
+        let Some(millidegrees) = read_attr(&dir.join("temp")).and_then(|t| t.parse::<i64>().ok())
+        else {
+            continue;
+        };
+        temperatures.push(Temperature {
+            zone: format!("thermal_zone{}", zone),
+            kind: read_attr(&dir.join("type")),
+            celsius: millidegrees as f64 / 1000.0,
+        });
+    }
+    Ok(temperatures)
+}
+
+fn cpu_frequencies(sys: &Path) -> Result<Vec<CpuFrequency>> {
+    let khz_to_mhz = |path: PathBuf| {
+        read_attr(&path)
+            .and_then(|khz| khz.parse::<u32>().ok())
+            .map(|khz| khz / 1000)
+    };
+
+    let mut cpus = Vec::new();
+    for (cpu, dir) in numbered_entries(&sys.join("devices/system/cpu"), "cpu")? {
+        let cpufreq = dir.join("cpufreq");
+        if !cpufreq.is_dir() {
+            continue;
+        }
+        cpus.push(CpuFrequency {
+            cpu,
+            current_mhz: khz_to_mhz(cpufreq.join("scaling_cur_freq")),
+            min_mhz: khz_to_mhz(cpufreq.join("scaling_min_freq")),
+            max_mhz: khz_to_mhz(cpufreq.join("scaling_max_freq")),
+            governor: read_attr(&cpufreq.join("scaling_governor")),
+        });
+    }
+    Ok(cpus)
+}
+
+/// The Pi's voltage monitor appears as an `rpi_volt` hwmon device whose
+/// low-critical alarm is raised on under-voltage.
+fn under_voltage(sys: &Path) -> Result<Option<bool>> {
+    for (_, dir) in numbered_entries(&sys.join("class/hwmon"), "hwmon")? {
+        if read_attr(&dir.join("name")).as_deref() == Some("rpi_volt") {
+            return Ok(read_attr(&dir.join("in0_lcrit_alarm")).map(|alarm| alarm == "1"));
+        }
+    }
+    Ok(None)
+}
+
+fn throttling(raw: u32) -> Throttling {
+    Throttling {
+        raw,
+        under_voltage: raw & UNDER_VOLTAGE != 0,
+        frequency_capped: raw & FREQUENCY_CAPPED != 0,
+        throttled: raw & THROTTLED != 0,
+        soft_temp_limit: raw & SOFT_TEMP_LIMIT != 0,
+        under_voltage_occurred: raw & UNDER_VOLTAGE_OCCURRED != 0,
+        frequency_capped_occurred: raw & FREQUENCY_CAPPED_OCCURRED != 0,
+        throttled_occurred: raw & THROTTLED_OCCURRED != 0,
+        soft_temp_limit_occurred: raw & SOFT_TEMP_LIMIT_OCCURRED != 0,
+    }
+}
+
+/// Entries of `dir` named `prefix` followed by a number, in numeric order.
+fn numbered_entries(dir: &Path, prefix: &str) -> Result<Vec<(u32, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("{:?} does not exist", dir);
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut numbered: Vec<(u32, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let number = name.to_str()?.strip_prefix(prefix)?.parse().ok()?;
+            Some((number, entry.path()))
+        })
+        .collect();
+    numbered.sort();
+    Ok(numbered)
+}
+
+fn find_file(dir: &Path, name: &str, depth: usize) -> Option<PathBuf> {
+    let candidate = dir.join(name);
+    if candidate.is_file() {
+        return Some(candidate);
+    }
+    if depth == 0 {
+        return None;
+    }
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .find_map(|entry| find_file(&entry.path(), name, depth - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn test_fake_pi_tree() {
+        let root = TestDir::new("hardware");
+        let sys = root.join("sys");
+        let proc = root.join("proc");
+        let write = |path: PathBuf, content: &[u8]| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+
+        write(
+            proc.join("device-tree/model"),
+            b"Raspberry Pi 4 Model B Rev 1.4\0",
+        );
+        write(
+            proc.join("cpuinfo"),
+            b"Hardware\t: BCM2835\nSerial\t\t: 10000000abcdef01\n",
+        );
+        write(sys.join("class/thermal/thermal_zone0/temp"), b"48686\n");
+        write(
+            sys.join("class/thermal/thermal_zone0/type"),
+            b"cpu-thermal\n",
+        );
+        write(
+            sys.join("devices/system/cpu/cpu0/cpufreq/scaling_cur_freq"),
+            b"1500000\n",
+        );
+        write(
+            sys.join("devices/system/cpu/cpu0/cpufreq/scaling_governor"),
+            b"ondemand\n",
+        );
+        write(
+            sys.join("devices/system/cpu/cpufreq/policy0/scaling_cur_freq"),
+            b"1500000\n",
+        );
+        write(
+            sys.join("devices/platform/soc/soc:firmware/get_throttled"),
+            b"50005\n",
+        );
+        write(sys.join("class/hwmon/hwmon1/name"), b"rpi_volt\n");
+        write(sys.join("class/hwmon/hwmon1/in0_lcrit_alarm"), b"1\n");
+
+        let handler = HardwareHandler::new(HardwareConfig {
+            sysfs_root: sys,
+            procfs_root: proc,
+        });
+        let info = handler.hardware_info().unwrap();
+
+        assert_eq!(
+            info.model.as_deref(),
+            Some("Raspberry Pi 4 Model B Rev 1.4")
+        );
+        assert_eq!(info.serial.as_deref(), Some("10000000abcdef01"));
+        assert_eq!(info.temperatures[0].celsius, 48.686);
+        assert_eq!(info.cpus.len(), 1);
+        assert_eq!(info.cpus[0].current_mhz, Some(1500));
+        assert_eq!(info.cpus[0].governor.as_deref(), Some("ondemand"));
+        assert_eq!(info.under_voltage, Some(true));
+
+        let throttling = info.throttling.unwrap();
+        assert!(throttling.under_voltage && throttling.throttled);
+        assert!(throttling.under_voltage_occurred && throttling.throttled_occurred);
+        assert!(!throttling.frequency_capped);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::error::{AgentError, Result};
+use crate::protocol::{FileInfo, ListOptions, SortKey, SortOrder};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Sorts a listing by the requested key. Directories always come first,
+/// regardless of the sort direction.
+pub fn sort_files(files: &mut [FileInfo], key: SortKey, order: SortOrder) {
+    files.sort_by(|a, b| {
+        let ordering = match key {
+            SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortKey::Natural => natural_cmp(&a.name, &b.name),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+            SortKey::Type => extension(&a.name).cmp(&extension(&b.name)),
+        }
+        .then_with(|| natural_cmp(&a.name, &b.name));
+
+        let ordering = match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        };
+
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+}
+
+fn extension(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => ext.to_lowercase(),
+        _ => String::new(),
+    }
+}
+
+/// Compares names case-insensitively, treating runs of digits as numbers
+/// so that `img2` sorts before `img10`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x_num = take_number(&mut a_chars);
+                let y_num = take_number(&mut b_chars);
+
+                // Compare by magnitude first, then digit by digit
+                let x_trim = x_num.trim_start_matches('0');
+                let y_trim = y_num.trim_start_matches('0');
+                let ordering = x_trim
+                    .len()
+                    .cmp(&y_trim.len())
+                    .then_with(|| x_trim.cmp(y_trim))
+                    .then_with(|| x_num.len().cmp(&y_num.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.peek().copied().filter(|c| c.is_ascii_digit()) {
+        number.push(c);
+        chars.next();
+    }
+    number
+}
+
+/// Matches a name against a filter. Filters containing `*` or `?` are
+/// treated as globs, anything else as a substring; both ignore case.
+pub fn matches_filter(name: &str, filter: &str) -> bool {
+    let name = name.to_lowercase();
+    let filter = filter.to_lowercase();
+
+    if filter.contains(['*', '?']) {
+        glob_match(filter.as_bytes(), name.as_bytes())
+    } else {
+        name.contains(&filter)
+    }
+}
+
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Listing state carried by a cursor, so follow-up requests only need to
+/// pass the path and the cursor.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    path: String,
+    #[serde(flatten)]
+    options: ListOptions,
+}
+
+/// Builds the opaque cursor for the page starting at `offset`.
+pub fn encode_cursor(path: &str, options: &ListOptions, offset: usize) -> String {
+    let cursor = Cursor {
+        path: path.to_string(),
+        options: ListOptions {
+            offset,
+            cursor: None,
+            ..options.clone()
+        },
+    };
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(&cursor).unwrap_or_default())
+}
+
+/// Recovers the listing options stored in a cursor, rejecting cursors
+/// that were issued for a different directory.
+pub fn decode_cursor(cursor: &str, path: &str) -> Result<ListOptions> {
+    let invalid = || AgentError::InvalidRequest("Invalid cursor".to_string());
+
+    let raw = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let cursor: Cursor = serde_json::from_slice(&raw).map_err(|_| invalid())?;
+
+    if cursor.path != path {
+        return Err(AgentError::InvalidRequest(
+            "Cursor was issued for a different path".to_string(),
+        ));
+    }
+    Ok(cursor.options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["img10.jpg", "IMG2.jpg", "img1.jpg", "img02.jpg", "a"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["a", "img1.jpg", "IMG2.jpg", "img02.jpg", "img10.jpg"]);
+    }
+
+    #[test]
+    fn test_matches_filter() {
+        assert!(matches_filter("Report.PDF", "*.pdf"));
+        assert!(matches_filter("photo_001.jpg", "photo_???.jpg"));
+        assert!(matches_filter("holiday photos", "PHOTO"));
+        assert!(!matches_filter("notes.txt", "*.pdf"));
+        assert!(!matches_filter("photo_1.jpg", "photo_???.jpg"));
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let options = ListOptions {
+            limit: Some(100),
+            sort_by: SortKey::Natural,
+            filter: Some("*.jpg".to_string()),
+            ..ListOptions::default()
+        };
+        let cursor = encode_cursor("/home/pi", &options, 200);
+
+        let decoded = decode_cursor(&cursor, "/home/pi").unwrap();
+        assert_eq!(decoded.offset, 200);
+        assert_eq!(decoded.limit, Some(100));
+        assert_eq!(decoded.sort_by, SortKey::Natural);
+        assert_eq!(decoded.filter.as_deref(), Some("*.jpg"));
+
+        assert!(decode_cursor(&cursor, "/home/other").is_err());
+        assert!(decode_cursor("garbage", "/home/pi").is_err());
+    }
+}
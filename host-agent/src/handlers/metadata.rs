@@ -0,0 +1,158 @@
+use crate::protocol::{ExtendedInfo, FileType};
+use log::debug;
+use nix::unistd::{Gid, Group, Uid, User};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::Read;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Number of leading bytes read when sniffing the MIME type from content.
+const SNIFF_LEN: usize = 8192;
+
+/// Caches uid/gid to name lookups so a directory listing does not hit
+/// the passwd/group databases once per entry.
+#[derive(Default)]
+pub struct OwnerCache {
+    users: HashMap<u32, Option<String>>,
+    groups: HashMap<u32, Option<String>>,
+}
+
+impl OwnerCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn user_name(&mut self, uid: u32) -> Option<String> {
+        self.users
+            .entry(uid)
+            .or_insert_with(|| match User::from_uid(Uid::from_raw(uid)) {
+                Ok(user) => user.map(|u| u.name),
+                Err(e) => {
+                    debug!("Failed to look up uid {}: {}", uid, e);
+                    None
+                }
+            })
+            .clone()
+    }
+
+    pub fn group_name(&mut self, gid: u32) -> Option<String> {
+        self.groups
+            .entry(gid)
+            .or_insert_with(|| match Group::from_gid(Gid::from_raw(gid)) {
+                Ok(group) => group.map(|g| g.name),
+                Err(e) => {
+                    debug!("Failed to look up gid {}: {}", gid, e);
+                    None
+                }
+            })
+            .clone()
+    }
+}
+
+pub fn file_type(ft: &fs::FileType) -> FileType {
+    if ft.is_symlink() {
+        FileType::Symlink
+    } else if ft.is_dir() {
+        FileType::Dir
+    } else if ft.is_file() {
+        FileType::File
+    } else if ft.is_socket() {
+        FileType::Socket
+    } else if ft.is_fifo() {
+        FileType::Fifo
+    } else if ft.is_block_device() {
+        FileType::BlockDevice
+    } else if ft.is_char_device() {
+        FileType::CharDevice
+    } else {
+        FileType::Unknown
+    }
+}
+
+pub fn unix_time(time: std::io::Result<SystemTime>) -> Option<i64> {
+    time.ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+}
+
+/// Builds the extended metadata for `path`.
+///
+/// `link_meta` must come from `symlink_metadata` so that links are
+/// reported as links. When `sniff` is set, regular files are opened and
+/// their leading bytes inspected to detect the MIME type; otherwise only
+/// the file extension is used.
+pub fn extended_info(
+    path: &Path,
+    link_meta: &Metadata,
+    owners: &mut OwnerCache,
+    sniff: bool,
+) -> ExtendedInfo {
+    let file_type = file_type(&link_meta.file_type());
+
+    let (symlink_target, broken_link) = if file_type == FileType::Symlink {
+        let target = fs::read_link(path)
+            .ok()
+            .map(|t| t.to_string_lossy().to_string());
+        (target, fs::metadata(path).is_err())
+    } else {
+        (None, false)
+    };
+
+    let hidden = path
+        .file_name()
+        .map(|n| n.to_string_lossy().starts_with('.'))
+        .unwrap_or(false);
+
+    ExtendedInfo {
+        file_type,
+        owner: owners.user_name(link_meta.uid()),
+        group: owners.group_name(link_meta.gid()),
+        uid: link_meta.uid(),
+        gid: link_meta.gid(),
+        inode: link_meta.ino(),
+        nlink: link_meta.nlink(),
+        accessed: link_meta.atime(),
+        created: unix_time(link_meta.created()),
+        symlink_target,
+        broken_link,
+        mime_type: detect_mime(path, file_type, sniff),
+        hidden,
+    }
+}
+
+/// Detects the MIME type of `path`, using freedesktop `inode/*` types for
+/// anything that is not a regular file.
+pub fn detect_mime(path: &Path, file_type: FileType, sniff: bool) -> Option<String> {
+    let special = match file_type {
+        FileType::Dir => Some("inode/directory"),
+        FileType::Symlink => Some("inode/symlink"),
+        FileType::Socket => Some("inode/socket"),
+        FileType::Fifo => Some("inode/fifo"),
+        FileType::BlockDevice => Some("inode/blockdevice"),
+        FileType::CharDevice => Some("inode/chardevice"),
+        FileType::File | FileType::Unknown => None,
+    };
+    if let Some(mime) = special {
+        return Some(mime.to_string());
+    }
+
+    if sniff {
+        if let Some(mime) = sniff_mime(path) {
+            return Some(mime);
+        }
+    }
+
+    mime_guess::from_path(path)
+        .first()
+        .map(|m| m.essence_str().to_string())
+}
+
+fn sniff_mime(path: &Path) -> Option<String> {
+    let mut buf = Vec::with_capacity(SNIFF_LEN);
+    let file = File::open(path).ok()?;
+    file.take(SNIFF_LEN as u64).read_to_end(&mut buf).ok()?;
+
+    infer::get(&buf).map(|kind| kind.mime_type().to_string())
+}
@@ -1,3 +1,17 @@
+pub mod archive;
+pub mod checksum;
+pub mod diff;
+pub mod duplicates;
+pub mod encoding;
 pub mod files;
-//pub mod system;
+pub mod hardware;
+pub mod listing;
+pub mod metadata;
+pub mod mounts;
+pub mod permissions;
+pub mod tail;
+pub mod thumbnail;
+pub mod versions;
+pub mod xattrs;
+pub mod system;
 //pub mod process;
\ No newline at end of file
@@ -0,0 +1,257 @@
+use crate::error::Result;
+use crate::protocol::{BlockDevice, MountInfo, Partition};
+use crate::security::Validator;
+use log::{debug, info};
+use nix::sys::statvfs::statvfs;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const SYS_BLOCK: &str = "/sys/block";
+
+/// The kernel reports block device sizes in 512-byte sectors.
+const SECTOR_SIZE: u64 = 512;
+
+pub struct MountHandler {
+    validator: Validator,
+}
+
+/// One line of /proc/self/mountinfo.
+pub struct Mount {
+    /// `major:minor` of the mounted device
+    pub dev: String,
+    pub source: String,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub options: Vec<String>,
+}
+
+/// Capacity of a mounted filesystem in bytes. `free` is what is available
+/// to unprivileged users, so `used + free` can be less than `total`.
+pub struct Usage {
+    pub total: u64,
+    pub used: u64,
+    pub free: u64,
+}
+
+impl MountHandler {
+    pub fn new(validator: Validator) -> Self {
+        Self { validator }
+    }
+
+    /// Lists mounted filesystems with their usage. Pseudo filesystems
+    /// without any blocks (proc, sysfs, cgroups, ...) are only included
+    /// with `all`.
+    pub fn list_mounts(&self, all: bool) -> Result<Vec<MountInfo>> {
+        info!("Listing mounts");
+
+        let mut mounts = Vec::new();
+        for mount in read_mounts()? {
+            let usage = usage(&mount.mount_point);
+            if !all && usage.as_ref().is_none_or(|u| u.total == 0) {
+                continue;
+            }
+
+            let allowed = self.validator.is_within_allowed(&mount.mount_point);
+            let read_only = mount.options.iter().any(|o| o == "ro");
+            let (total, used, free) = usage.map_or((0, 0, 0), |u| (u.total, u.used, u.free));
+
+            mounts.push(MountInfo {
+                device: mount.source,
+                mount_point: mount.mount_point.to_string_lossy().to_string(),
+                fs_type: mount.fs_type,
+                options: mount.options,
+                read_only,
+                total,
+                used,
+                free,
+                allowed,
+            });
+        }
+
+        Ok(mounts)
+    }
+
+    /// Lists whole disks from /sys/block with their partitions and where
+    /// each is mounted. Empty devices such as unused loop devices are
+    /// skipped.
+    pub fn list_block_devices(&self) -> Result<Vec<BlockDevice>> {
+        info!("Listing block devices");
+
+        let mounts = read_mounts()?;
+        let mut devices = Vec::new();
+
+        for entry in fs::read_dir(SYS_BLOCK)? {
+            let entry = entry?;
+            let dir = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            let size = read_number(&dir.join("size")) * SECTOR_SIZE;
+            if size == 0 {
+                continue;
+            }
+
+            let mut partitions = Vec::new();
+            for part in fs::read_dir(&dir)?.flatten() {
+                let part_dir = part.path();
+                if !part_dir.join("partition").exists() {
+                    continue;
+                }
+                let part_name = part.file_name().to_string_lossy().to_string();
+                partitions.push(Partition {
+                    path: format!("/dev/{}", part_name),
+                    size: read_number(&part_dir.join("size")) * SECTOR_SIZE,
+                    mount_points: mount_points(&mounts, &part_dir),
+                    name: part_name,
+                });
+            }
+            partitions.sort_by(|a, b| a.name.cmp(&b.name));
+
+            devices.push(BlockDevice {
+                path: format!("/dev/{}", name),
+                size,
+                removable: read_number(&dir.join("removable")) == 1,
+                read_only: read_number(&dir.join("ro")) == 1,
+                rotational: read_number(&dir.join("queue/rotational")) == 1,
+                model: read_attr(&dir.join("device/model")),
+                vendor: read_attr(&dir.join("device/vendor")),
+                mount_points: mount_points(&mounts, &dir),
+                partitions,
+                name,
+            });
+        }
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(devices)
+    }
+}
+
+pub fn read_mounts() -> Result<Vec<Mount>> {
+    Ok(parse_mountinfo(&fs::read_to_string(MOUNTINFO)?))
+}
+
+// Block counts are 32-bit on 32-bit Raspberry Pi OS
+#[allow(clippy::unnecessary_cast)]
+pub fn usage(path: &Path) -> Option<Usage> {
+    match statvfs(path) {
+        Ok(stat) => {
+            let block = stat.fragment_size() as u64;
+            let total = stat.blocks() as u64 * block;
+            Some(Usage {
+                total,
+                used: total.saturating_sub(stat.blocks_free() as u64 * block),
+                free: stat.blocks_available() as u64 * block,
+            })
+        }
+        Err(e) => {
+            debug!("statvfs({:?}) failed: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Parses mountinfo lines of the form
+/// `id parent major:minor root mount-point options [optional...] - type source super-options`.
+fn parse_mountinfo(text: &str) -> Vec<Mount> {
+    text.lines()
+        .filter_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            let fields: Vec<&str> = mount.split(' ').collect();
+            let mut fs_fields = fs.split(' ');
+            let fs_type = fs_fields.next()?;
+            let source = fs_fields.next()?;
+
+            // Per-mount flags first, then those of the superblock
+            let mut options: Vec<String> = fields.get(5)?.split(',').map(str::to_string).collect();
+            for option in fs_fields.next().unwrap_or("").split(',') {
+                if !option.is_empty() && !options.iter().any(|o| o == option) {
+                    options.push(option.to_string());
+                }
+            }
+
+            Some(Mount {
+                dev: fields.get(2)?.to_string(),
+                source: unescape(source),
+                mount_point: PathBuf::from(unescape(fields.get(4)?)),
+                fs_type: fs_type.to_string(),
+                options,
+            })
+        })
+        .collect()
+}
+
+/// Undoes the octal escapes (`\040` for space etc.) used in mountinfo.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|b| (b'0'..=b'7').contains(b)));
+        match escape {
+            Some(digits) => {
+                let code = digits
+                    .iter()
+                    .fold(0u32, |code, digit| code * 8 + (digit - b'0') as u32);
+                out.push(code as u8);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Mount points of the device whose sysfs directory is `dir`, matched by
+/// device number since the root filesystem is often listed as /dev/root.
+fn mount_points(mounts: &[Mount], dir: &Path) -> Vec<String> {
+    let Some(dev) = read_attr(&dir.join("dev")) else {
+        return Vec::new();
+    };
+    mounts
+        .iter()
+        .filter(|mount| mount.dev == dev)
+        .map(|mount| mount.mount_point.to_string_lossy().to_string())
+        .collect()
+}
+
+/// Reads a sysfs, procfs or device-tree attribute. Device-tree strings
+/// end in a NUL byte.
+pub fn read_attr(path: &Path) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    let value = String::from_utf8_lossy(&bytes);
+    let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn read_number(path: &Path) -> u64 {
+    read_attr(path).and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let text = "\
+22 1 179:2 / / rw,noatime shared:1 - ext4 /dev/root rw\n\
+35 22 8:1 / /media/pi/USB\\040Stick rw,nosuid,nodev master:2 - vfat /dev/sda1 rw,fmask=0022\n";
+        let mounts = parse_mountinfo(text);
+
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].dev, "179:2");
+        assert_eq!(mounts[0].source, "/dev/root");
+        assert_eq!(mounts[0].options, vec!["rw", "noatime"]);
+        assert_eq!(mounts[1].mount_point, Path::new("/media/pi/USB Stick"));
+        assert_eq!(mounts[1].fs_type, "vfat");
+        assert_eq!(
+            mounts[1].options,
+            vec!["rw", "nosuid", "nodev", "fmask=0022"]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use crate::error::{AgentError, Result};
+
+const USER_BITS: u32 = 0o4700;
+const GROUP_BITS: u32 = 0o2070;
+const OTHER_BITS: u32 = 0o1007;
+
+/// Computes the new mode for a file from a chmod-style specification.
+///
+/// Accepts either an octal mode (`"755"`, `"0644"`) or a comma separated
+/// list of symbolic clauses (`"u+x,go-w"`, `"a=rX"`). `current` is the
+/// existing mode and `is_dir` controls how `X` is applied.
+pub fn apply_mode(spec: &str, current: u32, is_dir: bool) -> Result<u32> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err(AgentError::InvalidRequest("Empty mode".to_string()));
+    }
+
+    if spec.chars().all(|c| c.is_ascii_digit()) {
+        let mode = u32::from_str_radix(spec, 8)
+            .map_err(|_| AgentError::InvalidRequest(format!("Invalid octal mode: {}", spec)))?;
+        if mode > 0o7777 {
+            return Err(AgentError::InvalidRequest(format!("Invalid octal mode: {}", spec)));
+        }
+        return Ok(mode);
+    }
+
+    let mut mode = current & 0o7777;
+    for clause in spec.split(',') {
+        mode = apply_clause(clause, mode, is_dir)?;
+    }
+    Ok(mode)
+}
+
+fn apply_clause(clause: &str, mut mode: u32, is_dir: bool) -> Result<u32> {
+    let invalid = || AgentError::InvalidRequest(format!("Invalid symbolic mode: {}", clause));
+
+    let op_pos = clause.find(['+', '-', '=']).ok_or_else(invalid)?;
+    let (who, rest) = clause.split_at(op_pos);
+
+    let mut mask = 0;
+    for c in who.chars() {
+        mask |= match c {
+            'u' => USER_BITS,
+            'g' => GROUP_BITS,
+            'o' => OTHER_BITS,
+            'a' => USER_BITS | GROUP_BITS | OTHER_BITS,
+            _ => return Err(invalid()),
+        };
+    }
+    if mask == 0 {
+        mask = USER_BITS | GROUP_BITS | OTHER_BITS;
+    }
+
+    // A clause may chain several operations, e.g. "u+r-w".
+    let mut chars = rest.chars().peekable();
+    while let Some(op) = chars.next() {
+        if !matches!(op, '+' | '-' | '=') {
+            return Err(invalid());
+        }
+
+        let mut bits = 0;
+        while let Some(&c) = chars.peek() {
+            if matches!(c, '+' | '-' | '=') {
+                break;
+            }
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                'X' if is_dir || mode & 0o111 != 0 => 0o111,
+                'X' => 0,
+                's' => 0o6000,
+                't' => 0o1000,
+                _ => return Err(invalid()),
+            };
+            chars.next();
+        }
+        bits &= mask;
+
+        mode = match op {
+            '+' => mode | bits,
+            '-' => mode & !bits,
+            _ => (mode & !mask) | bits,
+        };
+    }
+
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_octal_mode() {
+        assert_eq!(apply_mode("755", 0o644, false).unwrap(), 0o755);
+        assert_eq!(apply_mode("0640", 0o777, false).unwrap(), 0o640);
+        assert!(apply_mode("789", 0o644, false).is_err());
+        assert!(apply_mode("17777", 0o644, false).is_err());
+    }
+
+    #[test]
+    fn test_symbolic_mode() {
+        assert_eq!(apply_mode("u+x", 0o644, false).unwrap(), 0o744);
+        assert_eq!(apply_mode("go-r", 0o644, false).unwrap(), 0o600);
+        assert_eq!(apply_mode("a=r", 0o755, false).unwrap(), 0o444);
+        assert_eq!(apply_mode("+x", 0o644, false).unwrap(), 0o755);
+        assert_eq!(apply_mode("u=rw,g=r,o=", 0o777, false).unwrap(), 0o640);
+        assert_eq!(apply_mode("u+r-w", 0o200, false).unwrap(), 0o400);
+    }
+
+    #[test]
+    fn test_conditional_execute() {
+        assert_eq!(apply_mode("a+X", 0o644, false).unwrap(), 0o644);
+        assert_eq!(apply_mode("a+X", 0o644, true).unwrap(), 0o755);
+        assert_eq!(apply_mode("a+X", 0o744, false).unwrap(), 0o755);
+    }
+
+    #[test]
+    fn test_invalid_symbolic_mode() {
+        assert!(apply_mode("", 0o644, false).is_err());
+        assert!(apply_mode("u", 0o644, false).is_err());
+        assert!(apply_mode("z+x", 0o644, false).is_err());
+        assert!(apply_mode("u+q", 0o644, false).is_err());
+    }
+}
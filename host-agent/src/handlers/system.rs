@@ -0,0 +1,412 @@
+use crate::error::Result;
+use crate::handlers::mounts::{self, read_attr};
+use crate::protocol::{
+    DefaultRoute, DiskIo, DnsConfig, FilesystemUsage, InterfaceAddress, IpFamily, NetworkInfo,
+    NetworkInterface, SystemInfo,
+};
+use log::{debug, info};
+use nix::ifaddrs::getifaddrs;
+use std::collections::HashSet;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use sysinfo::{Networks, System};
+
+const PROC: &str = "/proc";
+const DISKSTATS: &str = "/proc/diskstats";
+const SYS_BLOCK: &str = "/sys/block";
+const SYS_NET: &str = "/sys/class/net";
+const ROUTES: &str = "/proc/net/route";
+const ROUTES_V6: &str = "/proc/net/ipv6_route";
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Interval over which network rates are measured.
+const NETWORK_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Route flag for routes that are in use.
+const RTF_UP: u32 = 0x0001;
+
+/// /proc/diskstats counts in 512-byte sectors regardless of the device.
+const SECTOR_SIZE: u64 = 512;
+
+#[derive(Default)]
+pub struct SystemHandler;
+
+/// Cumulative counters of one disk from /proc/diskstats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskStats {
+    pub name: String,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    /// Milliseconds spent doing IO
+    pub io_ms: u64,
+}
+
+impl SystemHandler {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Samples CPU and disk counters twice, `MINIMUM_CPU_UPDATE_INTERVAL`
+    /// apart, since usage and throughput are rates. This sleeps, so callers
+    /// on the runtime run it on the blocking pool.
+    pub fn system_info(&self) -> Result<SystemInfo> {
+        info!("Collecting system info");
+
+        let mut sys = System::new();
+        sys.refresh_cpu();
+        let before = read_diskstats()?;
+        let started = Instant::now();
+
+        std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+
+        sys.refresh_cpu();
+        sys.refresh_memory();
+        let after = read_diskstats()?;
+        let disks = disk_io(&before, &after, started.elapsed().as_secs_f64());
+
+        let filesystems = filesystems()?;
+        // The scalar field keeps meaning "how full is the system disk"
+        let disk = filesystems
+            .iter()
+            .find(|fs| fs.mount_point == "/")
+            .map_or(0.0, |fs| fs.percent);
+
+        let memory = match sys.total_memory() {
+            0 => 0.0,
+            total => sys.used_memory() as f64 / total as f64 * 100.0,
+        };
+
+        Ok(SystemInfo {
+            cpu: sys.global_cpu_info().cpu_usage() as f64,
+            memory,
+            disk,
+            uptime: System::uptime(),
+            hostname: System::host_name().unwrap_or_default(),
+            processes: process_count(),
+            filesystems,
+            disks,
+        })
+    }
+
+    /// Lists interfaces with their addresses and traffic, plus default
+    /// routes and DNS servers. Rates are measured by sleeping for
+    /// `NETWORK_SAMPLE_INTERVAL`, so run it on the blocking pool.
+    pub fn network_info(&self) -> Result<NetworkInfo> {
+        info!("Collecting network info");
+
+        let mut networks = Networks::new_with_refreshed_list();
+        let started = Instant::now();
+        std::thread::sleep(NETWORK_SAMPLE_INTERVAL);
+        networks.refresh();
+        let secs = started.elapsed().as_secs_f64();
+
+        let addresses = interface_addresses();
+
+        let mut interfaces: Vec<NetworkInterface> = networks
+            .iter()
+            .map(|(name, data)| {
+                let sys = Path::new(SYS_NET).join(name);
+                let mac = data.mac_address();
+                NetworkInterface {
+                    name: name.clone(),
+                    mac: (!mac.is_unspecified()).then(|| mac.to_string()),
+                    state: read_attr(&sys.join("operstate"))
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    mtu: read_attr(&sys.join("mtu")).and_then(|mtu| mtu.parse().ok()),
+                    addresses: addresses
+                        .iter()
+                        .filter(|(interface, _)| interface == name)
+                        .map(|(_, address)| address.clone())
+                        .collect(),
+                    rx_bytes: data.total_received(),
+                    tx_bytes: data.total_transmitted(),
+                    rx_errors: data.total_errors_on_received(),
+                    tx_errors: data.total_errors_on_transmitted(),
+                    rx_rate: data.received() as f64 / secs,
+                    tx_rate: data.transmitted() as f64 / secs,
+                }
+            })
+            .collect();
+        interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut routes = parse_routes(&fs::read_to_string(ROUTES).unwrap_or_default());
+        routes.extend(parse_routes_v6(
+            &fs::read_to_string(ROUTES_V6).unwrap_or_default(),
+        ));
+
+        Ok(NetworkInfo {
+            interfaces,
+            routes,
+            dns: parse_resolv_conf(&fs::read_to_string(RESOLV_CONF).unwrap_or_default()),
+        })
+    }
+}
+
+/// Addresses of all interfaces, keyed by interface name.
+fn interface_addresses() -> Vec<(String, InterfaceAddress)> {
+    let addrs = match getifaddrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            debug!("getifaddrs failed: {}", e);
+            return Vec::new();
+        }
+    };
+
+    addrs
+        .filter_map(|ifaddr| {
+            let address = ifaddr.address?;
+            let netmask = ifaddr.netmask;
+            let address = if let Some(v4) = address.as_sockaddr_in() {
+                InterfaceAddress {
+                    family: IpFamily::Ipv4,
+                    address: Ipv4Addr::from(v4.ip()).to_string(),
+                    prefix: netmask
+                        .and_then(|mask| mask.as_sockaddr_in().map(|m| m.ip().count_ones() as u8))
+                        .unwrap_or(32),
+                }
+            } else if let Some(v6) = address.as_sockaddr_in6() {
+                InterfaceAddress {
+                    family: IpFamily::Ipv6,
+                    address: v6.ip().to_string(),
+                    prefix: netmask
+                        .and_then(|mask| {
+                            mask.as_sockaddr_in6()
+                                .map(|m| u128::from(m.ip()).count_ones() as u8)
+                        })
+                        .unwrap_or(128),
+                }
+            } else {
+                return None;
+            };
+            Some((ifaddr.interface_name, address))
+        })
+        .collect()
+}
+
+/// Default IPv4 routes from /proc/net/route, whose addresses are hex in
+/// host byte order.
+fn parse_routes(text: &str) -> Vec<DefaultRoute> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let hex = |i: usize| u32::from_str_radix(fields.get(i)?, 16).ok();
+            let (destination, gateway, flags, metric, mask) = (
+                hex(1)?,
+                hex(2)?,
+                hex(3)?,
+                fields.get(6)?.parse().ok()?,
+                hex(7)?,
+            );
+            if destination != 0 || mask != 0 || flags & RTF_UP == 0 {
+                return None;
+            }
+            Some(DefaultRoute {
+                family: IpFamily::Ipv4,
+                interface: fields[0].to_string(),
+                gateway: Ipv4Addr::from(gateway.to_ne_bytes()).to_string(),
+                metric,
+            })
+        })
+        .collect()
+}
+
+/// Default IPv6 routes from /proc/net/ipv6_route: destination, prefix
+/// length, source, source prefix, next hop, metric, refcount, use, flags
+/// and interface.
+fn parse_routes_v6(text: &str) -> Vec<DefaultRoute> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let destination = u128::from_str_radix(fields.first()?, 16).ok()?;
+            let prefix = u8::from_str_radix(fields.get(1)?, 16).ok()?;
+            let next_hop = u128::from_str_radix(fields.get(4)?, 16).ok()?;
+            let metric = u32::from_str_radix(fields.get(5)?, 16).ok()?;
+            let flags = u32::from_str_radix(fields.get(8)?, 16).ok()?;
+            let interface = fields.get(9)?;
+            // The kernel lists an unreachable ::/0 on lo when there is no route
+            if destination != 0 || prefix != 0 || flags & RTF_UP == 0 || *interface == "lo" {
+                return None;
+            }
+            Some(DefaultRoute {
+                family: IpFamily::Ipv6,
+                interface: interface.to_string(),
+                gateway: Ipv6Addr::from(next_hop).to_string(),
+                metric,
+            })
+        })
+        .collect()
+}
+
+fn parse_resolv_conf(text: &str) -> DnsConfig {
+    let mut dns = DnsConfig::default();
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("nameserver") => dns.nameservers.extend(words.next().map(str::to_string)),
+            Some("search") | Some("domain") => dns.search.extend(words.map(str::to_string)),
+            _ => {}
+        }
+    }
+    dns
+}
+
+/// Counts the entries in /proc that are processes, without reading each
+/// one's status the way a full process refresh does.
+fn process_count() -> usize {
+    fs::read_dir(PROC).map_or(0, |entries| {
+        entries
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().bytes().all(|b| b.is_ascii_digit()))
+            .count()
+    })
+}
+
+/// Usage of every mounted block device filesystem. A device mounted more
+/// than once (bind mounts) is reported at its first mount point.
+pub fn filesystems() -> Result<Vec<FilesystemUsage>> {
+    let mut seen = HashSet::new();
+    let mut filesystems = Vec::new();
+
+    for mount in mounts::read_mounts()? {
+        if !mount.source.starts_with("/dev/") || !seen.insert(mount.dev.clone()) {
+            continue;
+        }
+        let Some(usage) = mounts::usage(&mount.mount_point) else {
+            continue;
+        };
+        if usage.total == 0 {
+            continue;
+        }
+
+        // Like df, relative to the space usable by unprivileged users
+        let usable = usage.used + usage.free;
+        let percent = match usable {
+            0 => 0.0,
+            usable => usage.used as f64 / usable as f64 * 100.0,
+        };
+
+        filesystems.push(FilesystemUsage {
+            device: mount.source,
+            mount_point: mount.mount_point.to_string_lossy().to_string(),
+            fs_type: mount.fs_type,
+            total: usage.total,
+            used: usage.used,
+            free: usage.free,
+            percent,
+        });
+    }
+
+    Ok(filesystems)
+}
+
+/// Reads the counters of whole disks; partitions are left out since
+/// their IO is already part of the disk's.
+pub fn read_diskstats() -> Result<Vec<DiskStats>> {
+    let text = fs::read_to_string(DISKSTATS)?;
+    Ok(parse_diskstats(&text)
+        .into_iter()
+        .filter(|disk| Path::new(SYS_BLOCK).join(&disk.name).exists())
+        .collect())
+}
+
+/// Throughput between two samples taken `secs` apart. Disks that have
+/// never done any IO, such as unused loop devices, are skipped.
+pub fn disk_io(before: &[DiskStats], after: &[DiskStats], secs: f64) -> Vec<DiskIo> {
+    after
+        .iter()
+        .filter(|disk| disk.read_bytes > 0 || disk.write_bytes > 0)
+        .map(|disk| {
+            let previous = before.iter().find(|b| b.name == disk.name);
+            let delta = |f: fn(&DiskStats) -> u64| {
+                previous.map_or(0, |p| f(disk).saturating_sub(f(p))) as f64
+            };
+            let rate = |bytes: f64| if secs > 0.0 { bytes / secs } else { 0.0 };
+
+            DiskIo {
+                name: disk.name.clone(),
+                read_bytes: disk.read_bytes,
+                write_bytes: disk.write_bytes,
+                read_rate: rate(delta(|d| d.read_bytes)),
+                write_rate: rate(delta(|d| d.write_bytes)),
+                busy: (rate(delta(|d| d.io_ms)) / 10.0).min(100.0),
+            }
+        })
+        .collect()
+}
+
+/// Fields are `major minor name reads merged sectors-read ms-reading
+/// writes merged sectors-written ms-writing in-flight ms-io ...`.
+fn parse_diskstats(text: &str) -> Vec<DiskStats> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let number = |i: usize| fields.get(i)?.parse::<u64>().ok();
+            Some(DiskStats {
+                name: fields.get(2)?.to_string(),
+                read_bytes: number(5)? * SECTOR_SIZE,
+                write_bytes: number(9)? * SECTOR_SIZE,
+                io_ms: number(12)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_io() {
+        let before = parse_diskstats(
+            " 179       0 mmcblk0 5000 10 80000 900 2000 50 40000 3000 0 1000 3900\n\
+               7       0 loop0 0 0 0 0 0 0 0 0 0 0 0\n",
+        );
+        let after = parse_diskstats(
+            " 179       0 mmcblk0 5100 10 84000 950 2100 50 42000 3100 0 1500 4050\n\
+               7       0 loop0 0 0 0 0 0 0 0 0 0 0 0\n",
+        );
+        assert_eq!(before[0].read_bytes, 80000 * 512);
+
+        let io = disk_io(&before, &after, 2.0);
+        assert_eq!(io.len(), 1);
+        assert_eq!(io[0].name, "mmcblk0");
+        assert_eq!(io[0].read_rate, 4000.0 * 512.0 / 2.0);
+        assert_eq!(io[0].write_rate, 2000.0 * 512.0 / 2.0);
+        assert_eq!(io[0].busy, 25.0);
+    }
+
+    #[test]
+    fn test_network_config() {
+        // The kernel prints addresses in host byte order
+        let hex = |address: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(address));
+        let routes = parse_routes(&format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t00000000\t{}\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+             eth0\t{}\t00000000\t0001\t0\t0\t100\t{}\t0\t0\t0\n",
+            hex([192, 168, 1, 1]),
+            hex([192, 168, 1, 0]),
+            hex([255, 255, 255, 0]),
+        ));
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].gateway, "192.168.1.1");
+        assert_eq!(routes[0].metric, 100);
+
+        let routes = parse_routes_v6(
+            "00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 wlan0\n\
+             00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200 lo\n",
+        );
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].interface, "wlan0");
+        assert_eq!(routes[0].gateway, "fe80::1");
+        assert_eq!(routes[0].metric, 1024);
+
+        let dns = parse_resolv_conf(
+            "# generated\nnameserver 192.168.1.1\nnameserver ::1\nsearch lan home\n",
+        );
+        assert_eq!(dns.nameservers, vec!["192.168.1.1", "::1"]);
+        assert_eq!(dns.search, vec!["lan", "home"]);
+    }
+}
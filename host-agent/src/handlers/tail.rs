@@ -0,0 +1,224 @@
+use crate::error::{AgentError, Result};
+use crate::security::Validator;
+use log::{debug, info};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+/// Chunk size when scanning backwards for line breaks.
+const CHUNK_SIZE: u64 = 8 * 1024;
+
+/// Upper bounds for the initial line count and for what is read at once,
+/// so huge lines or a file growing by gigabytes cannot flood the
+/// connection.
+const MAX_TAIL_LINES: usize = 10_000;
+const MAX_READ_PER_POLL: u64 = 1024 * 1024;
+
+pub struct TailHandler {
+    validator: Validator,
+}
+
+/// Position of a followed file. Rotation is detected by the inode at
+/// the path changing, truncation by the size dropping below the offset.
+pub struct TailState {
+    path: PathBuf,
+    file: File,
+    inode: u64,
+    offset: u64,
+    partial: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct TailUpdate {
+    pub lines: Vec<String>,
+    pub truncated: bool,
+    pub rotated: bool,
+}
+
+impl TailHandler {
+    pub fn new(validator: Validator) -> Self {
+        Self { validator }
+    }
+
+    /// Returns the last `lines` lines of a file by scanning backwards from
+    /// the end, so only the tail is read regardless of the file size.
+    pub fn tail(&self, path: &str, lines: usize) -> Result<(Vec<String>, TailState)> {
+        info!("Tailing {} ({} lines)", path, lines);
+
+        let validated_path = self.validator.validate_path(path)?;
+        // Checked before opening, as opening a FIFO blocks until a writer shows up
+        let metadata = fs::metadata(&validated_path)?;
+        if !metadata.is_file() {
+            return Err(AgentError::InvalidRequest("Path is not a file".to_string()));
+        }
+        let mut file = File::open(&validated_path)?;
+
+        let size = metadata.len();
+        let start = find_tail_start(&mut file, size, lines.min(MAX_TAIL_LINES))?;
+        // Very long lines are cut at the front rather than read whole
+        let capped = start.max(size.saturating_sub(MAX_READ_PER_POLL));
+
+        file.seek(SeekFrom::Start(capped))?;
+        let mut buf = Vec::with_capacity((size - capped) as usize);
+        (&mut file).take(size - capped).read_to_end(&mut buf)?;
+
+        // A trailing line without newline may still be in the middle of
+        // being written; it is returned now and not repeated later.
+        let result = split_lines(&buf);
+
+        self.validator.audit_log("TAIL", &validated_path, true);
+        Ok((
+            result,
+            TailState {
+                path: validated_path,
+                file,
+                inode: metadata.ino(),
+                offset: size,
+                partial: Vec::new(),
+            },
+        ))
+    }
+}
+
+impl TailState {
+    /// Reads whatever was appended since the last call. Only complete
+    /// lines are returned; an unterminated last line is held back.
+    pub fn poll(&mut self) -> Result<TailUpdate> {
+        let mut update = TailUpdate::default();
+
+        // The path was validated once; a replacement must not redirect us
+        let metadata = fs::symlink_metadata(&self.path)?;
+        if !metadata.is_file() {
+            return Err(AgentError::InvalidRequest(format!(
+                "{} is no longer a regular file",
+                self.path.display()
+            )));
+        }
+
+        if metadata.ino() != self.inode {
+            debug!("{:?} was rotated", self.path);
+            self.file = File::open(&self.path)?;
+            self.inode = metadata.ino();
+            self.offset = 0;
+            self.partial.clear();
+            update.rotated = true;
+        } else if metadata.len() < self.offset {
+            debug!("{:?} was truncated", self.path);
+            self.offset = 0;
+            self.partial.clear();
+            update.truncated = true;
+        }
+
+        let available = metadata.len().saturating_sub(self.offset);
+        if available == 0 {
+            return Ok(update);
+        }
+
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = std::mem::take(&mut self.partial);
+        let read = (&mut self.file)
+            .take(available.min(MAX_READ_PER_POLL))
+            .read_to_end(&mut buf)?;
+        self.offset += read as u64;
+
+        match buf.iter().rposition(|&b| b == b'\n') {
+            Some(end) => {
+                self.partial = buf.split_off(end + 1);
+                update.lines = split_lines(&buf);
+            }
+            None => self.partial = buf,
+        }
+
+        Ok(update)
+    }
+}
+
+/// Finds the offset where the last `lines` lines begin. A final newline
+/// terminates the last line rather than starting an empty one.
+fn find_tail_start(file: &mut File, size: u64, lines: usize) -> Result<u64> {
+    if lines == 0 {
+        return Ok(size);
+    }
+
+    let mut remaining = lines;
+    let mut pos = size;
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut skip_final_newline = true;
+
+    while pos > 0 {
+        let len = pos.min(CHUNK_SIZE);
+        pos -= len;
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut buf[..len as usize])?;
+
+        for i in (0..len as usize).rev() {
+            if buf[i] != b'\n' {
+                skip_final_newline = false;
+                continue;
+            }
+            if skip_final_newline {
+                skip_final_newline = false;
+                continue;
+            }
+            remaining -= 1;
+            if remaining == 0 {
+                return Ok(pos + i as u64 + 1);
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+fn split_lines(buf: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(buf);
+    text.lines().map(|line| line.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use std::io::Write;
+
+    #[test]
+    fn test_find_tail_start() {
+        let root = TestDir::new("tail");
+        let path = root.join("file");
+        let mut content = String::new();
+        for i in 0..5000 {
+            content.push_str(&format!("line {}\n", i));
+        }
+        File::create(&path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        let size = content.len() as u64;
+        let start = find_tail_start(&mut file, size, 3).unwrap();
+        assert_eq!(&content[start as usize..], "line 4997\nline 4998\nline 4999\n");
+        assert_eq!(find_tail_start(&mut file, size, 10_000).unwrap(), 0);
+        assert_eq!(find_tail_start(&mut file, size, 0).unwrap(), size);
+    }
+
+    #[test]
+    fn test_tail_fifo_and_long_lines() {
+        let root = TestDir::new("tail-limits");
+        let handler = TailHandler::new(root.validator());
+
+        // Opening the FIFO would wait for a writer forever
+        let fifo = root.join("fifo");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU).unwrap();
+        assert!(handler.tail(&fifo.to_string_lossy(), 10).is_err());
+
+        let long = root.join("long");
+        let mut content = vec![b'x'; MAX_READ_PER_POLL as usize * 2];
+        content.extend_from_slice(b"\nlast\n");
+        fs::write(&long, &content).unwrap();
+        let (lines, _) = handler.tail(&long.to_string_lossy(), 2).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].len() < MAX_READ_PER_POLL as usize);
+        assert_eq!(lines[1], "last");
+    }
+}
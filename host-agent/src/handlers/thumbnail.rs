@@ -0,0 +1,284 @@
+use crate::config::ThumbnailConfig;
+use crate::error::{AgentError, Result};
+use crate::protocol::{ThumbnailData, ThumbnailFormat};
+use crate::security::Validator;
+use base64::Engine;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// Freedesktop thumbnail sizes, by cache directory name.
+const BUCKETS: [(&str, u32); 4] = [
+    ("normal", 128),
+    ("large", 256),
+    ("x-large", 512),
+    ("xx-large", 1024),
+];
+
+const JPEG_QUALITY: u8 = 85;
+
+pub struct ThumbnailHandler {
+    validator: Validator,
+    config: ThumbnailConfig,
+}
+
+impl ThumbnailHandler {
+    pub fn new(validator: Validator, config: ThumbnailConfig) -> Self {
+        Self { validator, config }
+    }
+
+    /// Returns a thumbnail for an image file. The requested size is
+    /// rounded up to the next freedesktop bucket (128, 256, 512, 1024) and
+    /// results are cached as PNG under `cache_dir/<bucket>/<md5(uri)>.png`.
+    pub fn thumbnail(
+        &self,
+        path: &str,
+        size: u32,
+        format: ThumbnailFormat,
+    ) -> Result<ThumbnailData> {
+        info!("Thumbnail for {} at {}px", path, size);
+
+        let validated_path = self.validator.validate_path(path)?;
+        let metadata = fs::metadata(&validated_path)?;
+        if !metadata.is_file() {
+            return Err(AgentError::InvalidRequest("Path is not a file".to_string()));
+        }
+        self.validator.validate_file_size(&validated_path)?;
+
+        let (bucket, pixels) = BUCKETS
+            .iter()
+            .copied()
+            .find(|(_, px)| size <= *px)
+            .unwrap_or(BUCKETS[BUCKETS.len() - 1]);
+
+        let uri = file_uri(&validated_path);
+        let mtime = metadata.mtime();
+        let cache_path = Path::new(&self.config.cache_dir)
+            .join(bucket)
+            .join(format!("{}.png", md5_hex(&uri)));
+
+        // A fresh thumbnail is at hand already; a cached one is only
+        // decoded again when it has to be converted
+        let (png_data, decoded, cached) = match read_cached(&cache_path, &uri, mtime) {
+            Some(data) => (data, None, true),
+            None => {
+                let image = self.decode(&validated_path)?;
+                let thumb = image.thumbnail(pixels, pixels);
+                let data = encode_png(&thumb, &uri, mtime)?;
+                if let Err(e) = write_cache(&cache_path, &data) {
+                    warn!("Failed to cache thumbnail {:?}: {}", cache_path, e);
+                }
+                (data, Some(thumb), false)
+            }
+        };
+
+        let (data, mime_type, (width, height)) = match format {
+            ThumbnailFormat::Png => {
+                let dimensions = match &decoded {
+                    Some(thumb) => (thumb.width(), thumb.height()),
+                    None => ImageReader::with_format(Cursor::new(&png_data), ImageFormat::Png)
+                        .into_dimensions()
+                        .map_err(|e| AgentError::Internal(format!("Corrupt thumbnail: {}", e)))?,
+                };
+                (png_data, "image/png", dimensions)
+            }
+            ThumbnailFormat::Jpeg => {
+                let thumb = match decoded {
+                    Some(thumb) => thumb,
+                    None => image::load_from_memory_with_format(&png_data, ImageFormat::Png)
+                        .map_err(|e| AgentError::Internal(format!("Corrupt thumbnail: {}", e)))?,
+                };
+                let mut out = Vec::new();
+                let encoder =
+                    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY);
+                thumb
+                    .to_rgb8()
+                    .write_with_encoder(encoder)
+                    .map_err(|e| AgentError::Internal(format!("JPEG encoding failed: {}", e)))?;
+                (out, "image/jpeg", (thumb.width(), thumb.height()))
+            }
+        };
+
+        self.validator.audit_log("THUMBNAIL", &validated_path, true);
+        Ok(ThumbnailData {
+            mime_type: mime_type.to_string(),
+            width,
+            height,
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+            cached,
+        })
+    }
+
+    /// Decodes an image with dimension and allocation limits so a
+    /// maliciously large file cannot exhaust the Pi's memory.
+    fn decode(&self, path: &Path) -> Result<DynamicImage> {
+        let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+
+        match reader.format() {
+            Some(
+                ImageFormat::Png
+                | ImageFormat::Jpeg
+                | ImageFormat::Gif
+                | ImageFormat::WebP
+                | ImageFormat::Bmp,
+            ) => {}
+            _ => {
+                return Err(AgentError::InvalidRequest(
+                    "Unsupported image format".to_string(),
+                ))
+            }
+        }
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.config.max_decode_dimension);
+        limits.max_image_height = Some(self.config.max_decode_dimension);
+        limits.max_alloc = Some(self.config.max_decode_memory);
+        reader.limits(limits);
+
+        reader
+            .decode()
+            .map_err(|e| AgentError::InvalidRequest(format!("Cannot decode image: {}", e)))
+    }
+}
+
+/// Returns the cached thumbnail if its `Thumb::URI` and `Thumb::MTime`
+/// chunks still match the source file.
+fn read_cached(cache_path: &Path, uri: &str, mtime: i64) -> Option<Vec<u8>> {
+    let file = File::open(cache_path).ok()?;
+    let reader = png::Decoder::new(BufReader::new(file)).read_info().ok()?;
+
+    let info = reader.info();
+    let text = |key: &str| {
+        info.uncompressed_latin1_text
+            .iter()
+            .find(|chunk| chunk.keyword == key)
+            .map(|chunk| chunk.text.clone())
+    };
+
+    if text("Thumb::URI").as_deref() != Some(uri)
+        || text("Thumb::MTime") != Some(mtime.to_string())
+    {
+        debug!("Stale thumbnail {:?}", cache_path);
+        return None;
+    }
+
+    fs::read(cache_path).ok()
+}
+
+fn encode_png(image: &DynamicImage, uri: &str, mtime: i64) -> Result<Vec<u8>> {
+    let rgba = image.to_rgba8();
+    let mut out = Vec::new();
+
+    let mut encoder = png::Encoder::new(Cursor::new(&mut out), rgba.width(), rgba.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let result = encoder
+        .add_text_chunk("Thumb::URI".to_string(), uri.to_string())
+        .and_then(|_| encoder.add_text_chunk("Thumb::MTime".to_string(), mtime.to_string()))
+        .and_then(|_| encoder.write_header())
+        .and_then(|mut writer| {
+            writer.write_image_data(rgba.as_raw())?;
+            writer.finish()
+        });
+    result.map_err(|e| AgentError::Internal(format!("PNG encoding failed: {}", e)))?;
+
+    Ok(out)
+}
+
+/// Writes the thumbnail via a temporary file so readers never see a
+/// partially written PNG. Directories are 0700 and files 0600 as the
+/// freedesktop specification requires.
+fn write_cache(cache_path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = cache_path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+
+    let tmp_path: PathBuf = cache_path.with_extension(format!("png.{}.tmp", std::process::id()));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(data)?;
+    drop(file);
+
+    fs::rename(&tmp_path, cache_path)
+}
+
+fn md5_hex(input: &str) -> String {
+    Md5::digest(input.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Builds the `file://` URI the freedesktop cache is keyed by.
+fn file_uri(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut uri = String::from("file://");
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn test_file_uri() {
+        assert_eq!(
+            file_uri(Path::new("/home/pi/Photos/summer 2024/IMG_01.jpg")),
+            "file:///home/pi/Photos/summer%202024/IMG_01.jpg"
+        );
+        assert_eq!(file_uri(Path::new("/tmp/č.png")), "file:///tmp/%C4%8D.png");
+    }
+
+    #[test]
+    fn test_cached_thumbnail() {
+        let root = TestDir::new("thumbnail");
+        let image = root.join("wide.png");
+        DynamicImage::new_rgb8(300, 150).save(&image).unwrap();
+        let config = ThumbnailConfig {
+            cache_dir: root.join("cache").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let handler = ThumbnailHandler::new(root.validator(), config);
+        let path = image.to_string_lossy();
+
+        let fresh = handler.thumbnail(&path, 100, ThumbnailFormat::Png).unwrap();
+        assert!(!fresh.cached);
+        assert_eq!((fresh.width, fresh.height), (128, 64));
+
+        // Cache hits report the same size whether or not they are converted
+        let png = handler.thumbnail(&path, 100, ThumbnailFormat::Png).unwrap();
+        assert!(png.cached);
+        assert_eq!((png.width, png.height), (128, 64));
+        assert_eq!(png.data, fresh.data);
+        let jpeg = handler.thumbnail(&path, 100, ThumbnailFormat::Jpeg).unwrap();
+        assert!(jpeg.cached);
+        assert_eq!((jpeg.mime_type.as_str(), jpeg.width, jpeg.height), ("image/jpeg", 128, 64));
+    }
+
+    #[test]
+    fn test_cache_key() {
+        // Reference value from the freedesktop thumbnail specification
+        assert_eq!(
+            md5_hex("file:///home/jens/photos/me.png"),
+            "c6ee772d9e49320e97ec29a7eb5b1697"
+        );
+    }
+}
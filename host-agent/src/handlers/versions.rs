@@ -0,0 +1,324 @@
+use crate::config::VersioningConfig;
+use crate::error::{AgentError, Result};
+use crate::handlers::{diff, encoding};
+use crate::protocol::{FileDiff, FileVersion};
+use crate::security::Validator;
+use log::{debug, info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the hidden store kept in each allowed root when no central
+/// `store_dir` is configured.
+pub const VERSIONS_DIR: &str = ".webdesk-versions";
+
+/// Previous contents of files overwritten through the agent. Each file
+/// gets a directory in the store mirroring its path, holding one copy
+/// per version named by the millisecond timestamp it was taken at.
+#[derive(Clone)]
+pub struct VersionStore {
+    config: VersioningConfig,
+    roots: Vec<PathBuf>,
+}
+
+pub struct VersionHandler {
+    validator: Validator,
+    store: VersionStore,
+}
+
+impl VersionStore {
+    pub fn new(config: VersioningConfig, allowed_paths: &[PathBuf]) -> Self {
+        Self {
+            config,
+            roots: allowed_paths.to_vec(),
+        }
+    }
+
+    /// Keeps the current content of `path` before it is replaced by
+    /// `replacement`. Nothing is kept for new files, unchanged content or
+    /// files above the configured size. The copy counts against quotas
+    /// like any other write, so it fails when it does not fit.
+    pub fn snapshot(
+        &self,
+        validator: &Validator,
+        path: &Path,
+        replacement: &[u8],
+    ) -> Result<Option<String>> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Ok(None),
+        };
+        if metadata.len() > self.config.max_file_size {
+            debug!("Not versioning {:?}: {} bytes", path, metadata.len());
+            return Ok(None);
+        }
+
+        let current = fs::read(path)?;
+        if current == replacement {
+            return Ok(None);
+        }
+
+        let dir = self.dir_for(path)?;
+        fs::create_dir_all(&dir)?;
+
+        // Two saves within the same millisecond still get distinct ids
+        let mut stamp = chrono::Utc::now().timestamp_millis();
+        while dir.join(stamp.to_string()).exists() {
+            stamp += 1;
+        }
+        let id = stamp.to_string();
+        let version = dir.join(&id);
+        let reservation = validator.check_quota(&version, current.len() as u64)?;
+        fs::write(&version, current)?;
+        reservation.commit();
+        debug!("Kept version {} of {:?}", id, path);
+
+        self.prune(validator, &dir)?;
+        Ok(Some(id))
+    }
+
+    /// Lists the versions of `path`, newest first.
+    pub fn list(&self, path: &Path) -> Result<Vec<FileVersion>> {
+        let dir = self.dir_for(path)?;
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut stamps = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let Some(stamp) = entry
+                .file_name()
+                .to_str()
+                .and_then(|n| n.parse::<i64>().ok())
+            else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                stamps.push((stamp, metadata.len()));
+            }
+        }
+        stamps.sort_unstable_by(|a, b| b.cmp(a));
+
+        Ok(stamps
+            .into_iter()
+            .map(|(stamp, size)| FileVersion {
+                id: stamp.to_string(),
+                timestamp: stamp / 1000,
+                size,
+            })
+            .collect())
+    }
+
+    /// Reads one stored version of `path`.
+    pub fn read(&self, path: &Path, id: &str) -> Result<Vec<u8>> {
+        // Ids are timestamps; anything else could name a path outside the store
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(AgentError::InvalidRequest(format!(
+                "Invalid version id: {}",
+                id
+            )));
+        }
+
+        let version = self.dir_for(path)?.join(id);
+        fs::read(&version).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                AgentError::FileNotFound(format!("{}@{}", path.display(), id))
+            }
+            _ => AgentError::Io(e),
+        })
+    }
+
+    /// Directory holding the versions of `path`: below the central store
+    /// by absolute path, or below the innermost allowed root containing it.
+    fn dir_for(&self, path: &Path) -> Result<PathBuf> {
+        if let Some(store) = &self.config.store_dir {
+            let relative = path.strip_prefix("/").unwrap_or(path);
+            return Ok(Path::new(store).join(relative));
+        }
+
+        let root = self
+            .roots
+            .iter()
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .ok_or_else(|| AgentError::PathNotAllowed(path.display().to_string()))?;
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        Ok(root.join(VERSIONS_DIR).join(relative))
+    }
+
+    /// Applies the retention settings to one file's versions.
+    fn prune(&self, validator: &Validator, dir: &Path) -> Result<()> {
+        let mut stamps: Vec<i64> = fs::read_dir(dir)?
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .collect();
+        stamps.sort_unstable_by(|a, b| b.cmp(a));
+
+        let cutoff = chrono::Utc::now().timestamp_millis()
+            - (self.config.max_age_days as i64).saturating_mul(86_400_000);
+
+        for (index, stamp) in stamps.iter().enumerate() {
+            let too_many = self.config.max_versions > 0 && index >= self.config.max_versions;
+            let too_old = self.config.max_age_days > 0 && *stamp < cutoff;
+            if too_many || too_old {
+                debug!("Pruning version {} in {:?}", stamp, dir);
+                let version = dir.join(stamp.to_string());
+                let size = fs::metadata(&version)?.len();
+                fs::remove_file(&version)?;
+                validator.release_quota(&version, size);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VersionHandler {
+    pub fn new(validator: Validator, config: VersioningConfig) -> Self {
+        let store = VersionStore::new(config, &validator.config().allowed_paths);
+        Self { validator, store }
+    }
+
+    pub fn list_versions(&self, path: &str) -> Result<Vec<FileVersion>> {
+        info!("Listing versions of {}", path);
+
+        let validated_path = self.validator.validate_path(path)?;
+        self.store.list(&validated_path)
+    }
+
+    /// Puts a stored version back in place. The content being replaced is
+    /// kept as a new version, so a restore can itself be undone.
+    pub fn restore_version(&self, path: &str, version: &str) -> Result<()> {
+        info!("Restoring {} to version {}", path, version);
+
+        let validated_path = self.validator.validate_path(path)?;
+        self.validator.check_lock(&validated_path)?;
+
+        let bytes = self.store.read(&validated_path, version)?;
+        if bytes.len() as u64 > self.validator.config().max_file_size {
+            return Err(AgentError::PermissionDenied(
+                "Content size exceeds limit".to_string(),
+            ));
+        }
+
+        let existing = fs::metadata(&validated_path).map(|m| m.len()).unwrap_or(0);
+        let new_len = bytes.len() as u64;
+        let reservation = self
+            .validator
+            .check_quota(&validated_path, new_len.saturating_sub(existing))?;
+
+        if let Err(e) = self.store.snapshot(&self.validator, &validated_path, &bytes) {
+            warn!("Failed to keep version of {:?}: {}", validated_path, e);
+        }
+        fs::write(&validated_path, bytes)?;
+        reservation.commit();
+        self.validator
+            .release_quota(&validated_path, existing.saturating_sub(new_len));
+
+        self.validator
+            .audit_log("RESTORE_VERSION", &validated_path, true);
+        Ok(())
+    }
+
+    /// Diffs a stored version against the current file, or against another
+    /// version when `against` is given.
+    pub fn diff_version(
+        &self,
+        path: &str,
+        version: &str,
+        against: Option<&str>,
+    ) -> Result<FileDiff> {
+        info!("Diffing {} version {}", path, version);
+
+        let validated_path = self.validator.validate_path(path)?;
+        let label = validated_path.to_string_lossy();
+
+        let old = self.store.read(&validated_path, version)?;
+        let (new, new_label) = match against {
+            Some(other) => (
+                self.store.read(&validated_path, other)?,
+                format!("{}@{}", label, other),
+            ),
+            None => {
+                self.validator.validate_file_size(&validated_path)?;
+                (fs::read(&validated_path)?, label.to_string())
+            }
+        };
+
+        let (old_text, _) = encoding::decode(&old)?;
+        let (new_text, _) = encoding::decode(&new)?;
+        let diff = diff::diff_text(
+            &format!("{}@{}", label, version),
+            &new_label,
+            &old_text,
+            &new_text,
+        );
+
+        self.validator
+            .audit_log("DIFF_VERSION", &validated_path, true);
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use crate::config::PathQuota;
+
+    #[test]
+    fn test_snapshot_retention() {
+        let root = TestDir::new("versions");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        let file = root.join("docs/notes.txt");
+
+        let config = VersioningConfig {
+            enabled: true,
+            store_dir: None,
+            max_versions: 2,
+            max_age_days: 30,
+            max_file_size: 1024,
+        };
+        let store = VersionStore::new(config, &[root.to_path_buf()]);
+        let validator = root.validator();
+
+        // Nothing to keep for a new file
+        assert_eq!(store.snapshot(&validator, &file, b"one").unwrap(), None);
+        fs::write(&file, "one").unwrap();
+        assert_eq!(store.snapshot(&validator, &file, b"one").unwrap(), None);
+
+        for next in ["two", "three", "four"] {
+            assert!(store
+                .snapshot(&validator, &file, next.as_bytes())
+                .unwrap()
+                .is_some());
+            fs::write(&file, next).unwrap();
+        }
+
+        let versions = store.list(&file).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(store.read(&file, &versions[0].id).unwrap(), b"three");
+        assert_eq!(store.read(&file, &versions[1].id).unwrap(), b"two");
+        assert!(root.join(VERSIONS_DIR).join("docs/notes.txt").is_dir());
+        assert!(store.read(&file, "../../notes.txt").is_err());
+
+        // A version that does not fit the quota is not kept
+        let mut validator = root.validator();
+        validator.config.quotas.paths = vec![PathQuota {
+            path: root.to_path_buf(),
+            soft_limit: None,
+            hard_limit: Some(12),
+        }];
+        validator.seed_quotas().unwrap().join().unwrap();
+        assert!(matches!(
+            store.snapshot(&validator, &file, b"five"),
+            Err(AgentError::QuotaExceeded(_))
+        ));
+        assert_eq!(store.list(&file).unwrap().len(), 2);
+    }
+}
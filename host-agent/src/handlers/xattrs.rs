@@ -0,0 +1,427 @@
+use crate::error::{AgentError, Result};
+use crate::handlers::metadata::OwnerCache;
+use crate::protocol::{Acl, AclEntry, AclTag, Xattr};
+use crate::security::Validator;
+use base64::Engine;
+use log::{debug, info};
+use nix::unistd::{Group, User};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// POSIX ACLs are stored by the kernel in these attributes.
+const ACL_ACCESS: &str = "system.posix_acl_access";
+const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+/// Clients may only write the `user` namespace; `security` and `trusted`
+/// attributes carry capabilities and policy that must not be set remotely.
+const WRITABLE_PREFIX: &str = "user.";
+
+// On-disk ACL format, see linux/posix_acl_xattr.h
+const ACL_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_USER: u16 = 0x02;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_GROUP: u16 = 0x08;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+
+pub struct XattrHandler {
+    validator: Validator,
+}
+
+impl XattrHandler {
+    pub fn new(validator: Validator) -> Self {
+        Self { validator }
+    }
+
+    /// Lists extended attributes with their values. ACL attributes are
+    /// left out; `GetAcl` decodes them.
+    pub fn list_xattrs(&self, path: &str) -> Result<Vec<Xattr>> {
+        info!("Listing xattrs of {}", path);
+
+        let validated_path = self.validator.validate_path(path)?;
+        let mut xattrs = Vec::new();
+        for name in xattr::list(&validated_path)? {
+            let name = name.to_string_lossy().to_string();
+            if name == ACL_ACCESS || name == ACL_DEFAULT {
+                continue;
+            }
+            if let Some(value) = xattr::get(&validated_path, &name)? {
+                xattrs.push(to_xattr(name, value));
+            }
+        }
+        xattrs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(xattrs)
+    }
+
+    pub fn get_xattr(&self, path: &str, name: &str) -> Result<Xattr> {
+        info!("Getting xattr {} of {}", name, path);
+
+        let validated_path = self.validator.validate_path(path)?;
+        match xattr::get(&validated_path, name)? {
+            Some(value) => Ok(to_xattr(name.to_string(), value)),
+            None => Err(AgentError::FileNotFound(format!(
+                "{}: no attribute {}",
+                validated_path.display(),
+                name
+            ))),
+        }
+    }
+
+    /// Sets a `user.` attribute. Binary values are sent base64 encoded.
+    pub fn set_xattr(&self, path: &str, name: &str, value: &str, base64: bool) -> Result<()> {
+        info!("Setting xattr {} of {}", name, path);
+
+        self.validator.ensure_metadata_changes_allowed()?;
+        let validated_path = self.validator.validate_path(path)?;
+        self.validator.check_lock(&validated_path)?;
+        check_writable(name)?;
+
+        let value = if base64 {
+            base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .map_err(|e| AgentError::InvalidRequest(format!("Invalid base64 value: {}", e)))?
+        } else {
+            value.as_bytes().to_vec()
+        };
+        xattr::set(&validated_path, name, &value)?;
+
+        self.validator.audit_log("SET_XATTR", &validated_path, true);
+        Ok(())
+    }
+
+    pub fn remove_xattr(&self, path: &str, name: &str) -> Result<()> {
+        info!("Removing xattr {} of {}", name, path);
+
+        self.validator.ensure_metadata_changes_allowed()?;
+        let validated_path = self.validator.validate_path(path)?;
+        self.validator.check_lock(&validated_path)?;
+        check_writable(name)?;
+
+        xattr::remove(&validated_path, name)?;
+
+        self.validator
+            .audit_log("REMOVE_XATTR", &validated_path, true);
+        Ok(())
+    }
+
+    /// Reads the access ACL and, for directories, the default ACL. Files
+    /// without an ACL report the three entries equivalent to their mode.
+    pub fn get_acl(&self, path: &str) -> Result<Acl> {
+        info!("Getting ACL of {}", path);
+
+        let validated_path = self.validator.validate_path(path)?;
+        let metadata = fs::metadata(&validated_path)?;
+        let mut owners = OwnerCache::new();
+
+        let access = match xattr::get(&validated_path, ACL_ACCESS)? {
+            Some(bytes) => decode_acl(&bytes)?,
+            None => mode_acl(metadata.mode()),
+        };
+        let default = match xattr::get(&validated_path, ACL_DEFAULT)? {
+            Some(bytes) => decode_acl(&bytes)?,
+            None => Vec::new(),
+        };
+
+        Ok(Acl {
+            access: resolve_names(access, &mut owners),
+            default: resolve_names(default, &mut owners),
+        })
+    }
+
+    /// Replaces the access and/or default ACL. An omitted list is left
+    /// alone; an empty default list removes the default ACL. The mask is
+    /// computed when named entries are given without one.
+    pub fn set_acl(
+        &self,
+        path: &str,
+        access: Option<Vec<AclEntry>>,
+        default: Option<Vec<AclEntry>>,
+    ) -> Result<()> {
+        info!("Setting ACL of {}", path);
+
+        self.validator.ensure_metadata_changes_allowed()?;
+        let validated_path = self.validator.validate_path(path)?;
+        self.validator.check_lock(&validated_path)?;
+        let metadata = fs::metadata(&validated_path)?;
+
+        // Encode both first so an invalid default ACL changes nothing
+        let access = access.map(encode_acl).transpose()?;
+        let default = match default {
+            Some(_) if !metadata.is_dir() => {
+                return Err(AgentError::InvalidRequest(
+                    "Default ACLs only apply to directories".to_string(),
+                ))
+            }
+            Some(entries) if entries.is_empty() => Some(None),
+            Some(entries) => Some(Some(encode_acl(entries)?)),
+            None => None,
+        };
+
+        // The kernel updates the mode bits to match the access ACL
+        if let Some(bytes) = access {
+            xattr::set(&validated_path, ACL_ACCESS, &bytes)?;
+        }
+        match default {
+            Some(Some(bytes)) => xattr::set(&validated_path, ACL_DEFAULT, &bytes)?,
+            Some(None) => match xattr::remove(&validated_path, ACL_DEFAULT) {
+                Err(e) if e.raw_os_error() == Some(nix::libc::ENODATA) => {}
+                result => result?,
+            },
+            None => {}
+        }
+
+        self.validator.audit_log("SET_ACL", &validated_path, true);
+        Ok(())
+    }
+}
+
+/// Copies `user.` attributes and ACLs from one file to another, as
+/// `cp --preserve=xattr` would for an unprivileged user. Other
+/// namespaces are skipped.
+pub fn copy_xattrs(from: &Path, to: &Path) -> Result<()> {
+    for name in xattr::list(from)? {
+        let name = name.to_string_lossy();
+        if !name.starts_with(WRITABLE_PREFIX) && name != ACL_ACCESS && name != ACL_DEFAULT {
+            debug!("Not copying xattr {} of {:?}", name, from);
+            continue;
+        }
+        if let Some(value) = xattr::get(from, name.as_ref())? {
+            xattr::set(to, name.as_ref(), &value)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_writable(name: &str) -> Result<()> {
+    if !name.starts_with(WRITABLE_PREFIX) || name.len() == WRITABLE_PREFIX.len() {
+        return Err(AgentError::PermissionDenied(format!(
+            "Only user.* attributes can be changed, not {}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Values are returned as text unless they are not UTF-8 or contain NUL
+/// bytes, as binary metadata such as Samba's `user.DOSATTRIB` does.
+fn to_xattr(name: String, value: Vec<u8>) -> Xattr {
+    match String::from_utf8(value) {
+        Ok(text) if !text.contains('\0') => Xattr {
+            name,
+            value: text,
+            base64: false,
+        },
+        Ok(text) => to_base64(name, text.into_bytes()),
+        Err(e) => to_base64(name, e.into_bytes()),
+    }
+}
+
+fn to_base64(name: String, value: Vec<u8>) -> Xattr {
+    Xattr {
+        name,
+        value: base64::engine::general_purpose::STANDARD.encode(value),
+        base64: true,
+    }
+}
+
+fn mode_acl(mode: u32) -> Vec<AclEntry> {
+    [
+        (AclTag::UserObj, mode >> 6),
+        (AclTag::GroupObj, mode >> 3),
+        (AclTag::Other, mode),
+    ]
+    .into_iter()
+    .map(|(tag, bits)| AclEntry {
+        tag,
+        id: None,
+        name: None,
+        perms: format_perms(bits as u16),
+    })
+    .collect()
+}
+
+fn resolve_names(mut entries: Vec<AclEntry>, owners: &mut OwnerCache) -> Vec<AclEntry> {
+    for entry in &mut entries {
+        entry.name = match (entry.tag, entry.id) {
+            (AclTag::User, Some(id)) => owners.user_name(id),
+            (AclTag::Group, Some(id)) => owners.group_name(id),
+            _ => None,
+        };
+    }
+    entries
+}
+
+fn decode_acl(bytes: &[u8]) -> Result<Vec<AclEntry>> {
+    let corrupt = || AgentError::Internal("Malformed ACL attribute".to_string());
+
+    let (header, body) = bytes.split_at_checked(4).ok_or_else(corrupt)?;
+    if u32::from_le_bytes(header.try_into().unwrap()) != ACL_VERSION || body.len() % 8 != 0 {
+        return Err(corrupt());
+    }
+
+    body.chunks_exact(8)
+        .map(|chunk| {
+            let tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+            let perm = u16::from_le_bytes([chunk[2], chunk[3]]);
+            let id = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            let tag = match tag {
+                TAG_USER_OBJ => AclTag::UserObj,
+                TAG_USER => AclTag::User,
+                TAG_GROUP_OBJ => AclTag::GroupObj,
+                TAG_GROUP => AclTag::Group,
+                TAG_MASK => AclTag::Mask,
+                TAG_OTHER => AclTag::Other,
+                _ => return Err(corrupt()),
+            };
+            Ok(AclEntry {
+                tag,
+                id: (id != ACL_UNDEFINED_ID).then_some(id),
+                name: None,
+                perms: format_perms(perm),
+            })
+        })
+        .collect()
+}
+
+/// Validates entries and encodes them in the kernel's format, which
+/// requires them sorted by tag and id.
+fn encode_acl(entries: Vec<AclEntry>) -> Result<Vec<u8>> {
+    let mut raw = Vec::with_capacity(entries.len() + 1);
+    for entry in &entries {
+        let perm = parse_perms(&entry.perms)?;
+        let (tag, id) = match entry.tag {
+            AclTag::UserObj => (TAG_USER_OBJ, ACL_UNDEFINED_ID),
+            AclTag::User => (TAG_USER, qualifier(entry)?),
+            AclTag::GroupObj => (TAG_GROUP_OBJ, ACL_UNDEFINED_ID),
+            AclTag::Group => (TAG_GROUP, qualifier(entry)?),
+            AclTag::Mask => (TAG_MASK, ACL_UNDEFINED_ID),
+            AclTag::Other => (TAG_OTHER, ACL_UNDEFINED_ID),
+        };
+        raw.push((tag, id, perm));
+    }
+    raw.sort_unstable();
+
+    for required in [TAG_USER_OBJ, TAG_GROUP_OBJ, TAG_OTHER] {
+        if raw.iter().filter(|(tag, _, _)| *tag == required).count() != 1 {
+            return Err(AgentError::InvalidRequest(
+                "An ACL needs exactly one user_obj, group_obj and other entry".to_string(),
+            ));
+        }
+    }
+    if raw
+        .windows(2)
+        .any(|pair| pair[0].0 == pair[1].0 && pair[0].1 == pair[1].1)
+    {
+        return Err(AgentError::InvalidRequest(
+            "Duplicate ACL entry".to_string(),
+        ));
+    }
+
+    let named = |tag| tag == TAG_USER || tag == TAG_GROUP;
+    if raw.iter().any(|(tag, _, _)| named(*tag)) && !raw.iter().any(|(tag, _, _)| *tag == TAG_MASK)
+    {
+        // Like setfacl, the mask grants whatever the group class needs
+        let mask = raw
+            .iter()
+            .filter(|(tag, _, _)| named(*tag) || *tag == TAG_GROUP_OBJ)
+            .fold(0, |mask, (_, _, perm)| mask | perm);
+        raw.push((TAG_MASK, ACL_UNDEFINED_ID, mask));
+        raw.sort_unstable();
+    }
+
+    let mut bytes = ACL_VERSION.to_le_bytes().to_vec();
+    for (tag, id, perm) in raw {
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&perm.to_le_bytes());
+        bytes.extend_from_slice(&id.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Named entries take a numeric id or a user/group name.
+fn qualifier(entry: &AclEntry) -> Result<u32> {
+    if let Some(id) = entry.id {
+        return Ok(id);
+    }
+    let name = entry.name.as_deref().ok_or_else(|| {
+        AgentError::InvalidRequest("Named ACL entries need an id or name".to_string())
+    })?;
+
+    let id = match entry.tag {
+        AclTag::User => User::from_name(name)
+            .map_err(|e| AgentError::Internal(e.to_string()))?
+            .map(|user| user.uid.as_raw()),
+        _ => Group::from_name(name)
+            .map_err(|e| AgentError::Internal(e.to_string()))?
+            .map(|group| group.gid.as_raw()),
+    };
+    id.ok_or_else(|| AgentError::InvalidRequest(format!("Unknown user or group: {}", name)))
+}
+
+fn format_perms(bits: u16) -> String {
+    [(4, 'r'), (2, 'w'), (1, 'x')]
+        .iter()
+        .map(|&(bit, c)| if bits & bit != 0 { c } else { '-' })
+        .collect()
+}
+
+fn parse_perms(perms: &str) -> Result<u16> {
+    perms.chars().try_fold(0, |bits, c| match c {
+        'r' => Ok(bits | 4),
+        'w' => Ok(bits | 2),
+        'x' => Ok(bits | 1),
+        '-' => Ok(bits),
+        _ => Err(AgentError::InvalidRequest(format!(
+            "Invalid ACL permissions: {}",
+            perms
+        ))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(tag: AclTag, id: Option<u32>, perms: &str) -> AclEntry {
+        AclEntry {
+            tag,
+            id,
+            name: None,
+            perms: perms.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_acl_roundtrip() {
+        let entries = vec![
+            entry(AclTag::Other, None, "r--"),
+            entry(AclTag::Group, Some(1000), "rw-"),
+            entry(AclTag::UserObj, None, "rwx"),
+            entry(AclTag::GroupObj, None, "r-x"),
+        ];
+        let decoded = decode_acl(&encode_acl(entries).unwrap()).unwrap();
+
+        // Sorted by tag, with the mask filled in from the group class
+        let summary: Vec<_> = decoded
+            .iter()
+            .map(|e| (e.tag, e.id, e.perms.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (AclTag::UserObj, None, "rwx"),
+                (AclTag::GroupObj, None, "r-x"),
+                (AclTag::Group, Some(1000), "rw-"),
+                (AclTag::Mask, None, "rwx"),
+                (AclTag::Other, None, "r--"),
+            ]
+        );
+
+        assert!(encode_acl(vec![entry(AclTag::UserObj, None, "rwx")]).is_err());
+        assert!(parse_perms("rwz").is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::error::{AgentError, Result};
+use crate::protocol::{JobInfo, JobOutput, JobProgress, JobState};
+use log::{error, info};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// How many finished jobs are kept around for clients to query.
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// A long running operation executed on the blocking thread pool.
+///
+/// Workers report progress through the `Job` they are given and should
+/// call `check_cancelled` regularly so `CancelJob` takes effect.
+pub struct Job {
+    seq: u64,
+    id: String,
+    kind: String,
+    started: i64,
+    cancelled: AtomicBool,
+    status: Mutex<JobStatus>,
+}
+
+struct JobStatus {
+    state: JobState,
+    progress: JobProgress,
+    finished: Option<i64>,
+    error: Option<String>,
+    output: Option<JobOutput>,
+}
+
+impl Job {
+    fn new(seq: u64, kind: &str) -> Self {
+        Self {
+            seq,
+            id: format!("job-{}", seq),
+            kind: kind.to_string(),
+            started: chrono::Utc::now().timestamp(),
+            cancelled: AtomicBool::new(false),
+            status: Mutex::new(JobStatus {
+                state: JobState::Running,
+                progress: JobProgress::default(),
+                finished: None,
+                error: None,
+                output: None,
+            }),
+        }
+    }
+
+    pub fn set_total(&self, entries: Option<u64>, bytes: Option<u64>) {
+        let mut status = self.status.lock().unwrap();
+        status.progress.total_entries = entries;
+        status.progress.total_bytes = bytes;
+    }
+
+    pub fn advance(&self, entries: u64, bytes: u64) {
+        let mut status = self.status.lock().unwrap();
+        status.progress.processed_entries += entries;
+        status.progress.processed_bytes += bytes;
+    }
+
+    pub fn set_current(&self, current: &str) {
+        self.status.lock().unwrap().progress.current = Some(current.to_string());
+    }
+
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(AgentError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn finish(&self, result: Result<JobOutput>) {
+        let mut status = self.status.lock().unwrap();
+        status.finished = Some(chrono::Utc::now().timestamp());
+        status.progress.current = None;
+
+        match result {
+            Ok(output) => {
+                status.state = JobState::Completed;
+                status.output = Some(output);
+            }
+            Err(AgentError::Cancelled) => {
+                status.state = JobState::Cancelled;
+            }
+            Err(e) => {
+                error!("Job {} ({}) failed: {}", self.id, self.kind, e);
+                status.state = JobState::Failed;
+                status.error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.status.lock().unwrap().state == JobState::Running
+    }
+
+    fn info(&self) -> JobInfo {
+        let status = self.status.lock().unwrap();
+        JobInfo {
+            id: self.id.clone(),
+            kind: self.kind.clone(),
+            state: status.state,
+            progress: status.progress.clone(),
+            started: self.started,
+            finished: status.finished,
+            error: status.error.clone(),
+            output: status.output.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Job {
+    /// A job that is not tracked by any manager, for handler tests.
+    pub fn for_tests() -> Self {
+        Self::new(0, "test")
+    }
+}
+
+#[derive(Clone)]
+pub struct JobManager {
+    jobs: Arc<Mutex<HashMap<String, Arc<Job>>>>,
+    next_seq: Arc<AtomicU64>,
+    max_running: usize,
+}
+
+impl JobManager {
+    pub fn new(max_running: usize) -> Self {
+        Self {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            next_seq: Arc::new(AtomicU64::new(1)),
+            max_running,
+        }
+    }
+
+    /// Starts `work` on the blocking thread pool and returns the job id.
+    pub fn spawn<F>(&self, kind: &str, work: F) -> Result<String>
+    where
+        F: FnOnce(&Job) -> Result<JobOutput> + Send + 'static,
+    {
+        let mut jobs = self.jobs.lock().unwrap();
+
+        let running = jobs.values().filter(|j| j.is_running()).count();
+        if running >= self.max_running {
+            return Err(AgentError::InvalidRequest(format!(
+                "Too many running jobs ({}), try again later",
+                running
+            )));
+        }
+        Self::prune(&mut jobs);
+
+        let job = Arc::new(Job::new(self.next_seq.fetch_add(1, Ordering::Relaxed), kind));
+        let id = job.id.clone();
+        jobs.insert(id.clone(), job.clone());
+
+        info!("Starting job {} ({})", id, kind);
+        tokio::task::spawn_blocking(move || {
+            let result = work(&job);
+            job.finish(result);
+        });
+
+        Ok(id)
+    }
+
+    pub fn status(&self, id: &str) -> Result<JobInfo> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|job| job.info())
+            .ok_or_else(|| AgentError::NotFound(format!("Unknown job: {}", id)))
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut sorted: Vec<&Arc<Job>> = jobs.values().collect();
+        sorted.sort_by_key(|j| j.seq);
+        sorted.into_iter().map(|j| j.info()).collect()
+    }
+
+    pub fn cancel(&self, id: &str) -> Result<()> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get(id)
+            .ok_or_else(|| AgentError::NotFound(format!("Unknown job: {}", id)))?;
+
+        info!("Cancelling job {}", id);
+        job.cancelled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Drops the oldest finished jobs once more than `MAX_FINISHED_JOBS`
+    /// have accumulated.
+    fn prune(jobs: &mut HashMap<String, Arc<Job>>) {
+        let mut finished: Vec<(u64, String)> = jobs
+            .values()
+            .filter(|j| !j.is_running())
+            .map(|j| (j.seq, j.id.clone()))
+            .collect();
+
+        if finished.len() <= MAX_FINISHED_JOBS {
+            return;
+        }
+
+        finished.sort();
+        let excess = finished.len() - MAX_FINISHED_JOBS;
+        for (_, id) in finished.into_iter().take(excess) {
+            jobs.remove(&id);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::error::{AgentError, Result};
+use crate::protocol::LockInfo;
+use log::info;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Longest lease a client can ask for; longer edits renew the lock.
+pub const MAX_LEASE_SECS: u64 = 3600;
+
+/// Advisory leases on files, held by client connections. They only bind
+/// operations made through the agent, which refuses to modify a path
+/// another connection holds a lease on.
+#[derive(Clone, Default)]
+pub struct LockManager {
+    leases: Arc<Mutex<HashMap<PathBuf, Lease>>>,
+}
+
+struct Lease {
+    owner: String,
+    session: u64,
+    acquired: i64,
+    expires: i64,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a lease on `path`, or renews it when `session` already
+    /// holds it.
+    pub fn acquire(
+        &self,
+        path: &Path,
+        owner: &str,
+        session: u64,
+        duration: u64,
+    ) -> Result<LockInfo> {
+        let now = chrono::Utc::now().timestamp();
+        let expires = now + duration.clamp(1, MAX_LEASE_SECS) as i64;
+
+        let mut leases = self.leases.lock().unwrap();
+        leases.retain(|_, lease| lease.expires > now);
+
+        let lease = match leases.get_mut(path) {
+            Some(lease) if lease.session != session => {
+                return Err(AgentError::Locked(format!(
+                    "{} is locked by {}",
+                    path.display(),
+                    lease.owner
+                )));
+            }
+            Some(lease) => {
+                lease.owner = owner.to_string();
+                lease.expires = expires;
+                lease
+            }
+            None => {
+                info!("Lock on {:?} taken by {}", path, owner);
+                leases.entry(path.to_path_buf()).or_insert(Lease {
+                    owner: owner.to_string(),
+                    session,
+                    acquired: now,
+                    expires,
+                })
+            }
+        };
+
+        Ok(lease.info(path, session))
+    }
+
+    pub fn release(&self, path: &Path, session: u64) -> Result<()> {
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get(path) {
+            Some(lease) if lease.session == session => {
+                leases.remove(path);
+                Ok(())
+            }
+            Some(lease) => Err(AgentError::Locked(format!(
+                "{} is locked by {}",
+                path.display(),
+                lease.owner
+            ))),
+            None => Err(AgentError::InvalidRequest(format!(
+                "{} is not locked",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Drops every lease held by a connection that went away.
+    pub fn release_session(&self, session: u64) {
+        let mut leases = self.leases.lock().unwrap();
+        let before = leases.len();
+        leases.retain(|_, lease| lease.session != session);
+        if leases.len() != before {
+            info!(
+                "Released {} locks of closed connection",
+                before - leases.len()
+            );
+        }
+    }
+
+    /// Fails if another connection holds a live lease on `path` or, when
+    /// `path` is a directory, on anything below it.
+    pub fn check(&self, path: &Path, session: Option<u64>) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let leases = self.leases.lock().unwrap();
+
+        let conflict = leases.iter().find(|(locked, lease)| {
+            lease.expires > now && Some(lease.session) != session && locked.starts_with(path)
+        });
+        match conflict {
+            Some((locked, lease)) => Err(AgentError::Locked(format!(
+                "{} is locked by {}",
+                locked.display(),
+                lease.owner
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn list(&self, session: Option<u64>) -> Vec<LockInfo> {
+        let now = chrono::Utc::now().timestamp();
+        let leases = self.leases.lock().unwrap();
+
+        let mut locks: Vec<LockInfo> = leases
+            .iter()
+            .filter(|(_, lease)| lease.expires > now)
+            .map(|(path, lease)| lease.info(path, session.unwrap_or(u64::MAX)))
+            .collect();
+        locks.sort_by(|a, b| a.path.cmp(&b.path));
+        locks
+    }
+}
+
+impl Lease {
+    fn info(&self, path: &Path, session: u64) -> LockInfo {
+        LockInfo {
+            path: path.to_string_lossy().to_string(),
+            owner: self.owner.clone(),
+            acquired: self.acquired,
+            expires: self.expires,
+            mine: self.session == session,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_conflicts() {
+        let locks = LockManager::new();
+        let file = Path::new("/home/pi/notes.txt");
+
+        locks.acquire(file, "tab 1", 1, 60).unwrap();
+        assert!(locks.acquire(file, "tab 2", 2, 60).is_err());
+        assert!(locks.acquire(file, "tab 1", 1, 120).is_ok());
+
+        assert!(locks.check(file, Some(1)).is_ok());
+        assert!(locks.check(file, Some(2)).is_err());
+        // Deleting the parent directory would remove the locked file
+        assert!(locks.check(Path::new("/home/pi"), Some(2)).is_err());
+        assert!(locks
+            .check(Path::new("/home/pi/other.txt"), Some(2))
+            .is_ok());
+
+        assert!(locks.release(file, 2).is_err());
+        locks.release_session(1);
+        assert!(locks.check(file, Some(2)).is_ok());
+        assert!(locks.list(None).is_empty());
+    }
+}
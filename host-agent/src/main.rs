@@ -1,9 +1,17 @@
+mod alerts;
 mod config;
 mod error;
 mod server;
 mod handlers;
+mod jobs;
+mod locks;
+mod metrics;
+mod persist;
 mod security;
 mod protocol;
+mod session;
+#[cfg(test)]
+mod testing;
 
 use anyhow::Result;
 use log::{info, error};
@@ -0,0 +1,429 @@
+use crate::alerts::AlertManager;
+use crate::config::MetricsConfig;
+use crate::error::{AgentError, Result};
+use crate::handlers::{hardware, mounts};
+use crate::persist;
+use crate::protocol::{Metric, MetricPoint, MetricsHistory};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use sysinfo::{Networks, System};
+use tokio::time::MissedTickBehavior;
+
+const METRIC_COUNT: usize = 6;
+
+type Values = [Option<f64>; METRIC_COUNT];
+
+/// In-memory history of system metrics. Every tier is a ring buffer at
+/// its own resolution; samples are averaged into each tier as they come
+/// in, so coarse tiers cover long ranges at a fixed memory cost.
+#[derive(Clone)]
+pub struct MetricsStore {
+    tiers: Arc<Mutex<Vec<Tier>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Tier {
+    resolution: i64,
+    #[serde(skip)]
+    capacity: usize,
+    points: VecDeque<Point>,
+    /// Samples of the period that is still in progress
+    #[serde(skip)]
+    pending: Option<Bucket>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Point {
+    timestamp: i64,
+    values: Values,
+}
+
+struct Bucket {
+    start: i64,
+    sums: [f64; METRIC_COUNT],
+    counts: [u32; METRIC_COUNT],
+}
+
+/// Keeps the state needed for rates between samples.
+struct Sampler {
+    system: System,
+    networks: Networks,
+    sysfs_root: PathBuf,
+    last: Instant,
+}
+
+impl MetricsStore {
+    pub fn new(config: &MetricsConfig) -> Self {
+        let mut tiers: Vec<Tier> = config
+            .tiers
+            .iter()
+            .filter(|tier| tier.resolution_secs > 0)
+            .map(|tier| Tier {
+                resolution: tier.resolution_secs as i64,
+                capacity: (tier.retention_secs / tier.resolution_secs).max(1) as usize,
+                points: VecDeque::new(),
+                pending: None,
+            })
+            .collect();
+        tiers.sort_by_key(|tier| tier.resolution);
+
+        Self {
+            tiers: Arc::new(Mutex::new(tiers)),
+        }
+    }
+
+    fn record(&self, timestamp: i64, values: Values) {
+        let mut tiers = self.tiers.lock().unwrap();
+        for tier in tiers.iter_mut() {
+            let start = timestamp - timestamp.rem_euclid(tier.resolution);
+            if tier.pending.as_ref().is_some_and(|b| b.start != start) {
+                let point = tier.pending.take().unwrap().average();
+                tier.push(point);
+            }
+            tier.pending
+                .get_or_insert_with(|| Bucket::new(start))
+                .add(&values);
+        }
+    }
+
+    /// Returns `range` seconds of history for one metric. The finest tier
+    /// covering the range is used and, if `resolution` is coarser than
+    /// that tier, its points are averaged further.
+    pub fn history(
+        &self,
+        metric: Metric,
+        range: u64,
+        resolution: Option<u64>,
+    ) -> Result<MetricsHistory> {
+        if range == 0 {
+            return Err(AgentError::InvalidRequest(
+                "Range must be positive".to_string(),
+            ));
+        }
+        let range = i64::try_from(range)
+            .map_err(|_| AgentError::InvalidRequest(format!("Range too large: {}", range)))?;
+        let requested = i64::try_from(resolution.unwrap_or(0)).map_err(|_| {
+            AgentError::InvalidRequest(format!("Resolution too large: {:?}", resolution))
+        })?;
+
+        let tiers = self.tiers.lock().unwrap();
+        let covering: Vec<&Tier> = tiers.iter().filter(|t| t.retention() >= range).collect();
+        let tier = covering
+            .iter()
+            .rev()
+            .find(|t| t.resolution <= requested)
+            .or(covering.first())
+            .copied()
+            .or_else(|| tiers.iter().max_by_key(|t| t.retention()))
+            .ok_or_else(|| AgentError::InvalidRequest("No metrics tiers configured".to_string()))?;
+        let resolution = requested.max(tier.resolution);
+
+        let since = chrono::Utc::now().timestamp().saturating_sub(range);
+        let i = index(metric);
+
+        // Average what falls into each period of the requested resolution
+        let mut buckets: BTreeMap<i64, (f64, u32)> = BTreeMap::new();
+        let pending = tier.pending.as_ref().map(|bucket| bucket.average());
+        for point in tier.points.iter().chain(pending.as_ref()) {
+            let Some(value) = point.values[i] else {
+                continue;
+            };
+            if point.timestamp < since {
+                continue;
+            }
+            let bucket = buckets
+                .entry(point.timestamp - point.timestamp.rem_euclid(resolution))
+                .or_default();
+            bucket.0 += value;
+            bucket.1 += 1;
+        }
+
+        Ok(MetricsHistory {
+            metric,
+            resolution: resolution as u64,
+            points: buckets
+                .into_iter()
+                .map(|(timestamp, (sum, count))| MetricPoint {
+                    timestamp,
+                    value: sum / count as f64,
+                })
+                .collect(),
+        })
+    }
+
+    /// Restores history saved by `save`, dropping whatever has aged out
+    /// of the configured tiers in the meantime.
+    pub fn load(&self, path: &Path) -> Result<()> {
+        let saved: Vec<Tier> = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| AgentError::Internal(format!("Corrupt metrics file: {}", e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let mut tiers = self.tiers.lock().unwrap();
+        for tier in tiers.iter_mut() {
+            let Some(saved) = saved.iter().find(|s| s.resolution == tier.resolution) else {
+                continue;
+            };
+            let since = now - tier.retention();
+            for point in saved.points.iter().filter(|p| p.timestamp >= since) {
+                tier.push(point.clone());
+            }
+        }
+
+        info!("Loaded metrics history from {:?}", path);
+        Ok(())
+    }
+
+    /// Writes the history via a temporary file, so a crash mid-write
+    /// leaves the previous copy intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = {
+            let tiers = self.tiers.lock().unwrap();
+            serde_json::to_vec(&*tiers).map_err(|e| AgentError::Internal(e.to_string()))?
+        };
+        persist::write_atomic(path, &json)?;
+
+        debug!("Saved metrics history to {:?}", path);
+        Ok(())
+    }
+}
+
+impl Tier {
+    fn retention(&self) -> i64 {
+        self.resolution * self.capacity as i64
+    }
+
+    fn push(&mut self, point: Point) {
+        if self.points.len() == self.capacity {
+            self.points.pop_front();
+        }
+        self.points.push_back(point);
+    }
+}
+
+impl Bucket {
+    fn new(start: i64) -> Self {
+        Self {
+            start,
+            sums: [0.0; METRIC_COUNT],
+            counts: [0; METRIC_COUNT],
+        }
+    }
+
+    fn add(&mut self, values: &Values) {
+        for (i, value) in values.iter().enumerate() {
+            if let Some(value) = value {
+                self.sums[i] += value;
+                self.counts[i] += 1;
+            }
+        }
+    }
+
+    fn average(&self) -> Point {
+        let mut values = [None; METRIC_COUNT];
+        for (i, value) in values.iter_mut().enumerate() {
+            if self.counts[i] > 0 {
+                *value = Some(self.sums[i] / self.counts[i] as f64);
+            }
+        }
+        Point {
+            timestamp: self.start,
+            values,
+        }
+    }
+}
+
+impl Sampler {
+    fn new(sysfs_root: PathBuf) -> Self {
+        let mut system = System::new();
+        system.refresh_cpu();
+        Self {
+            system,
+            networks: Networks::new_with_refreshed_list(),
+            sysfs_root,
+            last: Instant::now(),
+        }
+    }
+
+    fn sample(&mut self) -> Values {
+        let secs = self.last.elapsed().as_secs_f64();
+        self.last = Instant::now();
+        let mut values = [None; METRIC_COUNT];
+
+        self.system.refresh_cpu();
+        values[index(Metric::Cpu)] = Some(self.system.global_cpu_info().cpu_usage() as f64);
+
+        self.system.refresh_memory();
+        let total = self.system.total_memory();
+        if total > 0 {
+            values[index(Metric::Memory)] =
+                Some(self.system.used_memory() as f64 / total as f64 * 100.0);
+        }
+
+        values[index(Metric::Disk)] = mounts::usage(Path::new("/")).and_then(|usage| {
+            let usable = usage.used + usage.free;
+            (usable > 0).then(|| usage.used as f64 / usable as f64 * 100.0)
+        });
+
+        // Picks up interfaces that appeared since the last sample
+        self.networks.refresh_list();
+        if secs > 0.0 {
+            let (rx, tx) = self
+                .networks
+                .iter()
+                .filter(|(name, _)| name.as_str() != "lo")
+                .fold((0, 0), |(rx, tx), (_, data)| {
+                    (rx + data.received(), tx + data.transmitted())
+                });
+            values[index(Metric::NetworkRx)] = Some(rx as f64 / secs);
+            values[index(Metric::NetworkTx)] = Some(tx as f64 / secs);
+        }
+
+        values[index(Metric::Temperature)] = hardware::temperatures(&self.sysfs_root)
+            .ok()
+            .and_then(|temps| temps.into_iter().map(|t| t.celsius).reduce(f64::max));
+
+        values
+    }
+}
+
+fn index(metric: Metric) -> usize {
+    match metric {
+        Metric::Cpu => 0,
+        Metric::Memory => 1,
+        Metric::Disk => 2,
+        Metric::NetworkRx => 3,
+        Metric::NetworkTx => 4,
+        Metric::Temperature => 5,
+    }
+}
+
+/// Samples metrics into `store` and checks them against the alert rules
+/// until the agent exits, saving the history periodically when
+/// `persist_path` is set.
+pub async fn run(
+    store: MetricsStore,
+    alerts: AlertManager,
+    config: MetricsConfig,
+    sysfs_root: PathBuf,
+) {
+    let persist_path = config.persist_path.as_ref().map(PathBuf::from);
+    if let Some(path) = &persist_path {
+        if let Err(e) = store.load(path) {
+            warn!("Failed to load metrics history: {}", e);
+        }
+    }
+
+    let mut sampler = Sampler::new(sysfs_root);
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.sample_interval_secs.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick fires immediately, before there is anything to compare
+    ticker.tick().await;
+
+    let persist_interval = Duration::from_secs(config.persist_interval_secs.max(1));
+    let mut last_save = Instant::now();
+
+    loop {
+        ticker.tick().await;
+        // Reading sysfs and statvfs can block, so sample on the blocking
+        // pool and take the sampler back afterwards
+        let (returned, values) = match tokio::task::spawn_blocking(move || {
+            let values = sampler.sample();
+            (sampler, values)
+        })
+        .await
+        {
+            Ok(sampled) => sampled,
+            Err(e) => {
+                error!("Metrics sampler failed, no longer sampling: {}", e);
+                return;
+            }
+        };
+        sampler = returned;
+
+        let now = chrono::Utc::now().timestamp();
+        store.record(now, values);
+        alerts.evaluate(now, |metric| values[index(metric)]);
+
+        if let Some(path) = &persist_path {
+            if last_save.elapsed() >= persist_interval {
+                last_save = Instant::now();
+                let (store, path) = (store.clone(), path.clone());
+                let saved = tokio::task::spawn_blocking(move || store.save(&path)).await;
+                match saved {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Failed to save metrics history: {}", e),
+                    Err(e) => warn!("Failed to save metrics history: {}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MetricsTier;
+
+    #[test]
+    fn test_downsampling() {
+        let store = MetricsStore::new(&MetricsConfig {
+            enabled: true,
+            sample_interval_secs: 1,
+            tiers: vec![
+                MetricsTier {
+                    resolution_secs: 1,
+                    retention_secs: 600,
+                },
+                MetricsTier {
+                    resolution_secs: 60,
+                    retention_secs: 3600,
+                },
+            ],
+            persist_path: None,
+            persist_interval_secs: 60,
+        });
+
+        // Five minutes of one sample per second, CPU counting up
+        let now = chrono::Utc::now().timestamp();
+        let start = now - now.rem_euclid(60) - 300;
+        for t in 0..300 {
+            let mut values = [None; METRIC_COUNT];
+            values[index(Metric::Cpu)] = Some(t as f64);
+            store.record(start + t, values);
+        }
+
+        // Short ranges come from the fine tier
+        let recent = store.history(Metric::Cpu, 120, None).unwrap();
+        assert_eq!(recent.resolution, 1);
+        assert_eq!(recent.points.last().unwrap().value, 299.0);
+
+        let hour = store.history(Metric::Cpu, 3600, None).unwrap();
+        assert_eq!(hour.resolution, 60);
+        assert_eq!(hour.points.len(), 5);
+        assert_eq!(hour.points[0].value, 29.5);
+        assert_eq!(hour.points[4].value, 269.5);
+
+        let coarse = store.history(Metric::Cpu, 3600, Some(120)).unwrap();
+        assert_eq!(coarse.resolution, 120);
+        assert!(store
+            .history(Metric::Memory, 60, None)
+            .unwrap()
+            .points
+            .is_empty());
+
+        // Ranges beyond what fits the clock are refused, not wrapped
+        let refused = store.history(Metric::Cpu, u64::MAX, None).unwrap_err();
+        assert_eq!(refused.code(), 400);
+        assert!(store.history(Metric::Cpu, 60, Some(u64::MAX)).is_err());
+        assert_eq!(store.history(Metric::Cpu, i64::MAX as u64, None).unwrap().resolution, 60);
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Request {
@@ -10,29 +11,244 @@ pub struct Request {
 #[serde(tag = "type", content = "params")]
 pub enum Action {
     // File operations
-    ListFiles { path: String },
+    ListFiles {
+        path: String,
+        #[serde(default)]
+        extended: bool,
+        #[serde(flatten)]
+        options: ListOptions,
+    },
+    ListTree {
+        path: String,
+        #[serde(default = "default_tree_depth")]
+        depth: usize,
+        #[serde(default)]
+        dirs_only: bool,
+        max_entries: Option<usize>,
+    },
+    Stat { path: String },
     ReadFile { path: String },
-    WriteFile { path: String, content: String },
+    HexDump {
+        path: String,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default = "default_hex_length")]
+        length: usize,
+    },
+    TailFile {
+        path: String,
+        #[serde(default = "default_tail_lines")]
+        lines: usize,
+        #[serde(default)]
+        follow: bool,
+    },
+    WriteFile {
+        path: String,
+        content: String,
+        encoding: Option<String>,
+        line_ending: Option<LineEnding>,
+        #[serde(default)]
+        bom: bool,
+    },
     CreateDir { path: String },
     DeleteFile { path: String },
-    CopyFile { from: String, to: String },
-    MoveFile { from: String, to: String },
+    /// `preserve_xattrs` also copies `user.` attributes and ACLs.
+    CopyFile {
+        from: String,
+        to: String,
+        #[serde(default)]
+        preserve_xattrs: bool,
+    },
+    MoveFile {
+        from: String,
+        to: String,
+        #[serde(default)]
+        preserve_xattrs: bool,
+    },
+    CreateSymlink { target: String, link: String },
+    CreateHardlink { target: String, link: String },
+    ReadLink { path: String },
+    CreateArchive {
+        paths: Vec<String>,
+        dest: String,
+        format: ArchiveFormat,
+    },
+    ExtractArchive { archive: String, dest: String },
+    ListArchive {
+        path: String,
+        inner_path: Option<String>,
+    },
+    ReadArchiveEntry { path: String, entry: String },
+    ListVersions { path: String },
+    RestoreVersion { path: String, version: String },
+    /// Diffs a version against the current file, or another version.
+    DiffVersion {
+        path: String,
+        version: String,
+        #[serde(default)]
+        against: Option<String>,
+    },
+    DiffFiles { a: String, b: String },
+    DiffDirectories {
+        a: String,
+        b: String,
+        #[serde(default)]
+        compare: CompareMode,
+    },
+    SyncDirectories {
+        source: String,
+        dest: String,
+        #[serde(default)]
+        options: SyncOptions,
+    },
+    FindDuplicates {
+        roots: Vec<String>,
+        #[serde(default = "default_min_size")]
+        min_size: u64,
+    },
+    Checksum {
+        path: String,
+        #[serde(default)]
+        algorithms: Vec<HashAlgorithm>,
+    },
+    VerifyChecksums { path: String },
+    Thumbnail {
+        path: String,
+        size: u32,
+        #[serde(default)]
+        format: ThumbnailFormat,
+    },
+    SetPermissions {
+        path: String,
+        mode: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    SetOwner {
+        path: String,
+        user: Option<String>,
+        group: Option<String>,
+        #[serde(default)]
+        recursive: bool,
+    },
+    ListXattrs { path: String },
+    GetXattr { path: String, name: String },
+    SetXattr {
+        path: String,
+        name: String,
+        value: String,
+        #[serde(default)]
+        base64: bool,
+    },
+    RemoveXattr { path: String, name: String },
+    GetAcl { path: String },
+    SetAcl {
+        path: String,
+        access: Option<Vec<AclEntry>>,
+        default: Option<Vec<AclEntry>>,
+    },
+    SetTimes {
+        path: String,
+        accessed: Option<i64>,
+        modified: Option<i64>,
+    },
+
+    QuotaStatus,
+    /// `all` also lists pseudo filesystems such as proc and cgroups.
+    ListMounts {
+        #[serde(default)]
+        all: bool,
+    },
+    ListBlockDevices,
+
+    /// Takes or renews an advisory lease on a file.
+    LockFile {
+        path: String,
+        owner: String,
+        #[serde(default = "default_lease_secs")]
+        duration_secs: u64,
+    },
+    UnlockFile { path: String },
+    ListLocks,
+
+    /// Stops the events started by the request with this id.
+    Unsubscribe { id: String },
+
+    JobStatus { job_id: String },
+    ListJobs,
+    CancelJob { job_id: String },
 
     SystemInfo,
+    HardwareInfo,
+    NetworkInfo,
+    /// `range` seconds of samples, averaged to `resolution` seconds if
+    /// given and coarser than the stored history.
+    MetricsHistory {
+        metric: Metric,
+        range: u64,
+        #[serde(default)]
+        resolution: Option<u64>,
+    },
+    /// Pushes `Alert` events whenever an alert fires or resolves.
+    SubscribeAlerts,
+    ListAlerts,
+    AcknowledgeAlert { id: String },
     ListProcesses,
     KillProcess { pid: u32 },
 
     Ping,
 }
 
+fn default_tree_depth() -> usize {
+    2
+}
+
+fn default_min_size() -> u64 {
+    1
+}
+
+fn default_tail_lines() -> usize {
+    10
+}
+
+fn default_hex_length() -> usize {
+    4096
+}
+
+fn default_lease_secs() -> u64 {
+    300
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Response {
     pub id: String,
     pub result: ResponseResult,
 }
 
+/// Pushed to the client outside the request/response flow. `id` is the
+/// id of the request that subscribed to the events.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EventMessage {
+    pub id: String,
+    pub event: Event,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Event {
+    TailLines {
+        lines: Vec<String>,
+        truncated: bool,
+        rotated: bool,
+    },
+    Alert(Alert),
+    /// The subscription failed and no further events will follow.
+    Error { message: String },
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum ResponseResult {
     Success(ResponseData),
     Error { error: String, code: u32 },
@@ -41,32 +257,715 @@ pub enum ResponseResult {
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type", content = "data")]
 pub enum ResponseData {
-    Files { files: Vec<FileInfo> },
-    FileContent { content: String, size: u64 },
+    Files {
+        files: Vec<FileInfo>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        total: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_cursor: Option<String>,
+    },
+    Stat(FileInfo),
+    Tree(TreeNode),
+    Thumbnail(ThumbnailData),
+    Diff(FileDiff),
+    Link(LinkInfo),
+    Tail {
+        lines: Vec<String>,
+        following: bool,
+    },
+    HexDump(HexDump),
+    Quotas { quotas: Vec<QuotaUsage> },
+    Mounts { mounts: Vec<MountInfo> },
+    BlockDevices { devices: Vec<BlockDevice> },
+    Versions { versions: Vec<FileVersion> },
+    Xattrs { xattrs: Vec<Xattr> },
+    Xattr(Xattr),
+    Acl(Acl),
+    Lock(LockInfo),
+    Locks { locks: Vec<LockInfo> },
+    FileContent {
+        content: String,
+        size: u64,
+        #[serde(default, flatten, skip_serializing_if = "Option::is_none")]
+        format: Option<TextFormat>,
+    },
     Success { message: String },
+    JobStarted { job_id: String },
+    Job(JobInfo),
+    Jobs { jobs: Vec<JobInfo> },
     SystemInfo(SystemInfo),
+    HardwareInfo(HardwareInfo),
+    NetworkInfo(NetworkInfo),
+    MetricsHistory(MetricsHistory),
+    Alerts { alerts: Vec<Alert> },
+    Alert(Alert),
     Processes { processes: Vec<ProcessInfo> },
     Pong,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ListOptions {
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub sort_by: SortKey,
+    #[serde(default)]
+    pub sort_order: SortOrder,
+    #[serde(default = "default_show_hidden")]
+    pub show_hidden: bool,
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+fn default_show_hidden() -> bool {
+    true
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: None,
+            sort_by: SortKey::default(),
+            sort_order: SortOrder::default(),
+            show_hidden: default_show_hidden(),
+            filter: None,
+            cursor: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Natural,
+    Size,
+    Modified,
+    Type,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct FileInfo {
     pub name: String,
     pub path: String,
     pub is_dir: bool,
+    #[serde(default)]
+    pub is_symlink: bool,
     pub size: u64,
     pub modified: i64,
     pub permissions: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extended: Option<Box<ExtendedInfo>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExtendedInfo {
+    pub file_type: FileType,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub uid: u32,
+    pub gid: u32,
+    pub inode: u64,
+    pub nlink: u64,
+    pub accessed: i64,
+    pub created: Option<i64>,
+    pub symlink_target: Option<String>,
+    pub broken_link: bool,
+    pub mime_type: Option<String>,
+    pub hidden: bool,
+}
+
+/// Usage against one configured quota; either `path` or `uid` is set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct QuotaUsage {
+    pub path: Option<String>,
+    pub uid: Option<u32>,
+    pub used: u64,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+    pub soft_exceeded: bool,
+    pub hard_exceeded: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MountInfo {
+    pub device: String,
+    pub mount_point: String,
+    pub fs_type: String,
+    pub options: Vec<String>,
+    pub read_only: bool,
+    pub total: u64,
+    pub used: u64,
+    pub free: u64,
+    /// Whether the mount point lies within `allowed_paths`.
+    pub allowed: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BlockDevice {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub removable: bool,
+    pub read_only: bool,
+    pub rotational: bool,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub mount_points: Vec<String>,
+    pub partitions: Vec<Partition>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Partition {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub mount_points: Vec<String>,
+}
+
+/// An extended attribute. Values that are not UTF-8 are base64 encoded.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Xattr {
+    pub name: String,
+    pub value: String,
+    pub base64: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Acl {
+    pub access: Vec<AclEntry>,
+    pub default: Vec<AclEntry>,
+}
+
+/// One ACL entry. Named `user` and `group` entries carry a uid/gid, a
+/// name, or both; `perms` is written like `rwx` or `r-x`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub perms: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclTag {
+    UserObj,
+    User,
+    GroupObj,
+    Group,
+    Mask,
+    Other,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileVersion {
+    pub id: String,
+    pub timestamp: i64,
+    pub size: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LockInfo {
+    pub path: String,
+    pub owner: String,
+    pub acquired: i64,
+    pub expires: i64,
+    /// Whether the lock is held by the requesting connection.
+    pub mine: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HexDump {
+    pub offset: u64,
+    /// Size of the whole file, for scrolling.
+    pub size: u64,
+    pub rows: Vec<HexRow>,
+    /// The same range, base64 encoded.
+    pub data: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HexRow {
+    pub offset: u64,
+    pub hex: String,
+    pub ascii: String,
+}
+
+/// How a text file was stored on disk, so it can be written back the
+/// same way.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TextFormat {
+    pub encoding: String,
+    pub line_ending: Option<LineEnding>,
+    pub bom: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Cr,
+    Mixed,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TreeNode {
+    #[serde(flatten)]
+    pub info: FileInfo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub children: Option<Vec<TreeNode>>,
+    /// Set when this directory has entries that are not included, either
+    /// because the depth or the entry limit was reached or because it
+    /// could not be read.
+    pub truncated: bool,
+    /// Why the directory could not be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LinkInfo {
+    pub path: String,
+    pub target: String,
+    pub resolved: Option<String>,
+    pub broken: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Socket,
+    Fifo,
+    BlockDevice,
+    CharDevice,
+    Unknown,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbnailFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ThumbnailData {
+    pub mime_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub data: String,
+    pub cached: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: String,
+    pub state: JobState,
+    pub progress: JobProgress,
+    pub started: i64,
+    pub finished: Option<i64>,
+    pub error: Option<String>,
+    pub output: Option<JobOutput>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct JobProgress {
+    pub processed_entries: u64,
+    pub total_entries: Option<u64>,
+    pub processed_bytes: u64,
+    pub total_bytes: Option<u64>,
+    pub current: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", content = "data")]
+pub enum JobOutput {
+    Archive { path: String, entries: u64, bytes: u64 },
+    Extract { dest: String, entries: u64, bytes: u64 },
+    Checksums {
+        path: String,
+        size: u64,
+        checksums: BTreeMap<HashAlgorithm, String>,
+    },
+    Verification {
+        ok: u64,
+        failed: u64,
+        missing: u64,
+        results: Vec<ChecksumResult>,
+    },
+    DirectoryDiff {
+        added: u64,
+        removed: u64,
+        modified: u64,
+        entries: Vec<DiffEntry>,
+    },
+    Sync {
+        dry_run: bool,
+        copied: u64,
+        created_dirs: u64,
+        deleted: u64,
+        bytes: u64,
+        actions: Vec<SyncAction>,
+        truncated: bool,
+    },
+    Duplicates {
+        scanned: u64,
+        wasted: u64,
+        groups: Vec<DuplicateGroup>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FileDiff {
+    pub a: String,
+    pub b: String,
+    pub identical: bool,
+    pub unified: String,
+    pub hunks: Vec<DiffHunk>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiffHunk {
+    pub old_start: usize,
+    pub old_lines: usize,
+    pub new_start: usize,
+    pub new_lines: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiffLine {
+    pub kind: LineChange,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LineChange {
+    Context,
+    Insert,
+    Delete,
+}
+
+/// How `DiffDirectories` decides that two files differ. Files of
+/// different size always do.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareMode {
+    Size,
+    #[default]
+    Mtime,
+    Hash,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DiffEntry {
+    pub path: String,
+    pub status: DiffStatus,
+    pub is_dir: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffStatus {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SyncOptions {
+    /// `mtime` compares size and modification time, `hash` file content.
+    #[serde(default)]
+    pub compare: CompareMode,
+    /// Remove destination entries that no longer exist in the source.
+    #[serde(default)]
+    pub delete: bool,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SyncAction {
+    pub path: String,
+    pub op: SyncOp,
+    pub size: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncOp {
+    Copy,
+    CreateDir,
+    Delete,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DuplicateGroup {
+    pub size: u64,
+    /// Space freed by keeping a single copy.
+    pub wasted: u64,
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+    Crc32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ChecksumResult {
+    pub path: String,
+    pub status: VerifyStatus,
+    pub expected: String,
+    pub actual: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStatus {
+    Ok,
+    Mismatch,
+    Missing,
+    Error,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SystemInfo {
     pub cpu: f64,
     pub memory: f64,
+    /// Usage of the root filesystem in percent; see `filesystems` for
+    /// the other drives.
     pub disk: f64,
     pub uptime: u64,
     pub hostname: String,
     pub processes: usize,
+    pub filesystems: Vec<FilesystemUsage>,
+    pub disks: Vec<DiskIo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NetworkInfo {
+    pub interfaces: Vec<NetworkInterface>,
+    pub routes: Vec<DefaultRoute>,
+    pub dns: DnsConfig,
+}
+
+/// A network interface. Counters are totals since boot, rates are bytes
+/// per second.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub mac: Option<String>,
+    /// Operational state as reported by the kernel, e.g. `up` or `down`
+    pub state: String,
+    pub mtu: Option<u32>,
+    pub addresses: Vec<InterfaceAddress>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_rate: f64,
+    pub tx_rate: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct InterfaceAddress {
+    pub family: IpFamily,
+    pub address: String,
+    pub prefix: u8,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DefaultRoute {
+    pub family: IpFamily,
+    pub interface: String,
+    pub gateway: String,
+    pub metric: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct DnsConfig {
+    pub nameservers: Vec<String>,
+    pub search: Vec<String>,
+}
+
+/// Board telemetry. Fields are absent where the kernel does not expose
+/// them, e.g. throttling outside a Raspberry Pi.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HardwareInfo {
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub temperatures: Vec<Temperature>,
+    pub cpus: Vec<CpuFrequency>,
+    pub under_voltage: Option<bool>,
+    pub throttling: Option<Throttling>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Temperature {
+    pub zone: String,
+    pub kind: Option<String>,
+    pub celsius: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CpuFrequency {
+    pub cpu: u32,
+    pub current_mhz: Option<u32>,
+    pub min_mhz: Option<u32>,
+    pub max_mhz: Option<u32>,
+    pub governor: Option<String>,
+}
+
+/// Firmware throttling state. The `_occurred` flags stick until reboot.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Throttling {
+    pub raw: u32,
+    pub under_voltage: bool,
+    pub frequency_capped: bool,
+    pub throttled: bool,
+    pub soft_temp_limit: bool,
+    pub under_voltage_occurred: bool,
+    pub frequency_capped_occurred: bool,
+    pub throttled_occurred: bool,
+    pub soft_temp_limit_occurred: bool,
+}
+
+/// Metrics sampled in the background. Usage is in percent, network
+/// rates in bytes per second summed over all interfaces except loopback,
+/// temperature in degrees Celsius of the hottest thermal zone.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Cpu,
+    Memory,
+    Disk,
+    NetworkRx,
+    NetworkTx,
+    Temperature,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MetricsHistory {
+    pub metric: Metric,
+    pub resolution: u64,
+    pub points: Vec<MetricPoint>,
+}
+
+/// Average of a metric over the period starting at `timestamp`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MetricPoint {
+    pub timestamp: i64,
+    pub value: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+/// A firing of an alert rule. `value` is the sample that triggered it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Alert {
+    pub id: String,
+    pub rule: String,
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    pub value: f64,
+    pub state: AlertState,
+    pub triggered_at: i64,
+    pub resolved_at: Option<i64>,
+    pub acknowledged: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FilesystemUsage {
+    pub device: String,
+    pub mount_point: String,
+    pub fs_type: String,
+    pub total: u64,
+    pub used: u64,
+    pub free: u64,
+    pub percent: f64,
+}
+
+/// IO of one disk: byte counters since boot and rates in bytes per
+/// second. `busy` is the share of time the disk was doing IO.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiskIo {
+    pub name: String,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_rate: f64,
+    pub write_rate: f64,
+    pub busy: f64,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -1,3 +1,4 @@
+pub mod quota;
 pub mod validator;
 //pub mod whitelist;
 
@@ -0,0 +1,425 @@
+use crate::config::SecurityConfig;
+use crate::error::{AgentError, Result};
+use crate::protocol::QuotaUsage;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long a measured usage is trusted before the tree is walked again.
+/// Writes made through the agent are added in between.
+const USAGE_TTL: Duration = Duration::from_secs(300);
+
+/// Disk usage accounting for the quotas in `SecurityConfig`. Path quotas
+/// count every file below the quota path. Files written through the
+/// agent all belong to the agent's user, so user quotas cannot be
+/// measured on disk; they count what each UID wrote through the agent
+/// since it started.
+///
+/// Trees are only ever walked on a background thread. Until the first
+/// walk of a path quota finishes, only what was written through the
+/// agent counts against it, so `seed` should run at startup.
+#[derive(Default)]
+pub struct QuotaTracker {
+    usage: Arc<Mutex<HashMap<Scope, Usage>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    Path(PathBuf),
+    Uid(u32),
+}
+
+struct Usage {
+    bytes: u64,
+    /// None until the first walk finishes
+    measured: Option<Instant>,
+    /// A walk to replace this measurement is under way
+    refreshing: bool,
+    /// Bytes reserved less bytes released since that walk started, which
+    /// it may not see and which are added to its result
+    changed: i64,
+}
+
+impl Usage {
+    fn new(bytes: u64) -> Self {
+        Self {
+            bytes,
+            measured: Some(Instant::now()),
+            refreshing: false,
+            changed: 0,
+        }
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.bytes = self.bytes.saturating_add(bytes);
+        if self.refreshing {
+            self.changed = self.changed.saturating_add_unsigned(bytes);
+        }
+    }
+
+    fn sub(&mut self, bytes: u64) {
+        self.bytes = self.bytes.saturating_sub(bytes);
+        if self.refreshing {
+            self.changed = self.changed.saturating_sub_unsigned(bytes);
+        }
+    }
+}
+
+struct Limits {
+    scope: Scope,
+    soft: Option<u64>,
+    hard: Option<u64>,
+}
+
+impl QuotaTracker {
+    /// Checks that writing `bytes` more to `path` keeps every applicable
+    /// quota under its hard limit, and if so accounts for them.
+    pub fn reserve(
+        &self,
+        config: &SecurityConfig,
+        path: &Path,
+        peer_uid: Option<u32>,
+        bytes: u64,
+    ) -> Result<()> {
+        self.reserve_limits(applicable_limits(config, path, peer_uid), bytes)
+    }
+
+    /// Takes freed space off the cached usage, so space released by
+    /// deletes and shrinking writes is available before the next walk.
+    pub fn release(&self, config: &SecurityConfig, path: &Path, peer_uid: Option<u32>, bytes: u64) {
+        self.release_limits(applicable_limits(config, path, peer_uid), bytes)
+    }
+
+    /// Like `reserve`, for data moved from `excluded` to `path`: only path
+    /// quotas that `path` enters are charged. Moving leaves the usage of
+    /// the quotas covering both places, and of the user, unchanged.
+    pub fn reserve_excluding(
+        &self,
+        config: &SecurityConfig,
+        path: &Path,
+        excluded: &Path,
+        bytes: u64,
+    ) -> Result<()> {
+        self.reserve_limits(limits_excluding(config, path, excluded), bytes)
+    }
+
+    /// The counterpart of `reserve_excluding`.
+    pub fn release_excluding(
+        &self,
+        config: &SecurityConfig,
+        path: &Path,
+        excluded: &Path,
+        bytes: u64,
+    ) {
+        self.release_limits(limits_excluding(config, path, excluded), bytes)
+    }
+
+    /// Starts measuring every path quota, returning the thread doing so
+    /// if there are any.
+    pub fn seed(&self, config: &SecurityConfig) -> Option<JoinHandle<()>> {
+        self.refresh(&all_limits(config))
+    }
+
+    /// Reports usage for every configured quota, waiting for the trees
+    /// that are due to be measured.
+    pub fn status(&self, config: &SecurityConfig) -> Vec<QuotaUsage> {
+        let limits = all_limits(config);
+        if let Some(walk) = self.refresh(&limits) {
+            let _ = walk.join();
+        }
+
+        let usage = self.usage.lock().unwrap();
+        limits
+            .into_iter()
+            .map(|limit| {
+                let used = usage.get(&limit.scope).map_or(0, |u| u.bytes);
+                let (path, uid) = match &limit.scope {
+                    Scope::Path(path) => (Some(path.to_string_lossy().to_string()), None),
+                    Scope::Uid(uid) => (None, Some(*uid)),
+                };
+                QuotaUsage {
+                    path,
+                    uid,
+                    used,
+                    soft_limit: limit.soft,
+                    hard_limit: limit.hard,
+                    soft_exceeded: limit.soft.is_some_and(|soft| used > soft),
+                    hard_exceeded: limit.hard.is_some_and(|hard| used > hard),
+                }
+            })
+            .collect()
+    }
+
+    fn reserve_limits(&self, limits: Vec<Limits>, bytes: u64) -> Result<()> {
+        if limits.is_empty() || bytes == 0 {
+            return Ok(());
+        }
+        self.refresh(&limits);
+
+        let mut usage = self.usage.lock().unwrap();
+        for limit in &limits {
+            let used = usage.get(&limit.scope).map_or(0, |u| u.bytes);
+            let after = used.saturating_add(bytes);
+
+            if let Some(hard) = limit.hard.filter(|&hard| after > hard) {
+                warn!(
+                    "Hard quota for {} exceeded: {} > {}",
+                    limit.scope, after, hard
+                );
+                return Err(AgentError::QuotaExceeded(format!(
+                    "{} would use {} bytes, hard limit is {}",
+                    limit.scope, after, hard
+                )));
+            }
+            if let Some(soft) = limit.soft.filter(|&soft| after > soft) {
+                warn!(
+                    "Soft quota for {} exceeded: {} > {}",
+                    limit.scope, after, soft
+                );
+            }
+        }
+
+        for limit in limits {
+            usage.entry(limit.scope).or_insert_with(|| Usage::new(0)).add(bytes);
+        }
+        Ok(())
+    }
+
+    fn release_limits(&self, limits: Vec<Limits>, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        for limit in limits {
+            if let Some(entry) = usage.get_mut(&limit.scope) {
+                entry.sub(bytes);
+            }
+        }
+    }
+
+    /// Walks the trees of path quotas that were never measured or whose
+    /// measurement expired on a background thread. The lock is not held
+    /// while walking, so other writes go on meanwhile; those landing in a
+    /// tree being walked may be counted twice until the next walk.
+    fn refresh(&self, limits: &[Limits]) -> Option<JoinHandle<()>> {
+        let mut roots = Vec::new();
+        {
+            let mut usage = self.usage.lock().unwrap();
+            for limit in limits {
+                let Scope::Path(root) = &limit.scope else {
+                    continue;
+                };
+                let entry = usage.entry(limit.scope.clone()).or_insert_with(|| Usage {
+                    measured: None,
+                    ..Usage::new(0)
+                });
+                let expired = entry.measured.is_none_or(|m| m.elapsed() >= USAGE_TTL);
+                if !entry.refreshing && expired {
+                    entry.refreshing = true;
+                    entry.changed = 0;
+                    roots.push(root.clone());
+                }
+            }
+        }
+        if roots.is_empty() {
+            return None;
+        }
+
+        let usage = self.usage.clone();
+        Some(std::thread::spawn(move || {
+            for root in roots {
+                let bytes = measure_logged(&root);
+                let mut usage = usage.lock().unwrap();
+                let entry = usage.entry(Scope::Path(root)).or_insert_with(|| Usage::new(0));
+                *entry = Usage::new(bytes.saturating_add_signed(entry.changed));
+            }
+        }))
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Scope::Path(path) => write!(f, "{}", path.display()),
+            Scope::Uid(uid) => write!(f, "uid {}", uid),
+        }
+    }
+}
+
+fn all_limits(config: &SecurityConfig) -> Vec<Limits> {
+    let paths = config.quotas.paths.iter().map(|q| Limits {
+        scope: Scope::Path(q.path.clone()),
+        soft: q.soft_limit,
+        hard: q.hard_limit,
+    });
+    let users = config.quotas.users.iter().map(|q| Limits {
+        scope: Scope::Uid(q.uid),
+        soft: q.soft_limit,
+        hard: q.hard_limit,
+    });
+    paths.chain(users).collect()
+}
+
+fn applicable_limits(config: &SecurityConfig, path: &Path, peer_uid: Option<u32>) -> Vec<Limits> {
+    all_limits(config)
+        .into_iter()
+        .filter(|limit| match &limit.scope {
+            Scope::Path(root) => path.starts_with(root),
+            Scope::Uid(uid) => peer_uid == Some(*uid),
+        })
+        .collect()
+}
+
+/// Path quotas covering `path` but not `excluded`.
+fn limits_excluding(config: &SecurityConfig, path: &Path, excluded: &Path) -> Vec<Limits> {
+    all_limits(config)
+        .into_iter()
+        .filter(|limit| match &limit.scope {
+            Scope::Path(root) => path.starts_with(root) && !excluded.starts_with(root),
+            Scope::Uid(_) => false,
+        })
+        .collect()
+}
+
+fn measure_logged(root: &Path) -> u64 {
+    let started = Instant::now();
+    let bytes = measure(root);
+    debug!(
+        "Measured {} in {:?}: {} bytes",
+        root.display(),
+        started.elapsed(),
+        bytes
+    );
+    bytes
+}
+
+/// Sums the size of regular files below `path` without following
+/// symlinks.
+fn measure(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+
+    if metadata.is_file() {
+        return metadata.len();
+    }
+    if !metadata.is_dir() {
+        return 0;
+    }
+
+    fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| measure(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+    use crate::config::{PathQuota, QuotaConfig, UserQuota};
+    use crate::security::Validator;
+
+    #[test]
+    fn test_hard_limit() {
+        let root = TestDir::new("quota");
+        fs::write(root.join("existing"), vec![0u8; 600]).unwrap();
+
+        let config = SecurityConfig {
+            allowed_paths: vec![root.to_path_buf()],
+            forbidden_patterns: vec![],
+            max_file_size: 1024 * 1024,
+            max_path_depth: 10,
+            audit_enabled: false,
+            allow_metadata_changes: false,
+            max_extract_size: 1024 * 1024,
+            max_archive_entries: 100,
+            quotas: QuotaConfig {
+                paths: vec![PathQuota {
+                    path: root.to_path_buf(),
+                    soft_limit: Some(800),
+                    hard_limit: Some(1000),
+                }],
+                users: vec![],
+            },
+        };
+
+        let tracker = QuotaTracker::default();
+        tracker.seed(&config).unwrap().join().unwrap();
+        let target = root.join("new");
+        assert!(tracker.reserve(&config, &target, None, 300).is_ok());
+        // The reservation counts even before the next measurement
+        assert!(matches!(
+            tracker.reserve(&config, &target, None, 200),
+            Err(AgentError::QuotaExceeded(_))
+        ));
+        assert!(tracker
+            .reserve(&config, Path::new("/elsewhere"), None, 5000)
+            .is_ok());
+
+        tracker.release(&config, &target, None, 100);
+        assert!(tracker.reserve(&config, &target, None, 100).is_ok());
+
+        let status = tracker.status(&config);
+        assert_eq!(status[0].used, 900);
+        assert!(status[0].soft_exceeded);
+        assert!(!status[0].hard_exceeded);
+    }
+
+    #[test]
+    fn test_user_quota_and_moves() {
+        let root = TestDir::new("quota-move");
+        fs::create_dir_all(root.join("quota")).unwrap();
+        fs::write(root.join("quota/existing"), vec![0u8; 600]).unwrap();
+
+        let config = SecurityConfig {
+            allowed_paths: vec![root.to_path_buf()],
+            forbidden_patterns: vec![],
+            max_file_size: 1024 * 1024,
+            max_path_depth: 10,
+            audit_enabled: false,
+            allow_metadata_changes: false,
+            max_extract_size: 1024 * 1024,
+            max_archive_entries: 100,
+            quotas: QuotaConfig {
+                paths: vec![PathQuota {
+                    path: root.join("quota"),
+                    soft_limit: None,
+                    hard_limit: Some(1000),
+                }],
+                users: vec![UserQuota {
+                    uid: 1000,
+                    soft_limit: None,
+                    hard_limit: Some(1000),
+                }],
+            },
+        };
+
+        // Files on disk belong to whoever runs the tests, not to uid 1000
+        let tracker = QuotaTracker::default();
+        tracker.seed(&config).unwrap().join().unwrap();
+        let outside = root.join("outside");
+        tracker.reserve(&config, &outside, Some(1000), 700).unwrap();
+        assert!(tracker.reserve(&config, &outside, Some(1000), 400).is_err());
+        assert_eq!(tracker.status(&config)[1].used, 700);
+
+        // Moving into the quota is charged, moving within it is not
+        let inside = root.join("quota/moved");
+        assert!(tracker
+            .reserve_excluding(&config, &inside, &outside, 500)
+            .is_err());
+        tracker
+            .reserve_excluding(&config, &inside, &root.join("quota/other"), 5000)
+            .unwrap();
+        assert_eq!(tracker.status(&config)[0].used, 600);
+
+        // A reservation that is not committed is given back
+        let validator = Validator::new(config);
+        validator.seed_quotas().unwrap().join().unwrap();
+        let reservation = validator.check_quota(&inside, 300).unwrap();
+        assert!(validator.check_quota(&inside, 300).is_err());
+        drop(reservation);
+        validator.check_quota(&inside, 300).unwrap().commit();
+        assert_eq!(validator.quota_status()[0].used, 900);
+    }
+}
@@ -1,19 +1,48 @@
 use crate::config::SecurityConfig;
 use crate::error::{AgentError, Result};
+use crate::protocol::QuotaUsage;
+use crate::locks::LockManager;
+use crate::security::quota::QuotaTracker;
 use log::{debug, warn};
+use std::collections::VecDeque;
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Symlinks followed while resolving a path before giving up, as in the
+/// kernel.
+const MAX_SYMLINKS: usize = 40;
 
 #[derive(Clone)]
 pub struct Validator {
     pub config: SecurityConfig,
+    quotas: Arc<QuotaTracker>,
+    locks: LockManager,
+    peer_uid: Option<u32>,
+    session: Option<u64>,
 }
 
 impl Validator {
     pub fn new(config: SecurityConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            quotas: Arc::new(QuotaTracker::default()),
+            locks: LockManager::new(),
+            peer_uid: None,
+            session: None,
+        }
+    }
+
+    /// Returns a validator acting for one client connection: its user
+    /// quota applies and its own file locks do not block it. Quota
+    /// accounting and locks stay shared between connections.
+    pub fn for_session(&self, session: u64, peer_uid: Option<u32>) -> Self {
+        Self {
+            session: Some(session),
+            peer_uid,
+            ..self.clone()
+        }
     }
 
     pub fn validate_path(&self, path: &str) -> Result<PathBuf> {
@@ -45,19 +74,108 @@ impl Validator {
             Err(e) => return Err(AgentError::Io(e)),
         };
 
-        if !self.is_path_allowed(&canonical) {
-            warn!("Access denied to path: {:?}", canonical);
-            return Err(AgentError::PathNotAllowed(canonical.display().to_string()));
+        self.check_path(&canonical)?;
+
+        debug!("Path validated: {:?}", canonical);
+        Ok(canonical)
+    }
+
+    /// Validates a path without resolving its final component, so a
+    /// symlink is checked (and later operated on) as the link itself.
+    pub fn validate_link_path(&self, path: &str) -> Result<PathBuf> {
+        debug!("Validating link path: {}", path);
+
+        if path.contains("..") {
+            warn!("Path traversal attempt detected: {}", path);
+            return Err(AgentError::PathTraversal(path.to_string()));
+        }
+
+        let path_buf = PathBuf::from(path);
+        let (parent, name) = match (path_buf.parent(), path_buf.file_name()) {
+            (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => (parent, name),
+            _ => return self.validate_path(path),
+        };
+
+        let parent = fs::canonicalize(parent).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                AgentError::FileNotFound(path.to_string())
+            } else {
+                AgentError::Io(e)
+            }
+        })?;
+        let location = parent.join(name);
+
+        self.check_path(&location)?;
+        Ok(location)
+    }
+
+    /// Resolves the target of a symlink located at `link` and validates it.
+    /// Relative targets are resolved against the link's directory, following
+    /// symlinks on the way like the kernel will when the link is used.
+    pub fn validate_link_target(&self, link: &Path, target: &str) -> Result<PathBuf> {
+        let joined = link.parent().unwrap_or(Path::new("/")).join(target);
+        let resolved = resolve(&joined)?;
+
+        self.check_path(&resolved)?;
+        Ok(resolved)
+    }
+
+    /// Maps an archive member name to its location below `dest`, rejecting
+    /// absolute names and `..` components (zip-slip).
+    pub fn validate_archive_entry(&self, dest: &Path, name: &str) -> Result<PathBuf> {
+        let mut relative = PathBuf::new();
+        for component in Path::new(name).components() {
+            match component {
+                Component::Normal(part) => relative.push(part),
+                Component::CurDir => {}
+                _ => {
+                    warn!("Archive entry escapes destination: {}", name);
+                    return Err(AgentError::PathTraversal(name.to_string()));
+                }
+            }
         }
 
-        if self.contains_forbidden_pattern(&canonical) {
-            warn!("Forbidden pattern in path: {:?}", canonical);
+        if relative.as_os_str().is_empty() {
+            return Err(AgentError::InvalidRequest(format!(
+                "Invalid archive entry name: {}",
+                name
+            )));
+        }
+
+        let path = dest.join(relative);
+        self.check_path(&path)?;
+        Ok(path)
+    }
+
+    /// Checks that a link stored in an archive, once extracted to `entry`,
+    /// points somewhere inside `dest`, following the links already
+    /// extracted on the way.
+    pub fn validate_archive_link(&self, dest: &Path, entry: &Path, target: &Path) -> Result<()> {
+        let joined = entry.parent().unwrap_or(dest).join(target);
+
+        let resolved = resolve(&joined)?;
+        if !resolved.starts_with(dest) {
+            warn!("Archive link escapes destination: {:?} -> {:?}", entry, target);
+            return Err(AgentError::PathTraversal(target.display().to_string()));
+        }
+
+        self.check_path(&resolved)
+    }
+
+    fn check_path(&self, path: &Path) -> Result<()> {
+        if !self.is_path_allowed(path) {
+            warn!("Access denied to path: {:?}", path);
+            return Err(AgentError::PathNotAllowed(path.display().to_string()));
+        }
+
+        if self.contains_forbidden_pattern(path) {
+            warn!("Forbidden pattern in path: {:?}", path);
             return Err(AgentError::PermissionDenied(
                 "Path contains forbidden pattern".to_string(),
             ));
         }
 
-        let depth = canonical.components().count();
+        let depth = path.components().count();
         if depth > self.config.max_path_depth {
             warn!("Path depth exceeds limit: {} > {}", depth, self.config.max_path_depth);
             return Err(AgentError::PermissionDenied(
@@ -65,8 +183,30 @@ impl Validator {
             ));
         }
 
-        debug!("Path validated: {:?}", canonical);
-        Ok(canonical)
+        Ok(())
+    }
+
+    /// Checks a path found while walking below an already validated
+    /// directory. The path is not canonicalized, so symlinks inside the
+    /// tree are checked as links rather than by their targets.
+    pub fn validate_child(&self, path: &Path) -> Result<()> {
+        self.check_path(path)
+    }
+
+    /// Whether an absolute, already resolved path such as a mount point
+    /// would pass validation. Unlike `validate_path` nothing is logged, so
+    /// it suits flagging paths the client did not ask for.
+    pub fn is_within_allowed(&self, path: &Path) -> bool {
+        self.is_path_allowed(path) && !self.contains_forbidden_pattern(path)
+    }
+
+    pub fn ensure_metadata_changes_allowed(&self) -> Result<()> {
+        if !self.config.allow_metadata_changes {
+            return Err(AgentError::PermissionDenied(
+                "Metadata changes are disabled".to_string(),
+            ));
+        }
+        Ok(())
     }
 
     fn is_path_allowed(&self, path: &Path) -> bool {
@@ -80,8 +220,7 @@ impl Validator {
         let path_str = path.to_string_lossy();
 
         for pattern in &self.config.forbidden_patterns {
-            if pattern.starts_with('*') {
-                let ext = &pattern[1..];
+            if let Some(ext) = pattern.strip_prefix('*') {
                 if path_str.ends_with(ext) {
                     return true;
                 }
@@ -107,6 +246,64 @@ impl Validator {
         Ok(size)
     }
 
+    /// Accounts for `bytes` about to be written at `path`, failing with
+    /// `QuotaExceeded` if a hard limit would be crossed. The space is
+    /// given back unless the reservation is committed after the write.
+    pub fn check_quota(&self, path: &Path, bytes: u64) -> Result<QuotaReservation<'_>> {
+        self.quotas.reserve(&self.config, path, self.peer_uid, bytes)?;
+        Ok(QuotaReservation {
+            validator: self,
+            path: path.to_path_buf(),
+            from: None,
+            bytes,
+        })
+    }
+
+    /// Like `check_quota`, for `bytes` moving from `from` to `to`. Only
+    /// the quotas that `to` enters are charged, and committing frees the
+    /// ones `from` leaves.
+    pub fn check_move_quota(
+        &self,
+        from: &Path,
+        to: &Path,
+        bytes: u64,
+    ) -> Result<QuotaReservation<'_>> {
+        self.quotas.reserve_excluding(&self.config, to, from, bytes)?;
+        Ok(QuotaReservation {
+            validator: self,
+            path: to.to_path_buf(),
+            from: Some(from.to_path_buf()),
+            bytes,
+        })
+    }
+
+    pub fn release_quota(&self, path: &Path, bytes: u64) {
+        self.quotas.release(&self.config, path, self.peer_uid, bytes)
+    }
+
+    /// Fails if another connection holds a lock on `path` or below it.
+    pub fn check_lock(&self, path: &Path) -> Result<()> {
+        self.locks.check(path, self.session)
+    }
+
+    pub fn locks(&self) -> &LockManager {
+        &self.locks
+    }
+
+    pub fn session(&self) -> Option<u64> {
+        self.session
+    }
+
+    /// Measures the trees of path quotas in the background, so the first
+    /// writes below them are checked against what is already there.
+    pub fn seed_quotas(&self) -> Option<JoinHandle<()>> {
+        self.quotas.seed(&self.config)
+    }
+
+    pub fn quota_status(&self) -> Vec<QuotaUsage> {
+        self.quotas.status(&self.config)
+    }
+
     pub fn audit_log(&self, operation: &str, path: &Path, success: bool) {
         if !self.config.audit_enabled {
             return;
@@ -138,9 +335,153 @@ impl Validator {
     }
 }
 
+/// Quota space held for a write in progress. Dropping it without
+/// committing, as happens when the write fails, gives the space back.
+#[must_use = "the reserved space is released when this is dropped"]
+pub struct QuotaReservation<'a> {
+    validator: &'a Validator,
+    path: PathBuf,
+    /// Where moved data comes from
+    from: Option<PathBuf>,
+    bytes: u64,
+}
+
+impl QuotaReservation<'_> {
+    /// Keeps the space accounted once the write went through.
+    pub fn commit(mut self) {
+        if let Some(from) = &self.from {
+            let validator = self.validator;
+            validator
+                .quotas
+                .release_excluding(&validator.config, from, &self.path, self.bytes);
+        }
+        self.bytes = 0;
+    }
+
+    /// Commits the size actually written when only an estimate was
+    /// reserved, which may still fail if it turned out larger.
+    pub fn commit_written(mut self, written: u64) -> Result<()> {
+        if written > self.bytes {
+            self.reserve(written - self.bytes)?;
+        } else {
+            self.release(self.bytes - written);
+        }
+        self.bytes = written;
+        self.commit();
+        Ok(())
+    }
+
+    fn reserve(&self, bytes: u64) -> Result<()> {
+        let validator = self.validator;
+        match &self.from {
+            Some(from) => validator
+                .quotas
+                .reserve_excluding(&validator.config, &self.path, from, bytes),
+            None => validator
+                .quotas
+                .reserve(&validator.config, &self.path, validator.peer_uid, bytes),
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        let validator = self.validator;
+        match &self.from {
+            Some(from) => validator
+                .quotas
+                .release_excluding(&validator.config, &self.path, from, bytes),
+            None => validator.release_quota(&self.path, bytes),
+        }
+    }
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            self.release(self.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+impl Validator {
+    /// A validator allowing everything below `root`, for handler tests.
+    pub fn for_tests(root: &Path) -> Self {
+        Self::new(SecurityConfig {
+            allowed_paths: vec![root.to_path_buf()],
+            forbidden_patterns: vec![],
+            max_file_size: 1024 * 1024,
+            max_path_depth: 20,
+            audit_enabled: false,
+            allow_metadata_changes: false,
+            max_extract_size: 1024 * 1024,
+            max_archive_entries: 100,
+            quotas: Default::default(),
+        })
+    }
+}
+
+/// Resolves an absolute path the way the kernel does: symlinks are
+/// followed where they appear and `..` applies to where they lead rather
+/// than to the text. Components that do not exist are taken as they are,
+/// and a `..` climbing back out of them lands on the last real directory,
+/// from where links are followed again.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let mut pending: VecDeque<PathBuf> = path
+        .components()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+    let mut resolved = PathBuf::from("/");
+    // Depth of the first component that does not exist
+    let mut missing: Option<usize> = None;
+    let mut links = 0;
+
+    while let Some(part) = pending.pop_front() {
+        match part.components().next() {
+            Some(Component::RootDir) => {
+                resolved = PathBuf::from("/");
+                missing = None;
+            }
+            Some(Component::ParentDir) => {
+                resolved.pop();
+                if missing.is_some_and(|depth| resolved.components().count() < depth) {
+                    missing = None;
+                }
+            }
+            Some(Component::Normal(name)) => {
+                let next = resolved.join(name);
+                if missing.is_none() {
+                    match fs::symlink_metadata(&next) {
+                        Ok(metadata) if metadata.file_type().is_symlink() => {
+                            links += 1;
+                            if links > MAX_SYMLINKS {
+                                return Err(AgentError::InvalidRequest(format!(
+                                    "Too many levels of symbolic links: {}",
+                                    path.display()
+                                )));
+                            }
+                            let target = fs::read_link(&next)?;
+                            for component in target.components().rev() {
+                                pending.push_front(PathBuf::from(component.as_os_str()));
+                            }
+                            continue;
+                        }
+                        Ok(_) => {}
+                        Err(_) => missing = Some(next.components().count()),
+                    }
+                }
+                resolved = next;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(resolved)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TestDir;
 
     fn test_config() -> SecurityConfig {
         SecurityConfig {
@@ -149,6 +490,10 @@ mod tests {
             max_file_size: 1024 * 1024, // 1MB
             max_path_depth: 10,
             audit_enabled: false,
+            allow_metadata_changes: false,
+            max_extract_size: 1024 * 1024,
+            max_archive_entries: 100,
+            quotas: Default::default(),
         }
     }
 
@@ -167,8 +512,62 @@ mod tests {
 
     #[test]
     fn test_allowed_path() {
-        let validator = Validator::new(test_config());
+        let _validator = Validator::new(test_config());
         // This would pass if /tmp exists
         // assert!(validator.validate_path("/tmp/tests.txt").is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_within_allowed() {
+        let validator = Validator::new(test_config());
+        assert!(validator.is_within_allowed(Path::new("/home/user")));
+        assert!(!validator.is_within_allowed(Path::new("/home/user/.ssh")));
+        assert!(!validator.is_within_allowed(Path::new("/")));
+    }
+
+    #[test]
+    fn test_link_target_outside_allowed() {
+        let validator = Validator::new(test_config());
+        let link = Path::new("/tmp/link");
+        assert!(validator.validate_link_target(link, "../etc/passwd").is_err());
+        assert!(validator.validate_link_target(link, "/etc/passwd").is_err());
+        assert!(validator.validate_link_target(link, "./target").is_ok());
+    }
+
+    #[test]
+    fn test_archive_entry_zip_slip() {
+        let validator = Validator::new(test_config());
+        let dest = Path::new("/tmp/out");
+        assert!(validator.validate_archive_entry(dest, "../evil.sh").is_err());
+        assert!(validator.validate_archive_entry(dest, "a/../../evil.sh").is_err());
+        assert!(validator.validate_archive_entry(dest, "/etc/cron.d/evil").is_err());
+        assert_eq!(
+            validator.validate_archive_entry(dest, "./docs/readme.txt").unwrap(),
+            PathBuf::from("/tmp/out/docs/readme.txt")
+        );
+        assert!(validator
+            .validate_archive_link(dest, Path::new("/tmp/out/a/link"), Path::new("../../etc"))
+            .is_err());
+        assert!(validator
+            .validate_archive_link(dest, Path::new("/tmp/out/a/link"), Path::new("../b"))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_link_target_through_symlink() {
+        let root = TestDir::new("validator");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        std::os::unix::fs::symlink("../..", root.join("a/b/up")).unwrap();
+        let validator = Validator::for_tests(&root.join("a"));
+
+        // On paper this stays in a/, but up/ leads to the parent of root
+        let link = root.join("a/b/link");
+        assert!(validator.validate_link_target(&link, "up/../x").is_err());
+        assert!(validator.validate_link_target(&link, "../x").is_ok());
+        assert_eq!(resolve(&root.join("a/b/up/a")).unwrap(), root.join("a"));
+
+        // Leaving a missing directory again gets back to following links
+        assert!(validator.validate_link_target(&link, "new/../x").is_ok());
+        assert!(validator.validate_link_target(&link, "new/../up/../x").is_err());
+    }
+}
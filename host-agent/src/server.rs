@@ -1,15 +1,38 @@
+use crate::alerts::AlertManager;
 use crate::config::Config;
 use crate::error::{AgentError, Result};
-use crate::handlers;
 use crate::security::Validator;
 use anyhow::Context;
 use log::{debug, error, info, warn};
-use serde_json;
 use std::path::Path;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use crate::handlers::archive::ArchiveHandler;
+use crate::handlers::checksum::ChecksumHandler;
+use crate::handlers::diff::DiffHandler;
+use crate::handlers::duplicates::DuplicateHandler;
 use crate::handlers::files::FileHandler;
-use crate::protocol::{Action, Request, Response, ResponseData, ResponseResult};
+use crate::handlers::hardware::HardwareHandler;
+use crate::handlers::mounts::MountHandler;
+use crate::handlers::system::SystemHandler;
+use crate::handlers::tail::{TailHandler, TailState};
+use crate::handlers::thumbnail::ThumbnailHandler;
+use crate::handlers::versions::VersionHandler;
+use crate::handlers::xattrs::XattrHandler;
+use crate::jobs::JobManager;
+use crate::metrics::{self, MetricsStore};
+use crate::protocol::{Action, Alert, Event, Request, Response, ResponseData, ResponseResult};
+use crate::session::{EventSink, Session};
+
+/// How often followed files are checked for new data.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Messages queued for a client that is not reading. Responses wait for
+/// room, subscriptions are ended when it runs out.
+const OUTGOING_BUFFER: usize = 64;
 
 pub async fn run(config: Config) -> anyhow::Result<()> {
     let socket_path = &config.server.socket_path;
@@ -27,15 +50,40 @@ pub async fn run(config: Config) -> anyhow::Result<()> {
     // TODO: Set socket permissions
 
     let validator = Validator::new(config.security.clone());
+    validator.seed_quotas();
+    let jobs = JobManager::new(config.performance.max_concurrent_operations);
+
+    let metrics = MetricsStore::new(&config.metrics);
+    let alerts = AlertManager::new(&config.alerts);
+    if config.metrics.enabled {
+        tokio::spawn(metrics::run(
+            metrics.clone(),
+            alerts.clone(),
+            config.metrics.clone(),
+            config.hardware.sysfs_root.clone(),
+        ));
+    } else if !config.alerts.rules.is_empty() {
+        warn!("Alert rules are configured but metrics collection is disabled");
+    }
+
+    let mut next_session = 0;
 
     loop {
         match listener.accept().await {
             Ok((stream, _addr)) => {
                 let config = config.clone();
-                let validator = validator.clone();
+                // User quotas apply to the UID on the other end of the socket
+                let peer_uid = stream.peer_cred().ok().map(|cred| cred.uid());
+                next_session += 1;
+                let validator = validator.for_session(next_session, peer_uid);
+                let jobs = jobs.clone();
+                let metrics = metrics.clone();
+                let alerts = alerts.clone();
 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, config, validator).await {
+                    if let Err(e) =
+                        handle_client(stream, config, validator, jobs, metrics, alerts).await
+                    {
                         error!("Client error: {}", e);
                     }
                 });
@@ -51,11 +99,32 @@ async fn handle_client(
     stream: UnixStream,
     config: Config,
     validator: Validator,
+    jobs: JobManager,
+    metrics: MetricsStore,
+    alerts: AlertManager,
 ) -> Result<()> {
     let (reader, mut writer) = stream.into_split();
     let mut reader = BufReader::new(reader);
     let mut line = String::new();
 
+    // Responses and pushed events share a single writer
+    let (outgoing, mut queue) = mpsc::channel::<String>(OUTGOING_BUFFER);
+    let writer_task = tokio::spawn(async move {
+        while let Some(message) = queue.recv().await {
+            let result = async {
+                writer.write_all(message.as_bytes()).await?;
+                writer.write_all(b"\n").await?;
+                writer.flush().await
+            }
+            .await;
+            if let Err(e) = result {
+                error!("Write error: {}", e);
+                break;
+            }
+        }
+    });
+    let session = Session::new(outgoing.clone());
+
     loop {
         line.clear();
 
@@ -64,11 +133,13 @@ async fn handle_client(
             Ok(_) => {
                 debug!("Received: {}", line.trim());
 
-                let response_json = process_request(&line, &config, &validator).await;
+                let response_json =
+                    process_request(&line, &config, &validator, &jobs, &metrics, &alerts, &session)
+                        .await;
 
-                writer.write_all(response_json.as_bytes()).await?;
-                writer.write_all(b"\n").await?;
-                writer.flush().await?;
+                if outgoing.send(response_json).await.is_err() {
+                    break;
+                }
             }
             Err(e) => {
                 error!("Read error: {}", e);
@@ -77,13 +148,110 @@ async fn handle_client(
         }
     }
 
+    // Locks are tied to the connection that took them
+    if let Some(session) = validator.session() {
+        validator.locks().release_session(session);
+    }
+
+    // Ends subscriptions, which lets the writer drain and finish
+    drop(session);
+    drop(outgoing);
+    let _ = writer_task.await;
+
     Ok(())
 }
 
+/// Polls a followed file and pushes new lines until the client goes
+/// away, unsubscribes or the file can no longer be read.
+async fn follow_tail(mut state: TailState, sink: EventSink) {
+    let mut interval = tokio::time::interval(TAIL_POLL_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if sink.is_closed() {
+            break;
+        }
+
+        // Reading can take a while on slow storage, so it runs off the runtime
+        let polled = tokio::task::spawn_blocking(move || {
+            let result = state.poll();
+            (state, result)
+        });
+        let result = match polled.await {
+            Ok((returned, result)) => {
+                state = returned;
+                result
+            }
+            Err(e) => {
+                error!("Tail poll failed: {}", e);
+                break;
+            }
+        };
+
+        match result {
+            Ok(update) if update.lines.is_empty() && !update.truncated && !update.rotated => {}
+            Ok(update) => {
+                let event = Event::TailLines {
+                    lines: update.lines,
+                    truncated: update.truncated,
+                    rotated: update.rotated,
+                };
+                if !sink.send(event) {
+                    break;
+                }
+            }
+            Err(e) => {
+                sink.send(Event::Error {
+                    message: e.to_string(),
+                });
+                break;
+            }
+        }
+    }
+}
+
+/// Pushes alerts as they fire and resolve until unsubscribed.
+async fn forward_alerts(mut alerts: broadcast::Receiver<Alert>, sink: EventSink) {
+    loop {
+        match alerts.recv().await {
+            Ok(alert) => {
+                if !sink.send(Event::Alert(alert)) {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Alert subscriber missed {} alerts", missed);
+                let message = format!("{} alerts were dropped, list alerts to catch up", missed);
+                if !sink.send(Event::Error { message }) {
+                    break;
+                }
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Runs handler work that may block for a while on the blocking pool, so
+/// it does not hold up the runtime workers serving other clients.
+async fn blocking<T, F>(work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| AgentError::Internal(format!("Blocking task failed: {}", e)))?
+}
+
 async fn process_request(
     request_str: &str,
-    _config: &Config,
+    config: &Config,
     validator: &Validator,
+    jobs: &JobManager,
+    metrics: &MetricsStore,
+    alerts: &AlertManager,
+    session: &Session,
 ) -> String {
     // Parse request
     let request: Request = match serde_json::from_str(request_str) {
@@ -101,37 +269,111 @@ async fn process_request(
     };
 
     // Create handlers
-    let file_handler = FileHandler::new(validator.clone());
+    let file_handler = FileHandler::new(validator.clone(), config.versioning.clone());
+    let archive_handler = ArchiveHandler::new(validator.clone());
+    let checksum_handler = ChecksumHandler::new(validator.clone());
+    let diff_handler = DiffHandler::new(validator.clone());
+    let duplicate_handler = DuplicateHandler::new(validator.clone());
+    let tail_handler = TailHandler::new(validator.clone());
+    let thumbnail_handler = ThumbnailHandler::new(validator.clone(), config.thumbnails.clone());
+    let version_handler = VersionHandler::new(validator.clone(), config.versioning.clone());
+    let xattr_handler = XattrHandler::new(validator.clone());
+    let mount_handler = MountHandler::new(validator.clone());
+    let system_handler = SystemHandler::new();
+    let hardware_handler = HardwareHandler::new(config.hardware.clone());
 
     // Process action
     let result = match request.action {
         Action::Ping => ResponseResult::Success(ResponseData::Pong),
 
-        Action::ListFiles { path } => match file_handler.list_files(&path) {
-            Ok(files) => ResponseResult::Success(ResponseData::Files { files }),
+        Action::ListFiles { path, extended, options } => {
+            match file_handler.list_files(&path, extended, &options) {
+                Ok(listing) => ResponseResult::Success(ResponseData::Files {
+                    files: listing.files,
+                    total: Some(listing.total),
+                    next_cursor: listing.next_cursor,
+                }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ListTree { path, depth, dirs_only, max_entries } => {
+            // Walks up to max_entries directories
+            let list = move || file_handler.list_tree(&path, depth, dirs_only, max_entries);
+            match blocking(list).await {
+                Ok(tree) => ResponseResult::Success(ResponseData::Tree(tree)),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::Stat { path } => match file_handler.stat(&path) {
+            Ok(info) => ResponseResult::Success(ResponseData::Stat(info)),
             Err(e) => ResponseResult::Error {
                 error: e.to_string(),
-                code: 500,
+                code: e.code(),
             },
         },
 
         Action::ReadFile { path } => match file_handler.read_file(&path) {
-            Ok((content, size)) => {
-                ResponseResult::Success(ResponseData::FileContent { content, size })
-            }
+            Ok((content, size, format)) => ResponseResult::Success(ResponseData::FileContent {
+                content,
+                size,
+                format: Some(format),
+            }),
             Err(e) => ResponseResult::Error {
                 error: e.to_string(),
-                code: 500,
+                code: e.code(),
             },
         },
 
-        Action::WriteFile { path, content } => match file_handler.write_file(&path, &content) {
+        Action::HexDump { path, offset, length } => {
+            match file_handler.hex_dump(&path, offset, length) {
+                Ok(dump) => ResponseResult::Success(ResponseData::HexDump(dump)),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::TailFile { path, lines, follow } => {
+            match blocking(move || tail_handler.tail(&path, lines)).await {
+                Ok((lines, state)) => {
+                    if follow {
+                        let sink = session.sink(&request.id);
+                        session.subscribe(&request.id, tokio::spawn(follow_tail(state, sink)));
+                    }
+                    ResponseResult::Success(ResponseData::Tail {
+                        lines,
+                        following: follow,
+                    })
+                }
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::WriteFile {
+            path,
+            content,
+            encoding,
+            line_ending,
+            bom,
+        } => match file_handler.write_file(&path, &content, encoding.as_deref(), line_ending, bom) {
             Ok(_) => ResponseResult::Success(ResponseData::Success {
                 message: "File written successfully".to_string(),
             }),
             Err(e) => ResponseResult::Error {
                 error: e.to_string(),
-                code: 500,
+                code: e.code(),
             },
         },
 
@@ -141,7 +383,7 @@ async fn process_request(
             }),
             Err(e) => ResponseResult::Error {
                 error: e.to_string(),
-                code: 500,
+                code: e.code(),
             },
         },
 
@@ -151,27 +393,483 @@ async fn process_request(
             }),
             Err(e) => ResponseResult::Error {
                 error: e.to_string(),
-                code: 500,
+                code: e.code(),
             },
         },
 
-        Action::CopyFile { from, to } => match file_handler.copy(&from, &to) {
+        Action::CopyFile {
+            from,
+            to,
+            preserve_xattrs,
+        } => match file_handler.copy(&from, &to, preserve_xattrs) {
             Ok(_) => ResponseResult::Success(ResponseData::Success {
                 message: "Copied successfully".to_string(),
             }),
             Err(e) => ResponseResult::Error {
                 error: e.to_string(),
-                code: 500,
+                code: e.code(),
             },
         },
 
-        Action::MoveFile { from, to } => match file_handler.move_item(&from, &to) {
+        Action::MoveFile {
+            from,
+            to,
+            preserve_xattrs,
+        } => match file_handler.move_item(&from, &to, preserve_xattrs) {
             Ok(_) => ResponseResult::Success(ResponseData::Success {
                 message: "Moved successfully".to_string(),
             }),
             Err(e) => ResponseResult::Error {
                 error: e.to_string(),
-                code: 500,
+                code: e.code(),
+            },
+        },
+
+        Action::CreateSymlink { target, link } => match file_handler.create_symlink(&target, &link) {
+            Ok(_) => ResponseResult::Success(ResponseData::Success {
+                message: "Symlink created successfully".to_string(),
+            }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::CreateHardlink { target, link } => match file_handler.create_hardlink(&target, &link) {
+            Ok(_) => ResponseResult::Success(ResponseData::Success {
+                message: "Hard link created successfully".to_string(),
+            }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::ReadLink { path } => match file_handler.read_link(&path) {
+            Ok(link) => ResponseResult::Success(ResponseData::Link(link)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::CreateArchive { paths, dest, format } => {
+            match jobs.spawn("create_archive", move |job| {
+                archive_handler.create_archive(&paths, &dest, format, job)
+            }) {
+                Ok(job_id) => ResponseResult::Success(ResponseData::JobStarted { job_id }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ExtractArchive { archive, dest } => {
+            match jobs.spawn("extract_archive", move |job| {
+                archive_handler.extract_archive(&archive, &dest, job)
+            }) {
+                Ok(job_id) => ResponseResult::Success(ResponseData::JobStarted { job_id }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ListArchive { path, inner_path } => {
+            match archive_handler.list_archive(&path, inner_path.as_deref()) {
+                Ok(files) => ResponseResult::Success(ResponseData::Files {
+                    files,
+                    total: None,
+                    next_cursor: None,
+                }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ReadArchiveEntry { path, entry } => {
+            match archive_handler.read_archive_entry(&path, &entry) {
+                Ok((content, size)) => ResponseResult::Success(ResponseData::FileContent {
+                    content,
+                    size,
+                    format: None,
+                }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ListVersions { path } => match version_handler.list_versions(&path) {
+            Ok(versions) => ResponseResult::Success(ResponseData::Versions { versions }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::RestoreVersion { path, version } => {
+            match version_handler.restore_version(&path, &version) {
+                Ok(_) => ResponseResult::Success(ResponseData::Success {
+                    message: "Version restored".to_string(),
+                }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::DiffVersion {
+            path,
+            version,
+            against,
+        } => match version_handler.diff_version(&path, &version, against.as_deref()) {
+            Ok(diff) => ResponseResult::Success(ResponseData::Diff(diff)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::DiffFiles { a, b } => {
+            // Reads both files whole and diffs them line by line
+            match blocking(move || diff_handler.diff_files(&a, &b)).await {
+                Ok(diff) => ResponseResult::Success(ResponseData::Diff(diff)),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::DiffDirectories { a, b, compare } => {
+            match jobs.spawn("diff_directories", move |job| {
+                diff_handler.diff_directories(&a, &b, compare, job)
+            }) {
+                Ok(job_id) => ResponseResult::Success(ResponseData::JobStarted { job_id }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::SyncDirectories { source, dest, options } => {
+            match jobs.spawn("sync", move |job| {
+                file_handler.sync_directories(&source, &dest, &options, job)
+            }) {
+                Ok(job_id) => ResponseResult::Success(ResponseData::JobStarted { job_id }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::FindDuplicates { roots, min_size } => {
+            match jobs.spawn("find_duplicates", move |job| {
+                duplicate_handler.find_duplicates(&roots, min_size, job)
+            }) {
+                Ok(job_id) => ResponseResult::Success(ResponseData::JobStarted { job_id }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::Checksum { path, algorithms } => {
+            match jobs.spawn("checksum", move |job| {
+                checksum_handler.checksum(&path, &algorithms, job)
+            }) {
+                Ok(job_id) => ResponseResult::Success(ResponseData::JobStarted { job_id }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::VerifyChecksums { path } => {
+            match jobs.spawn("verify_checksums", move |job| {
+                checksum_handler.verify_checksums(&path, job)
+            }) {
+                Ok(job_id) => ResponseResult::Success(ResponseData::JobStarted { job_id }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::Thumbnail { path, size, format } => {
+            // Decoding a large image takes long enough to stall a worker
+            match blocking(move || thumbnail_handler.thumbnail(&path, size, format)).await {
+                Ok(thumbnail) => ResponseResult::Success(ResponseData::Thumbnail(thumbnail)),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::SetPermissions { path, mode, recursive } => {
+            match file_handler.set_permissions(&path, &mode, recursive) {
+                Ok(_) => ResponseResult::Success(ResponseData::Success {
+                    message: "Permissions changed successfully".to_string(),
+                }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::SetOwner { path, user, group, recursive } => {
+            match file_handler.set_owner(&path, user.as_deref(), group.as_deref(), recursive) {
+                Ok(_) => ResponseResult::Success(ResponseData::Success {
+                    message: "Owner changed successfully".to_string(),
+                }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ListXattrs { path } => match xattr_handler.list_xattrs(&path) {
+            Ok(xattrs) => ResponseResult::Success(ResponseData::Xattrs { xattrs }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::GetXattr { path, name } => match xattr_handler.get_xattr(&path, &name) {
+            Ok(xattr) => ResponseResult::Success(ResponseData::Xattr(xattr)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::SetXattr {
+            path,
+            name,
+            value,
+            base64,
+        } => match xattr_handler.set_xattr(&path, &name, &value, base64) {
+            Ok(_) => ResponseResult::Success(ResponseData::Success {
+                message: "Attribute set".to_string(),
+            }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::RemoveXattr { path, name } => match xattr_handler.remove_xattr(&path, &name) {
+            Ok(_) => ResponseResult::Success(ResponseData::Success {
+                message: "Attribute removed".to_string(),
+            }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::GetAcl { path } => match xattr_handler.get_acl(&path) {
+            Ok(acl) => ResponseResult::Success(ResponseData::Acl(acl)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::SetAcl {
+            path,
+            access,
+            default,
+        } => match xattr_handler.set_acl(&path, access, default) {
+            Ok(_) => ResponseResult::Success(ResponseData::Success {
+                message: "ACL updated".to_string(),
+            }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::SetTimes { path, accessed, modified } => {
+            match file_handler.set_times(&path, accessed, modified) {
+                Ok(_) => ResponseResult::Success(ResponseData::Success {
+                    message: "Times changed successfully".to_string(),
+                }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::QuotaStatus => {
+            // Waits for trees that are due to be measured
+            let validator = validator.clone();
+            match blocking(move || Ok(validator.quota_status())).await {
+                Ok(quotas) => ResponseResult::Success(ResponseData::Quotas { quotas }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ListMounts { all } => {
+            // statvfs on a hung network mount blocks until it times out
+            match blocking(move || mount_handler.list_mounts(all)).await {
+                Ok(mounts) => ResponseResult::Success(ResponseData::Mounts { mounts }),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::ListBlockDevices => match mount_handler.list_block_devices() {
+            Ok(devices) => ResponseResult::Success(ResponseData::BlockDevices { devices }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::LockFile { path, owner, duration_secs } => {
+            match file_handler.lock_file(&path, &owner, duration_secs) {
+                Ok(lock) => ResponseResult::Success(ResponseData::Lock(lock)),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::UnlockFile { path } => match file_handler.unlock_file(&path) {
+            Ok(_) => ResponseResult::Success(ResponseData::Success {
+                message: "File unlocked".to_string(),
+            }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::ListLocks => ResponseResult::Success(ResponseData::Locks {
+            locks: file_handler.list_locks(),
+        }),
+
+        Action::Unsubscribe { id } => {
+            if session.unsubscribe(&id) {
+                ResponseResult::Success(ResponseData::Success {
+                    message: "Unsubscribed".to_string(),
+                })
+            } else {
+                ResponseResult::Error {
+                    error: format!("No subscription for request {}", id),
+                    code: 404,
+                }
+            }
+        }
+
+        Action::JobStatus { job_id } => match jobs.status(&job_id) {
+            Ok(job) => ResponseResult::Success(ResponseData::Job(job)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::ListJobs => ResponseResult::Success(ResponseData::Jobs { jobs: jobs.list() }),
+
+        Action::CancelJob { job_id } => match jobs.cancel(&job_id) {
+            Ok(_) => ResponseResult::Success(ResponseData::Success {
+                message: "Job cancellation requested".to_string(),
+            }),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::SystemInfo => {
+            // Rates need two samples some time apart
+            match blocking(move || system_handler.system_info()).await {
+                Ok(info) => ResponseResult::Success(ResponseData::SystemInfo(info)),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::NetworkInfo => {
+            // Traffic rates are measured over NETWORK_SAMPLE_INTERVAL
+            match blocking(move || system_handler.network_info()).await {
+                Ok(info) => ResponseResult::Success(ResponseData::NetworkInfo(info)),
+                Err(e) => ResponseResult::Error {
+                    error: e.to_string(),
+                    code: e.code(),
+                },
+            }
+        }
+
+        Action::HardwareInfo => match hardware_handler.hardware_info() {
+            Ok(info) => ResponseResult::Success(ResponseData::HardwareInfo(info)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::MetricsHistory { .. } if !config.metrics.enabled => ResponseResult::Error {
+            error: "Metrics collection is disabled".to_string(),
+            code: 503,
+        },
+
+        Action::MetricsHistory {
+            metric,
+            range,
+            resolution,
+        } => match metrics.history(metric, range, resolution) {
+            Ok(history) => ResponseResult::Success(ResponseData::MetricsHistory(history)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
+            },
+        },
+
+        Action::SubscribeAlerts => {
+            let sink = session.sink(&request.id);
+            session.subscribe(&request.id, tokio::spawn(forward_alerts(alerts.subscribe(), sink)));
+            ResponseResult::Success(ResponseData::Success {
+                message: "Subscribed to alerts".to_string(),
+            })
+        }
+
+        Action::ListAlerts => ResponseResult::Success(ResponseData::Alerts {
+            alerts: alerts.list(),
+        }),
+
+        Action::AcknowledgeAlert { id } => match alerts.acknowledge(&id) {
+            Ok(alert) => ResponseResult::Success(ResponseData::Alert(alert)),
+            Err(e) => ResponseResult::Error {
+                error: e.to_string(),
+                code: e.code(),
             },
         },
 
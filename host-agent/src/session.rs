@@ -0,0 +1,109 @@
+use crate::protocol::{Event, EventMessage};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tokio::task::JoinHandle;
+
+/// Per-connection state. Everything written to the client goes through
+/// `outgoing`, so responses and pushed events never interleave, and
+/// subscriptions are torn down when the connection goes away.
+pub struct Session {
+    outgoing: Sender<String>,
+    subscriptions: Mutex<HashMap<String, JoinHandle<()>>>,
+}
+
+/// Pushes events for one subscription, tagged with the id of the request
+/// that started it.
+#[derive(Clone)]
+pub struct EventSink {
+    id: String,
+    outgoing: Sender<String>,
+}
+
+impl EventSink {
+    /// Returns false once the client is gone, so producers can stop. A
+    /// client that stops reading would otherwise make events pile up in
+    /// memory, so once its queue is full the subscription is ended with
+    /// an error event and false is returned as well.
+    pub fn send(&self, event: Event) -> bool {
+        let Some(json) = self.encode(event) else {
+            return false;
+        };
+        match self.outgoing.try_send(json) {
+            Ok(()) => true,
+            Err(TrySendError::Closed(_)) => false,
+            Err(TrySendError::Full(_)) => {
+                warn!("Client is not reading, ending subscription {}", self.id);
+                let error = self.encode(Event::Error {
+                    message: "Subscription ended, events were not read in time".to_string(),
+                });
+                if let Some(json) = error {
+                    let outgoing = self.outgoing.clone();
+                    tokio::spawn(async move {
+                        let _ = outgoing.send(json).await;
+                    });
+                }
+                false
+            }
+        }
+    }
+
+    fn encode(&self, event: Event) -> Option<String> {
+        let message = EventMessage {
+            id: self.id.clone(),
+            event,
+        };
+        serde_json::to_string(&message).ok()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.is_closed()
+    }
+}
+
+impl Session {
+    pub fn new(outgoing: Sender<String>) -> Self {
+        Self {
+            outgoing,
+            subscriptions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn sink(&self, id: &str) -> EventSink {
+        EventSink {
+            id: id.to_string(),
+            outgoing: self.outgoing.clone(),
+        }
+    }
+
+    /// Registers the task producing events for request `id`. A previous
+    /// subscription with the same id is replaced.
+    pub fn subscribe(&self, id: &str, task: JoinHandle<()>) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|_, task| !task.is_finished());
+        if let Some(previous) = subscriptions.insert(id.to_string(), task) {
+            previous.abort();
+        }
+    }
+
+    pub fn unsubscribe(&self, id: &str) -> bool {
+        match self.subscriptions.lock().unwrap().remove(id) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let subscriptions = self.subscriptions.get_mut().unwrap();
+        debug!("Closing session with {} subscriptions", subscriptions.len());
+        for (_, task) in subscriptions.drain() {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Fixtures shared by the unit tests.
+
+use crate::security::Validator;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A scratch directory under the system temp dir. It starts out empty and
+/// is removed on drop, so a failing assertion does not leave it behind.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// `name` must be unique among the tests, which run in parallel.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("host-agent-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    /// A validator that allows this directory only.
+    pub fn validator(&self) -> Validator {
+        Validator::for_tests(&self.path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}